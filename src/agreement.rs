@@ -0,0 +1,127 @@
+// Programs that both engines run, with the value each must print or the error it must
+// stop with. Errors are compared without their location, which the interpreter gives
+// as a span and compiled code as a program counter.
+use compiler;
+use interpreter::Interpreter;
+use parser;
+use parser::syntax::block_to_ast;
+use vm;
+
+const OPTION : &str = "type Option = None | Some x;";
+
+const LIST_FUNCTIONS : &str = "len = |l| if empty? l then 0 else 1 + len (tail l) end;
+    map = |f, l| if empty? l then [] else f (head l) :: map f (tail l) end;
+    fold = |f, acc, l| if empty? l then acc else fold f (f acc (head l)) (tail l) end;
+    range = |i, n| if i < n then i :: range (i + 1) n else [] end;";
+
+const BUILD : &str = "fold = |f, acc, l| if empty? l then acc else fold f (f acc (head l)) (tail l) end;
+    build = |acc, n| if n then build (n :: acc) (n - 1) else acc end;";
+
+// the arm that matches is in tail position, so a recursive walk over a long list runs in constant stack;
+// the constructors are used above their declaration, as globals can be
+const LIST_TYPE : &str = "sum = |acc, l| match l with | Nil -> acc | Cons x rest -> sum (acc + x) rest end;
+    build = |acc, n| if n then build (Cons n acc) (n - 1) else acc end;
+    type List = Nil | Cons head tail;";
+
+// statements put before the expression, the expression, and how it ends; an error
+// only needs to start with the message given
+const CASES : &[(&str, &str, Result<&str, &str>)] = &[
+    // strings
+    ("", r#""say \"hi\"\n""#, Ok("say \"hi\"\n")),
+    (r#"greet = |name| "hello, " ++ name ++ "!";"#, r#"greet "world""#, Ok("hello, world!")),
+    ("", r#"substring "héllo world" 1 4"#, Ok("éllo")),
+    ("first = |s| substring s 0 1;", r#"first "abc""#, Ok("a")),
+    (r#"sub = substring "abcdef"; from = sub 2;"#, "from 3", Ok("cde")),
+    ("", r#"length "héllo" + length """#, Ok("5")),
+    ("", r#"("a" ++ "b" == "ab") + ("a" != "a") + ("x" == "y")"#, Ok("1")),
+    ("length = |s| 42;", r#"length "abc""#, Ok("42")),
+    ("", r#"["a" ++ "b"]"#, Ok(r#"["ab"]"#)),
+    ("", r#"substring "abc" 2 2"#, Err("index out of range")),
+    ("", r#""a" ++ 1"#, Err("expected a string but got 1")),
+    ("", r#"1 + "a""#, Err("expected a number but got \"a\"")),
+    ("", "length 3", Err("expected a string but got 3")),
+
+    // equality takes two numbers or two strings, and the left operand says which
+    ("", r#""a" == 1"#, Err("expected a string but got 1")),
+    ("", r#"1 != "a""#, Err("expected a number but got \"a\"")),
+    ("", "[1] == [1]", Err("expected a number but got [1]")),
+    ("", "1 == []", Err("expected a number but got []")),
+    (OPTION, "Some 1 == Some 1", Err("expected a number but got Some 1")),
+    (OPTION, r#""a" != None"#, Err("expected a string but got None")),
+    ("", "(|x| x) == 1", Err("expected a number but got <fun>")),
+
+    // lists
+    ("", "[]", Ok("[]")),
+    ("", "[1, 2 + 3, [4]]", Ok("[1, 5, [4]]")),
+    ("", r#"1 :: 2 :: ["three"]"#, Ok(r#"[1, 2, "three"]"#)),
+    ("", "(empty? []) + (empty? [1]) * 10 + head (tail [1, 2, 3])", Ok("3")),
+    (LIST_FUNCTIONS, "len [1, 2, 3]", Ok("3")),
+    (LIST_FUNCTIONS, "map (|x| x * x) (range 0 5)", Ok("[0, 1, 4, 9, 16]")),
+    (LIST_FUNCTIONS, "fold (|a, x| a + x) 0 (range 1 101)", Ok("5050")),
+    (BUILD, "fold (|n, x| n + 1) 0 (build [] 100000)", Ok("100000")),
+    (BUILD, "1 + build [] 100000", Err("expected a number but got [1, 2, 3, ")),
+    ("", "head []", Err("the list is empty")),
+    ("", "tail 1", Err("expected a list but got 1")),
+    ("", "1 :: 2", Err("expected a list but got 2")),
+
+    // constructors and match
+    (OPTION, "Some (Some (-1))", Ok("Some (Some (-1))")),
+    (OPTION, r#"[None, Some "a"]"#, Ok(r#"[None, Some "a"]"#)),
+    (OPTION, "Some", Ok("<fun>")),
+    ("x = Some 1; type Option = None | Some v;", "x", Ok("Some 1")),
+    (OPTION, "get = |o| match o with | Some (Some x) -> x | Some None -> 0 | None -> -1 end; get (Some (Some 5)) + get (Some None) + get None", Ok("4")),
+    (OPTION, r#"match Some 3 with | Some 2 -> "two" | Some _ -> "other" | _ -> "none" end"#, Ok("other")),
+    ("type Pair = Pair a b;", r#"match Pair "x" 1 with | Pair "y" n -> n | Pair "x" n -> n + 10 end"#, Ok("11")),
+    (r#"name = |n| match n with | 0 -> "zero" | -1 -> "minus one" | _ -> "many" end;"#, "name 0 ++ name (-1) ++ name 7", Ok("zerominus onemany")),
+    ("k = 2; f = |p| match p with | (a) -> let b = a * k in match b with | 4 -> a + b | c -> c end end;", "f 2 + f 3", Ok("12")),
+    (LIST_TYPE, "sum 0 (build Nil 10000)", Ok("50005000")),
+    // constructors of another type match none of the patterns, even when these are all of theirs
+    ("type A = X | Y; type B = P | Q;", "match X with | P -> 1 | Q -> 2 end", Err("no pattern matches X")),
+    ("type A = X | Y; type B = P | Q;", "match X with | P -> 1 | Q -> 2 | _ -> 3 end", Ok("3")),
+    (OPTION, "match Some 1 with | None -> 0 end", Err("no pattern matches Some 1")),
+    ("", "match 1 with | Some x -> x end", Err("unknown constructor 'Some'")),
+    (OPTION, "match None with | Some -> 0 end", Err("constructor 'Some' has 1 fields but the pattern gives 0")),
+    (OPTION, "match 1 with | None -> 0 end", Err("expected a value built by a constructor but got 1")),
+];
+
+fn interpret(source : &str) -> Result<String, String> {
+    let ast = block_to_ast(parser::parse_source("agreement.sm", source).unwrap());
+    Interpreter::new().eval(ast).map(|v| v.to_string()).map_err(|e| {
+        let e = e.to_string();
+        let first_line = e.lines().next().unwrap_or_default();
+        first_line[..first_line.rfind(" at ").unwrap()].to_string()
+    })
+}
+
+fn compile_and_run(source : &str) -> Result<String, String> {
+    let ast = block_to_ast(parser::parse_source("agreement.sm", source).unwrap());
+    let mut code = vec![];
+    compiler::compile_block(&ast, &mut code).map_err(|e| {
+        let e = e.to_string();
+        e[e.find(": ").unwrap() + 2..].to_string()
+    })?;
+    vm::process(&code).map(|v| v.to_string()).map_err(|e| {
+        let e = e.to_string();
+        e[..e.find(" at pc ").unwrap()].to_string()
+    })
+}
+
+fn ends_as(outcome : &Result<String, String>, expected : Result<&str, &str>) -> bool {
+    match (outcome, expected) {
+        (Ok(v), Ok(expected)) => v == expected,
+        (Err(e), Err(expected)) => e.starts_with(expected),
+        _ => false,
+    }
+}
+
+#[test]
+fn engines_agree_test() {
+    for &(prelude, exp, expected) in CASES {
+        let source = format!("{{ {} {} }}", prelude, exp);
+        let interpreted = interpret(&source);
+        let compiled = compile_and_run(&source);
+        assert!(ends_as(&interpreted, expected), "interpreter: {} gave {:?}", source, interpreted);
+        assert!(ends_as(&compiled, expected), "vm: {} gave {:?}", source, compiled);
+        assert_eq!(interpreted, compiled, "{}", source);
+    }
+}
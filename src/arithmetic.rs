@@ -0,0 +1,67 @@
+// Integer arithmetic on i32, with the choice of what an overflow does
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Overflow {
+    #[default]
+    Checked,  // overflow is an error
+    Wrapping, // results wrap around in two's complement
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArithError {
+    Overflow,
+    DivisionByZero,
+}
+
+// Division by zero is an error whatever the overflow mode is
+pub fn apply(overflow : Overflow, op : BinOp, n1 : i32, n2 : i32) -> Result<i32, ArithError> {
+    if op == BinOp::Div && n2 == 0 {
+        return Err(ArithError::DivisionByZero);
+    }
+
+    match overflow {
+        Overflow::Checked => {
+            let result = match op {
+                BinOp::Add => n1.checked_add(n2),
+                BinOp::Sub => n1.checked_sub(n2),
+                BinOp::Mul => n1.checked_mul(n2),
+                BinOp::Div => n1.checked_div(n2),
+            };
+            result.ok_or(ArithError::Overflow)
+        },
+        Overflow::Wrapping => {
+            Ok(match op {
+                BinOp::Add => n1.wrapping_add(n2),
+                BinOp::Sub => n1.wrapping_sub(n2),
+                BinOp::Mul => n1.wrapping_mul(n2),
+                BinOp::Div => n1.wrapping_div(n2),
+            })
+        },
+    }
+}
+
+
+#[test]
+fn checked_test() {
+    assert_eq!(apply(Overflow::Checked, BinOp::Mul, 6, 7), Ok(42));
+    assert_eq!(apply(Overflow::Checked, BinOp::Add, i32::MAX, 1), Err(ArithError::Overflow));
+    assert_eq!(apply(Overflow::Checked, BinOp::Sub, i32::MIN, 1), Err(ArithError::Overflow));
+    assert_eq!(apply(Overflow::Checked, BinOp::Div, i32::MIN, -1), Err(ArithError::Overflow));
+    assert_eq!(apply(Overflow::Checked, BinOp::Div, 1, 0), Err(ArithError::DivisionByZero));
+}
+
+#[test]
+fn wrapping_test() {
+    assert_eq!(apply(Overflow::Wrapping, BinOp::Add, i32::MAX, 1), Ok(i32::MIN));
+    assert_eq!(apply(Overflow::Wrapping, BinOp::Mul, 0x10000, 0x10000), Ok(0));
+    assert_eq!(apply(Overflow::Wrapping, BinOp::Div, i32::MIN, -1), Ok(i32::MIN));
+    assert_eq!(apply(Overflow::Wrapping, BinOp::Div, 1, 0), Err(ArithError::DivisionByZero));
+}
@@ -0,0 +1,70 @@
+// Functions every program can call without defining them
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Builtin {
+    Length,    // length s: number of characters in s
+    Substring, // substring s start len: the len characters of s from the start-th one
+    Head,      // head l: first item of a list that is not empty
+    Tail,      // tail l: the items of a list that is not empty but the first
+    IsEmpty,   // empty? l: 1 if the list has no items, 0 otherwise
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuiltinError {
+    IndexOutOfRange,
+    EmptyList,
+}
+
+impl Builtin {
+    pub fn from_name(name : &str) -> Option<Builtin> {
+        match name {
+            "length" => Some(Builtin::Length),
+            "substring" => Some(Builtin::Substring),
+            "head" => Some(Builtin::Head),
+            "tail" => Some(Builtin::Tail),
+            "empty?" => Some(Builtin::IsEmpty),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Length => "length",
+            Builtin::Substring => "substring",
+            Builtin::Head => "head",
+            Builtin::Tail => "tail",
+            Builtin::IsEmpty => "empty?",
+        }
+    }
+
+    // number of arguments taken before the builtin runs; it is curried like any function
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Length | Builtin::Head | Builtin::Tail | Builtin::IsEmpty => 1,
+            Builtin::Substring => 3,
+        }
+    }
+}
+
+// Strings are indexed by character, not by byte
+pub fn length(s : &str) -> i32 {
+    s.chars().count() as i32
+}
+
+pub fn substring(s : &str, start : i32, len : i32) -> Result<String, BuiltinError> {
+    if start < 0 || len < 0 || start as i64 + len as i64 > length(s) as i64 {
+        return Err(BuiltinError::IndexOutOfRange);
+    }
+    Ok(s.chars().skip(start as usize).take(len as usize).collect())
+}
+
+
+#[test]
+fn string_test() {
+    assert_eq!(length("héllo"), 5);
+    assert_eq!(substring("héllo", 1, 3), Ok("éll".to_string()));
+    assert_eq!(substring("héllo", 5, 0), Ok("".to_string()));
+    assert_eq!(substring("héllo", 4, 2), Err(BuiltinError::IndexOutOfRange));
+    assert_eq!(substring("héllo", -1, 2), Err(BuiltinError::IndexOutOfRange));
+    assert_eq!(substring("héllo", 0, i32::MAX), Err(BuiltinError::IndexOutOfRange));
+}
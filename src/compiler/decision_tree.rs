@@ -0,0 +1,183 @@
+// Compiles the arms of a match into a decision tree, which tests each part of the value
+// at most once on the way to the arm that matches (Maranget, "Compiling Pattern Matching
+// to Good Decision Trees"). The rows of the pattern matrix are the arms, in order, and
+// its columns the parts of the value that are still to be tested.
+use std::collections::HashMap;
+use std::rc::Rc;
+use parser::syntax::{Constructor, Pattern};
+use compiler::CompileError;
+
+// Path to a part of the matched value: the fields to take one after the other
+pub type Occurrence = Vec<usize>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Test {
+    Constructor(Rc<Constructor>),
+    Num(i32),
+    Str(Rc<str>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Tree {
+    Fail,                                       // no arm matches
+    Leaf(usize, Vec<(String, Occurrence)>),     // arm to take and the parts of the value its variables are bound to
+    Switch(Occurrence, Vec<(Test, Tree)>, Box<Tree>), // the first test that passes decides, then the default
+}
+
+#[derive(Clone)]
+struct Row<'a> {
+    columns: Vec<Option<&'a Pattern>>, // None matches anything without binding it
+    bindings: Vec<(String, Occurrence)>,
+    arm: usize,
+}
+
+// Checks that every constructor of the patterns exists and gets all its fields,
+// so that building the tree cannot fail
+fn check(pattern : &Pattern, constructors : &HashMap<String, Rc<Constructor>>) -> Result<(), CompileError> {
+    if let Pattern::Constructor(name, patterns, span) = pattern {
+        let constructor = constructors.get(name).ok_or_else(|| CompileError::UnknownConstructor(name.clone(), *span))?;
+        if patterns.len() != constructor.arity() {
+            return Err(CompileError::ConstructorArity(name.clone(), constructor.arity(), patterns.len(), *span));
+        }
+        for pattern in patterns {
+            check(pattern, constructors)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn build<'a>(patterns : impl Iterator<Item = &'a Pattern>, constructors : &HashMap<String, Rc<Constructor>>) -> Result<Tree, CompileError> {
+    let mut rows = vec![];
+    for (arm, pattern) in patterns.enumerate() {
+        check(pattern, constructors)?;
+        rows.push(Row{columns: vec![Some(pattern)], bindings: vec![], arm});
+    }
+    Ok(build_tree(vec![vec![]], rows, constructors))
+}
+
+fn head(pattern : &Pattern, constructors : &HashMap<String, Rc<Constructor>>) -> Test {
+    match pattern {
+        Pattern::Constructor(name, _, _) => Test::Constructor(constructors[name].clone()),
+        Pattern::Num(num, _) => Test::Num(*num),
+        Pattern::Str(text, _) => Test::Str(text.clone()),
+        Pattern::Wildcard(_) | Pattern::Var(_, _) => unreachable!("variables are not tests"),
+    }
+}
+
+fn build_tree(occurrences : Vec<Occurrence>, mut rows : Vec<Row>, constructors : &HashMap<String, Rc<Constructor>>) -> Tree {
+    // a variable matches anything, and binds the part of the value in its column
+    for row in &mut rows {
+        for (column, occurrence) in row.columns.iter_mut().zip(&occurrences) {
+            match column {
+                Some(Pattern::Var(name, _)) => {
+                    row.bindings.push((name.clone(), occurrence.clone()));
+                    *column = None;
+                },
+                Some(Pattern::Wildcard(_)) => *column = None,
+                _ => (),
+            }
+        }
+    }
+
+    let first = match rows.first() {
+        Some(first) => first,
+        None => return Tree::Fail,
+    };
+    // the first row matches when it has nothing left to test
+    let i = match first.columns.iter().position(|column| column.is_some()) {
+        Some(i) => i,
+        None => return Tree::Leaf(first.arm, first.bindings.clone()),
+    };
+
+    let mut tests : Vec<Test> = vec![];
+    for row in &rows {
+        if let Some(pattern) = row.columns[i] {
+            let test = head(pattern, constructors);
+            if !tests.contains(&test) {
+                tests.push(test);
+            }
+        }
+    }
+
+    let mut cases = vec![];
+    for test in &tests {
+        let arity = match test {
+            Test::Constructor(constructor) => constructor.arity(),
+            Test::Num(_) | Test::Str(_) => 0,
+        };
+        let mut specialized_occurrences = occurrences.clone();
+        let occurrence = specialized_occurrences.remove(i);
+        let fields = (0..arity).map(|k| occurrence.iter().cloned().chain(Some(k)).collect());
+        specialized_occurrences.splice(i..i, fields);
+
+        let specialized = rows.iter().filter_map(|row| {
+            let fields = match row.columns[i] {
+                None => vec![None; arity],
+                Some(pattern) if head(pattern, constructors) == *test => match pattern {
+                    Pattern::Constructor(_, patterns, _) => patterns.iter().map(Some).collect(),
+                    _ => vec![],
+                },
+                Some(_) => return None,
+            };
+            let mut row = row.clone();
+            row.columns.splice(i..=i, fields);
+            Some(row)
+        }).collect();
+        cases.push((test.clone(), build_tree(specialized_occurrences, specialized, constructors)));
+    }
+
+    // Programs are run without checking their types, so even when the constructors of the
+    // column are all there, a value of another type passes none of them and goes on to the
+    // rows below, as it would when the arms are tried in order
+    let mut default_occurrences = occurrences.clone();
+    default_occurrences.remove(i);
+    let rows = rows.iter().filter(|row| row.columns[i].is_none()).map(|row| {
+        let mut row = row.clone();
+        row.columns.remove(i);
+        row
+    }).collect();
+    let default = build_tree(default_occurrences, rows, constructors);
+    Tree::Switch(occurrences[i].clone(), cases, Box::new(default))
+}
+
+#[cfg(test)]
+use parser;
+#[cfg(test)]
+use parser::syntax::{ExpAst, StatementAst, BlockAst};
+
+#[test]
+fn decision_tree_test() {
+    let source = "{ type Option = None | Some x;
+        match 1 with | Some (Some x) -> x | Some None -> 0 | _ -> 1 end }";
+    let BlockAst::Block(statements) = parser::syntax::block_to_ast(parser::parse_source("tree.sm", source).unwrap());
+    let mut constructors = HashMap::new();
+    if let StatementAst::Type(_, declared, _) = &statements[0] {
+        for constructor in declared {
+            constructors.insert(constructor.name.clone(), Rc::new(constructor.clone()));
+        }
+    }
+    let arms = match &statements[1] {
+        StatementAst::Exp(exp, _) => match &**exp {
+            ExpAst::Match(_, arms, _) => arms,
+            e => panic!("expected a match but got {:?}", e),
+        },
+        s => panic!("expected an expression but got {:?}", s),
+    };
+    let none = Test::Constructor(constructors["None"].clone());
+    let some = Test::Constructor(constructors["Some"].clone());
+
+    // the root is tested once, and anything but Some goes straight to the wildcard
+    let tree = build(arms.iter().map(|(pattern, _)| pattern), &constructors).unwrap();
+    assert_eq!(tree, Tree::Switch(vec![], vec![
+        (some.clone(), Tree::Switch(vec![0], vec![
+            (some, Tree::Leaf(0, vec![("x".to_string(), vec![0, 0])])),
+            (none, Tree::Leaf(1, vec![])),
+        ], Box::new(Tree::Leaf(2, vec![])))),
+    ], Box::new(Tree::Leaf(2, vec![]))));
+
+    let arms = [parser::syntax::Pattern::Constructor("Some".to_string(), vec![], parser::syntax::Span::default())];
+    match build(arms.iter(), &constructors) {
+        Err(CompileError::ConstructorArity(name, 1, 0, _)) => assert_eq!(name, "Some"),
+        e => panic!("expected an arity error but got {:?}", e),
+    }
+}
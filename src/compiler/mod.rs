@@ -1,69 +1,659 @@
+pub mod decision_tree;
+use compiler::decision_tree::{Test, Tree};
 use parser::syntax::ExpAst;
 use parser::syntax::StatementAst;
 use parser::syntax::BlockAst;
+use parser::syntax::Span;
+use parser::syntax::CmpOp;
+use parser::syntax::{Constructor, Pattern};
 use vm;
+use builtins::Builtin;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
 
-pub fn compile(ast : &ExpAst, code : &mut Vec<vm::Operator>) {
+#[derive(Debug)]
+pub enum CompileError {
+    UnboundVariable(String, Span),
+    UnknownConstructor(String, Span),
+    ConstructorArity(String, usize, usize, Span), // constructor, number of fields it has and number the pattern gives
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UnboundVariable(name, span) => write!(f, "{:?}: unbound variable '{}'", span.start, name),
+            CompileError::UnknownConstructor(name, span) => write!(f, "{:?}: unknown constructor '{}'", span.start, name),
+            CompileError::ConstructorArity(name, arity, count, span) => {
+                write!(f, "{:?}: constructor '{}' has {} fields but the pattern gives {}", span.start, name, arity, count)
+            },
+        }
+    }
+}
+
+// Tracks where variables live on the VM stack while code is being generated
+#[derive(Clone)]
+pub struct Scope {
+    globals: Vec<String>,          // slot i is the i-th item from the bottom of the stack
+    locals: Vec<(String, usize)>,  // name and slot, slot i being the i-th item from the frame pointer
+    captured: Vec<String>,         // slot i is the i-th captured value of the running closure
+    recursive: Option<String>,     // name by which the running closure refers to itself
+    depth: usize,                  // number of items above the frame pointer at the current point
+    top_level: bool,               // the top-level frame starts at the bottom of the stack, so its first locals are the globals
+    constructors: HashMap<String, Rc<Constructor>>, // of every type declared in the block
+}
+impl Scope {
+    pub fn new() -> Scope {
+        Scope{globals: vec![], locals: vec![], captured: vec![], recursive: None, depth: 0, top_level: true, constructors: HashMap::new()}
+    }
+
+    // scope for the body of a function, whose argument is the first item of the frame
+    fn function(&self, arg : &str, captured : Vec<String>, recursive : Option<&str>) -> Scope {
+        Scope{
+            globals: self.globals.clone(),
+            locals: vec![(arg.to_string(), 0)],
+            captured,
+            recursive: recursive.map(|name| name.to_string()),
+            depth: 1,
+            top_level: false,
+            constructors: self.constructors.clone(),
+        }
+    }
+
+    // globals are reachable from anywhere, so only local and captured variables need capturing
+    fn is_capturable(&self, name : &str) -> bool {
+        let local = self.locals.iter().rev().find(|(l, _)| l == name);
+        local.is_some_and(|&(_, slot)| !self.top_level || slot >= self.globals.len())
+            || self.captured.iter().any(|c| c == name)
+            || self.recursive.as_ref().is_some_and(|r| r == name)
+    }
+
+    fn declare_constructors(&mut self, constructors : &[Constructor]) {
+        for constructor in constructors {
+            self.constructors.insert(constructor.name.clone(), Rc::new(constructor.clone()));
+        }
+    }
+
+    fn declare_global(&mut self, name : &str) {
+        self.locals.push((name.to_string(), self.globals.len()));
+        self.globals.push(name.to_string());
+    }
+
+    // the value on top of the stack becomes a local variable
+    fn declare_local(&mut self, name : &str) {
+        self.locals.push((name.to_string(), self.depth - 1));
+    }
+
+    // distance from the top of the stack, as expected by Load and Store
+    fn local_offset(&self, name : &str) -> Option<usize> {
+        let &(_, slot) = self.locals.iter().rev().find(|(l, _)| l == name)?;
+        Some(self.depth - slot - 1)
+    }
+
+    fn load(&mut self, name : &str, span : Span, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
+        if let Some(n) = self.local_offset(name) {
+            self.push(code, vm::Operator::Load(n));
+        }
+        else if let Some(slot) = self.captured.iter().position(|c| c == name) {
+            self.push(code, vm::Operator::LoadCaptured(slot));
+        }
+        else if self.recursive.as_ref().is_some_and(|r| r == name) {
+            self.push(code, vm::Operator::LoadClosure);
+        }
+        else if let Some(slot) = self.globals.iter().position(|g| g == name) {
+            self.push(code, vm::Operator::LoadGlobal(slot));
+        }
+        else if let Some(builtin) = Builtin::from_name(name) {
+            self.push(code, vm::Operator::PushBuiltin(builtin));
+        }
+        else {
+            return Err(CompileError::UnboundVariable(name.to_string(), span));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, code : &mut Vec<vm::Operator>, op : vm::Operator) {
+        match op {
+            vm::Operator::PushInt32(_) | vm::Operator::PushStr(_) | vm::Operator::PushBuiltin(_) => self.depth += 1,
+            vm::Operator::PushNil | vm::Operator::PushConstructor(_) => self.depth += 1,
+            vm::Operator::LoadCaptured(_) => self.depth += 1,
+            vm::Operator::Load(_) | vm::Operator::LoadGlobal(_) | vm::Operator::LoadClosure => self.depth += 1,
+            vm::Operator::MakeClosure(_, n) => self.depth = self.depth - n + 1,
+            vm::Operator::Pop | vm::Operator::JumpIf(_) | vm::Operator::JumpUnless(_) => self.depth -= 1,
+            vm::Operator::Add | vm::Operator::Sub | vm::Operator::Mul | vm::Operator::Div => self.depth -= 1,
+            vm::Operator::Concat | vm::Operator::Cons => self.depth -= 1,
+            vm::Operator::Equal | vm::Operator::Less | vm::Operator::Greater => self.depth -= 1,
+            vm::Operator::Call | vm::Operator::TailCall => self.depth -= 1,
+            vm::Operator::MatchFailure => self.depth -= 1,
+            _ => (),
+        }
+        code.push(op);
+    }
+}
+
+impl Default for Scope {
+    fn default() -> Scope {
+        Scope::new()
+    }
+}
+
+// Collects the variables used in the expression but not bound in it, in order of appearance
+fn free_variables(ast : &ExpAst, bound : &mut Vec<String>, free : &mut Vec<String>) {
+    match ast {
+        ExpAst::Add(t1, t2, _) | ExpAst::Sub(t1, t2, _) | ExpAst::Mul(t1, t2, _) | ExpAst::Div(t1, t2, _) | ExpAst::App(t1, t2, _) |
+        ExpAst::Compare(_, t1, t2, _) | ExpAst::And(t1, t2, _) | ExpAst::Or(t1, t2, _) | ExpAst::Concat(t1, t2, _) |
+        ExpAst::Cons(t1, t2, _) => {
+            free_variables(t1, bound, free);
+            free_variables(t2, bound, free);
+        },
+        ExpAst::Not(t, _) | ExpAst::Neg(t, _) => free_variables(t, bound, free),
+        ExpAst::Var(name, _) => {
+            if !bound.contains(name) && !free.contains(name) {
+                free.push(name.clone());
+            }
+        },
+        ExpAst::Num(_, _) | ExpAst::Str(_, _) | ExpAst::Nil(_) | ExpAst::Constructor(_, _) => (),
+        ExpAst::Fun(arg, body, _) => {
+            bound.push(arg.clone());
+            free_variables(body, bound, free);
+            bound.pop();
+        },
+        ExpAst::If(cond_exp, then_exp, else_exp, _) => {
+            free_variables(cond_exp, bound, free);
+            free_variables(then_exp, bound, free);
+            free_variables(else_exp, bound, free);
+        },
+        ExpAst::Let(name, value, body, _) => {
+            free_variables(value, bound, free);
+            bound.push(name.clone());
+            free_variables(body, bound, free);
+            bound.pop();
+        },
+        ExpAst::LetRec(name, value, body, _) => {
+            bound.push(name.clone());
+            free_variables(value, bound, free);
+            free_variables(body, bound, free);
+            bound.pop();
+        },
+        ExpAst::Match(exp, arms, _) => {
+            free_variables(exp, bound, free);
+            for (pattern, body) in arms {
+                let len = bound.len();
+                pattern.variables(bound);
+                free_variables(body, bound, free);
+                bound.truncate(len);
+            }
+        },
+    }
+}
+
+pub fn compile(ast : &ExpAst, scope : &mut Scope, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
+    compile_exp(ast, scope, code, false)
+}
+
+// An expression in tail position is the last thing its function evaluates, so a call
+// there does not need a frame of its own. Top-level code has no frame to reuse.
+fn compile_exp(ast : &ExpAst, scope : &mut Scope, code : &mut Vec<vm::Operator>, tail : bool) -> Result<(), CompileError> {
     match ast {
-        ExpAst::Add(t1, t2) => {
-            compile(t1, code);
-            compile(t2, code);
-            code.push(vm::Operator::Add);
+        ExpAst::Add(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            scope.push(code, vm::Operator::Add);
+        },
+        ExpAst::Sub(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            scope.push(code, vm::Operator::Sub);
+        },
+        ExpAst::Mul(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            scope.push(code, vm::Operator::Mul);
+        },
+        ExpAst::Div(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            scope.push(code, vm::Operator::Div);
+        },
+        ExpAst::App(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            let call = if tail && !scope.top_level { vm::Operator::TailCall } else { vm::Operator::Call };
+            scope.push(code, call);
         },
-        ExpAst::Sub(t1, t2) => {
-            compile(t1, code);
-            compile(t2, code);
-            code.push(vm::Operator::Sub);
+        ExpAst::Var(name, span) => scope.load(name, *span, code)?,
+        ExpAst::Num(num, _) => scope.push(code, vm::Operator::PushInt32(*num)),
+        ExpAst::Str(text, _) => scope.push(code, vm::Operator::PushStr(text.clone())),
+        ExpAst::Concat(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            scope.push(code, vm::Operator::Concat);
         },
-        ExpAst::Mul(t1, t2) => {
-            compile(t1, code);
-            compile(t2, code);
-            code.push(vm::Operator::Mul);
+        ExpAst::Nil(_) => scope.push(code, vm::Operator::PushNil),
+        ExpAst::Constructor(name, span) => {
+            let constructor = scope.constructors.get(name).ok_or_else(|| CompileError::UnknownConstructor(name.clone(), *span))?;
+            let constructor = vm::Constructor{name: Rc::from(name.as_str()), arity: constructor.arity()};
+            scope.push(code, vm::Operator::PushConstructor(Rc::new(constructor)));
         },
-        ExpAst::Div(t1, t2) => {
-            compile(t1, code);
-            compile(t2, code);
-            code.push(vm::Operator::Div);
+        ExpAst::Cons(t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            scope.push(code, vm::Operator::Cons);
         },
-        ExpAst::App(t1, t2) => {
-            // todo
+        ExpAst::Fun(arg, body, span) => compile_function(arg, body, *span, None, scope, code)?,
+        ExpAst::Compare(op, t1, t2, _) => {
+            compile(t1, scope, code)?;
+            compile(t2, scope, code)?;
+            match op {
+                CmpOp::Equal => scope.push(code, vm::Operator::Equal),
+                CmpOp::NotEqual => {
+                    scope.push(code, vm::Operator::Equal);
+                    scope.push(code, vm::Operator::Not);
+                },
+                CmpOp::Less => scope.push(code, vm::Operator::Less),
+                CmpOp::Greater => scope.push(code, vm::Operator::Greater),
+                CmpOp::LessEqual => {
+                    scope.push(code, vm::Operator::Greater);
+                    scope.push(code, vm::Operator::Not);
+                },
+                CmpOp::GreaterEqual => {
+                    scope.push(code, vm::Operator::Less);
+                    scope.push(code, vm::Operator::Not);
+                },
+            }
         },
-        ExpAst::Var(name) => {
-            // todo
+        ExpAst::Not(t, _) => {
+            compile(t, scope, code)?;
+            scope.push(code, vm::Operator::Not);
         },
-        ExpAst::Num(num) => code.push(vm::Operator::PushInt32(*num)),
-        ExpAst::Fun(_,_) => {
-            // todo
+        ExpAst::Neg(t, _) => {
+            scope.push(code, vm::Operator::PushInt32(0));
+            compile(t, scope, code)?;
+            scope.push(code, vm::Operator::Sub);
         },
-        ExpAst::If(cond_exp, then_exp, else_exp) => {
-            compile(cond_exp, code);
-            code.push(vm::Operator::PushInt32(0));
-            code.push(vm::Operator::Equal);
+        ExpAst::And(t1, t2, _) => compile_short_circuit(t1, t2, false, scope, code)?,
+        ExpAst::Or(t1, t2, _) => compile_short_circuit(t1, t2, true, scope, code)?,
+        ExpAst::If(cond_exp, then_exp, else_exp, _) => {
+            compile(cond_exp, scope, code)?;
+            scope.push(code, vm::Operator::PushInt32(0));
+            scope.push(code, vm::Operator::Equal);
 
             let mut then_code = vec![];
-            compile(then_exp, &mut then_code);
+            scope.depth -= 1; // JumpIf consumes the condition
+            compile_exp(then_exp, scope, &mut then_code, tail)?;
             let then_size = then_code.len();
 
             code.push(vm::Operator::JumpIf(then_size as isize + 2));
             code.append(&mut then_code);
 
+            // only one of the branches leaves its value on the stack
+            scope.depth -= 1;
             let mut else_code = vec![];
-            compile(else_exp, &mut else_code);
+            compile_exp(else_exp, scope, &mut else_code, tail)?;
             let else_size = else_code.len();
 
             code.push(vm::Operator::Jump(else_size as isize + 1));
             code.append(&mut else_code);
         },
+        ExpAst::Let(name, value, body, _) => {
+            compile(value, scope, code)?;
+            compile_let_body(name, body, scope, code, tail)?;
+        },
+        ExpAst::LetRec(name, value, body, _) => {
+            // the parser only accepts a function as the value
+            match &**value {
+                ExpAst::Fun(arg, fun_body, span) => compile_function(arg, fun_body, *span, Some(name), scope, code)?,
+                _ => compile(value, scope, code)?,
+            }
+            compile_let_body(name, body, scope, code, tail)?;
+        },
+        ExpAst::Match(exp, arms, _) => compile_match(exp, arms, scope, code, tail)?,
     };
+    Ok(())
+}
+
+// A function that is recursive refers to itself through the running closure,
+// since a closure cannot capture itself
+fn compile_function(arg : &str, body : &ExpAst, span : Span, recursive : Option<&str>, scope : &mut Scope, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
+    // closure conversion: the free variables of the body that are not globals
+    // are copied into the closure when it is created
+    let mut bound = vec![arg.to_string()];
+    bound.extend(recursive.map(|name| name.to_string()));
+    let mut free = vec![];
+    free_variables(body, &mut bound, &mut free);
+    let captured : Vec<String> = free.into_iter().filter(|v| scope.is_capturable(v)).collect();
+    for name in &captured {
+        scope.load(name, span, code)?;
+    }
+
+    // the body is placed right after the definition and skipped when it is evaluated
+    let captured_count = captured.len();
+    let mut body_scope = scope.function(arg, captured, recursive);
+    let mut body_code = vec![];
+    compile_exp(body, &mut body_scope, &mut body_code, true)?;
+    body_code.push(vm::Operator::Ret);
+
+    scope.push(code, vm::Operator::MakeClosure(2, captured_count));
+    code.push(vm::Operator::Jump(body_code.len() as isize + 1));
+    code.append(&mut body_code);
+    Ok(())
+}
+
+// The value of the let is on top of the stack. It stays there while the body is evaluated
+// and is then replaced by the value of the body. A call in tail position discards it with
+// the rest of the frame.
+fn compile_let_body(name : &str, body : &ExpAst, scope : &mut Scope, code : &mut Vec<vm::Operator>, tail : bool) -> Result<(), CompileError> {
+    scope.declare_local(name);
+    compile_exp(body, scope, code, tail)?;
+    scope.locals.pop();
+    scope.push(code, vm::Operator::Store(1));
+    scope.push(code, vm::Operator::Pop);
+    Ok(())
+}
+
+// The value being matched stays on the stack while the decision tree runs, and is then
+// replaced by the value of the arm that matched
+fn compile_match(exp : &ExpAst, arms : &[(Pattern, ExpAst)], scope : &mut Scope, code : &mut Vec<vm::Operator>, tail : bool) -> Result<(), CompileError> {
+    let tree = decision_tree::build(arms.iter().map(|(pattern, _)| pattern), &scope.constructors)?;
+    compile(exp, scope, code)?;
+    let root = scope.depth - 1;
+    let mut ends = vec![];
+    compile_tree(&tree, root, arms, scope, code, tail, &mut ends)?;
+    for end in ends {
+        code[end] = vm::Operator::Jump((code.len() - end) as isize);
+    }
+    Ok(())
+}
+
+// pushes the part of the matched value at the occurrence
+fn load_occurrence(occurrence : &[usize], root : usize, scope : &mut Scope, code : &mut Vec<vm::Operator>) {
+    scope.push(code, vm::Operator::Load(scope.depth - root - 1));
+    for &field in occurrence {
+        scope.push(code, vm::Operator::GetField(field));
+    }
 }
 
-pub fn compile_statement(ast : &StatementAst, code : &mut Vec<vm::Operator>) {
+// Each branch starts and ends with the matched value on top of the stack; the arms jump to
+// the end of the match once the value has been replaced
+fn compile_tree(tree : &Tree, root : usize, arms : &[(Pattern, ExpAst)], scope : &mut Scope, code : &mut Vec<vm::Operator>, tail : bool, ends : &mut Vec<usize>) -> Result<(), CompileError> {
+    let depth = scope.depth;
+    match tree {
+        Tree::Fail => {
+            load_occurrence(&[], root, scope, code);
+            scope.push(code, vm::Operator::MatchFailure);
+        },
+        Tree::Leaf(arm, bindings) => {
+            for (name, occurrence) in bindings {
+                load_occurrence(occurrence, root, scope, code);
+                scope.declare_local(name);
+            }
+            compile_exp(&arms[*arm].1, scope, code, tail)?;
+            scope.locals.truncate(scope.locals.len() - bindings.len());
+            scope.push(code, vm::Operator::Store(bindings.len() + 1));
+            for _ in 0..=bindings.len() {
+                scope.push(code, vm::Operator::Pop);
+            }
+            ends.push(code.len());
+            code.push(vm::Operator::Jump(0)); // patched once the end of the match is known
+        },
+        Tree::Switch(occurrence, cases, default) => {
+            for (test, subtree) in cases {
+                load_occurrence(occurrence, root, scope, code);
+                match test {
+                    Test::Constructor(constructor) => {
+                        scope.push(code, vm::Operator::GetConstructor);
+                        scope.push(code, vm::Operator::PushStr(Rc::from(constructor.name.as_str())));
+                    },
+                    Test::Num(num) => scope.push(code, vm::Operator::PushInt32(*num)),
+                    Test::Str(text) => scope.push(code, vm::Operator::PushStr(text.clone())),
+                }
+                scope.push(code, vm::Operator::Equal);
+                let next = code.len();
+                scope.push(code, vm::Operator::JumpUnless(0));
+                compile_tree(subtree, root, arms, scope, code, tail, ends)?;
+                scope.depth = depth;
+                code[next] = vm::Operator::JumpUnless((code.len() - next) as isize);
+            }
+            compile_tree(default, root, arms, scope, code, tail, ends)?;
+        },
+    }
+    scope.depth = depth;
+    Ok(())
+}
+
+// && and || skip their right operand when the left one already decides the result,
+// which is pushed as 1 or 0 either way
+fn compile_short_circuit(t1 : &ExpAst, t2 : &ExpAst, or : bool, scope : &mut Scope, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
+    compile(t1, scope, code)?;
+    scope.depth -= 1; // the jump consumes the left operand
+
+    let mut right_code = vec![];
+    compile(t2, scope, &mut right_code)?;
+    right_code.push(vm::Operator::Not);
+    right_code.push(vm::Operator::Not);
+    let right_size = right_code.len() as isize;
+
+    code.push(if or { vm::Operator::JumpIf(right_size + 2) } else { vm::Operator::JumpUnless(right_size + 2) });
+    code.append(&mut right_code);
+    code.push(vm::Operator::Jump(2));
+
+    // only one of the right operand and the constant leaves its value on the stack
+    scope.depth -= 1;
+    scope.push(code, vm::Operator::PushInt32(or as i32));
+    Ok(())
+}
+
+// Leaves the value of the statement on top of the stack
+pub fn compile_statement(ast : &StatementAst, scope : &mut Scope, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
     match ast {
-        StatementAst::Exp(exp_ast) => compile(exp_ast, code),
-        StatementAst::Assign(name, exp_ast) => (),
+        StatementAst::Exp(exp_ast, _) => compile(exp_ast, scope, code),
+        StatementAst::Assign(name, exp_ast, span) => {
+            compile(exp_ast, scope, code)?;
+            // the value is on top of the stack, so the offset already counts it
+            let n = scope.local_offset(name).ok_or_else(|| CompileError::UnboundVariable(name.clone(), *span))?;
+            scope.push(code, vm::Operator::Store(n));
+            Ok(())
+        },
+        StatementAst::Type(_, constructors, _) => {
+            scope.declare_constructors(constructors);
+            scope.push(code, vm::Operator::PushInt32(0));
+            Ok(())
+        },
+    }
+}
+
+// Top-level assignments get a stack slot each, allocated before the first statement
+// so that functions can refer to globals defined later in the block. Constructors
+// are known from the start of the block for the same reason.
+// The value of the last statement is left on top of the stack.
+pub fn compile_block(ast : &BlockAst, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
+    compile_block_in(ast, &mut Scope::new(), code)
+}
+
+// Compiles a block that runs on top of the globals of the blocks compiled before it in the
+// same scope, as the lines of the REPL do. The scope keeps the new globals and constructors.
+pub fn compile_block_in(ast : &BlockAst, scope : &mut Scope, code : &mut Vec<vm::Operator>) -> Result<(), CompileError> {
+    let BlockAst::Block(statements) = ast;
+    scope.locals = scope.globals.iter().enumerate().map(|(slot, name)| (name.clone(), slot)).collect();
+    scope.depth = scope.globals.len();
+
+    for statement in statements {
+        match statement {
+            StatementAst::Assign(name, _, _) if !scope.globals.contains(name) => {
+                scope.declare_global(name);
+                scope.push(code, vm::Operator::PushInt32(0));
+            },
+            StatementAst::Type(_, constructors, _) => scope.declare_constructors(constructors),
+            _ => (),
+        }
+    }
+
+    for (i, statement) in statements.iter().enumerate() {
+        compile_statement(statement, scope, code)?;
+        if i + 1 < statements.len() {
+            scope.push(code, vm::Operator::Pop);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use parser;
+
+#[cfg(test)]
+fn run(input : &str) -> Option<vm::Data> {
+    let block = parser::Block::new().parse(&mut parser::combinator::Input::new(input)).ok()?;
+    let ast = parser::syntax::block_to_ast(block);
+    let mut code = vec![];
+    compile_block(&ast, &mut code).ok()?;
+    vm::process(&code).ok()
+}
+
+#[test]
+fn test_compile_arithmetic() {
+    assert_eq!(run("1 + 2 - 4"), Some(vm::Data::Num(-1)));
+    assert_eq!(run("if 1 - 1 then 10 else 20 end + 1"), Some(vm::Data::Num(21)));
+}
+
+#[test]
+fn test_compile_assignment() {
+    assert_eq!(run("{ x = 3; y = x + 1; x + y }"), Some(vm::Data::Num(7)));
+    assert_eq!(run("{ x = 3; x = x + 1 }"), Some(vm::Data::Num(4)));
+    assert_eq!(run("{ x = 2; if x - 2 then x else x + 10 end }"), Some(vm::Data::Num(12)));
+}
+
+#[test]
+fn test_compile_function_definition_sum() {
+    let v = run("{ sum = |n| if n then sum (n - 1) + n else 0 end; sum(10) }");
+    assert_eq!(v, Some(vm::Data::Num(55)));
+}
+
+#[test]
+fn test_compile_function_definition_fib() {
+    let v = run("{ fib = |n| if n then if n-1 then fib(n-1) + fib(n-2) else 1 end else 1 end; fib(6) }");
+    assert_eq!(v, Some(vm::Data::Num(13)));
+}
+
+#[test]
+fn test_compile_function_value() {
+    match run("{ id = |x| x; id }") {
+        Some(vm::Data::Fun(_)) => (),
+        v => panic!("expected a function but got {:?}", v),
     }
+    assert_eq!(run("{ twice = |f| f (f 3); inc = |x| x + 1; twice inc }"), Some(vm::Data::Num(5)));
+}
+
+#[test]
+fn test_compile_closure() {
+    assert_eq!(run("{ plus = |x| |y| x + y; plus 1 2 }"), Some(vm::Data::Num(3)));
+    assert_eq!(run("{ sum = |x| |y| |z| x + y - z; sum 1 2 3 }"), Some(vm::Data::Num(0)));
+    assert_eq!(run("{ plus = |x| |y| x + y; inc = plus 1; n = 10; inc n }"), Some(vm::Data::Num(11)));
+}
+
+#[test]
+fn test_compile_unbound_variable() {
+    let mut code = vec![];
+    let mut input = parser::combinator::Input::new("{ y = 1; 2 + x }");
+    let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+    match compile_block(&ast, &mut code) {
+        Err(CompileError::UnboundVariable(name, span)) => {
+            assert_eq!(name, "x");
+            assert_eq!((span.start.line, span.start.column), (1, 14));
+            assert_eq!((span.end.line, span.end.column), (1, 15));
+        },
+        _ => panic!("expected an unbound variable error"),
+    }
+}
+
+#[test]
+fn test_compile_tail_calls() {
+    let run_with_stats = |input : &str| {
+        let block = parser::Block::new().parse(&mut parser::combinator::Input::new(input)).unwrap();
+        let mut code = vec![];
+        compile_block(&parser::syntax::block_to_ast(block), &mut code).unwrap();
+        vm::process_with_stats(&code, &vm::Config::default())
+    };
+
+    let (v, stats) = run_with_stats("{ loop = |n| if n then loop (n - 1) else 0 end; loop 1000000 }");
+    assert_eq!(v, Ok(vm::Data::Num(0)));
+    assert_eq!(stats.max_frames, 1);
+    assert!(stats.max_stack < 10, "{:?}", stats);
+
+    let (v, stats) = run_with_stats("{ even = |n| if n then odd (n - 1) else 1 end; odd = |n| if n then even (n - 1) else 0 end; even 1000001 }");
+    assert_eq!(v, Ok(vm::Data::Num(0)));
+    assert_eq!(stats.max_frames, 1);
+
+    // curried calls only reuse the frame for the last application
+    let (v, stats) = run_with_stats("{ count = |acc| |n| if n then count (acc + 1) (n - 1) else acc end; count 0 1000 }");
+    assert_eq!(v, Ok(vm::Data::Num(1000)));
+    assert_eq!(stats.max_frames, 2);
+
+    // calls that are not in tail position still need a frame each
+    let (v, stats) = run_with_stats("{ sum = |n| if n then sum (n - 1) + n else 0 end; sum 100 }");
+    assert_eq!(v, Ok(vm::Data::Num(5050)));
+    assert_eq!(stats.max_frames, 101);
+}
+
+#[test]
+fn test_compile_comparison_and_boolean() {
+    assert_eq!(run("1 + 1 == 2"), Some(vm::Data::Num(1)));
+    assert_eq!(run("1 != 1"), Some(vm::Data::Num(0)));
+    assert_eq!(run("(1 < 2) + (2 <= 2) + (3 > 2) + (2 >= 3)"), Some(vm::Data::Num(3)));
+    assert_eq!(run("!0 && !(1 == 2) || 0"), Some(vm::Data::Num(1)));
+    assert_eq!(run("0 || 0 && 1"), Some(vm::Data::Num(0)));
+    assert_eq!(run("{ fib = |n| if n < 2 then 1 else fib (n - 1) + fib (n - 2) end; fib 10 }"), Some(vm::Data::Num(89)));
+    assert_eq!(run("{ x = 3; y = 0 && 1 / 0; z = 5 || 1 / 0; x + y + z }"), Some(vm::Data::Num(4)));
+}
+
+#[test]
+fn test_compile_negation() {
+    assert_eq!(run("-5"), Some(vm::Data::Num(-5)));
+    assert_eq!(run("- 5 * 2"), Some(vm::Data::Num(-10)));
+    assert_eq!(run("3 - -2"), Some(vm::Data::Num(5)));
+    assert_eq!(run("{ inc = |x| x + 1; -inc 2 * 3 }"), Some(vm::Data::Num(-9)));
+    assert_eq!(run("{ x = 4; -x }"), Some(vm::Data::Num(-4)));
+    assert_eq!(run("{ min = -2147483648; -min }"), None);
 }
 
-pub fn compile_block(ast : &BlockAst, code : &mut Vec<vm::Operator>) {
+#[test]
+fn test_compile_let_and_multi_parameter_functions() {
+    assert_eq!(run("{ f = |x, y, z| x - y * z; f 10 2 3 }"), Some(vm::Data::Num(4)));
+    assert_eq!(run("let x = 2 in x * x"), Some(vm::Data::Num(4)));
+    assert_eq!(run("1 + let x = 1 in let y = x + 1 in x + y"), Some(vm::Data::Num(4)));
+    assert_eq!(run("let x = 1 in let x = x + 10 in x"), Some(vm::Data::Num(11)));
+    assert_eq!(run("{ x = 5; y = let x = 1 in x; x + y }"), Some(vm::Data::Num(6)));
+    assert_eq!(run("let k = 3 in let add = |x| x + k in add 4"), Some(vm::Data::Num(7)));
+    assert_eq!(run("{ f = |x| let y = x * 2 in |z| x + y + z; f 1 10 }"), Some(vm::Data::Num(13)));
+
+    assert_eq!(run("let rec fact = |n| if n < 2 then 1 else n * fact (n - 1) end in fact 5"), Some(vm::Data::Num(120)));
+    assert_eq!(run("{ sum = |n| let rec go = |i, acc| if i > n then acc else go (i + 1) (acc + i) end in go 1 0; sum 10 }"), Some(vm::Data::Num(55)));
+    assert_eq!(run("{ fact = 0; let rec fact = |n| if n then n * fact (n - 1) else 1 end in fact 4 }"), Some(vm::Data::Num(24)));
 
-}
\ No newline at end of file
+    // the let value is part of the frame that a tail call replaces
+    let block = parser::Block::new().parse(&mut parser::combinator::Input::new("{ count = |n| let m = n - 1 in if m < 0 then 0 else count m end; count 100000 }")).unwrap();
+    let mut code = vec![];
+    compile_block(&parser::syntax::block_to_ast(block), &mut code).unwrap();
+    let (v, stats) = vm::process_with_stats(&code, &vm::Config::default());
+    assert_eq!(v, Ok(vm::Data::Num(0)));
+    assert!(stats.max_stack < 10, "{:?}", stats);
+}
+
+
+#[test]
+fn test_compile_match_errors() {
+    let compile_error = |source : &str| {
+        let ast = parser::syntax::block_to_ast(parser::parse_source("match.sm", source).unwrap());
+        compile_block(&ast, &mut vec![]).unwrap_err().to_string()
+    };
+    let option = "type Option = None | Some x;";
+    assert_eq!(compile_error("match 1 with | Some x -> x end"), "1:16: unknown constructor 'Some'");
+    assert_eq!(compile_error(&format!("{{ {} match None with | Some -> 0 end }}", option)), "1:50: constructor 'Some' has 1 fields but the pattern gives 0");
+
+    let mut code = vec![];
+    let ast = parser::syntax::block_to_ast(parser::parse_source("match.sm", &format!("{{ {} match Some 1 with | None -> 0 end }}", option)).unwrap());
+    compile_block(&ast, &mut code).unwrap();
+    match vm::process(&code) {
+        Err(vm::VmError{kind: vm::VmErrorKind::MatchFailure(v), ..}) => assert_eq!(v.to_string(), "Some 1"),
+        v => panic!("expected a match failure but got {:?}", v),
+    }
+}
@@ -0,0 +1,46 @@
+// Equality of two values, as == and != compare them
+
+// What equality can tell apart in a value
+pub enum Operand<'a> {
+    Num(i32),
+    Str(&'a str),
+    Other, // lists, functions and values built by constructors, which cannot be compared
+}
+
+// What equality needs from the values of an engine
+pub trait Value {
+    fn operand(&self) -> Operand<'_>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Mismatch {
+    Left(&'static str),  // what the left operand should have been
+    Right(&'static str), // what the right operand should have been
+}
+
+// The left operand decides what the right one must be: a number or a string like it.
+// Any other left operand is reported as not being a number.
+pub fn equal<T: Value>(left : &T, right : &T) -> Result<bool, Mismatch> {
+    match (left.operand(), right.operand()) {
+        (Operand::Num(n1), Operand::Num(n2)) => Ok(n1 == n2),
+        (Operand::Str(s1), Operand::Str(s2)) => Ok(s1 == s2),
+        (Operand::Num(_), _) => Err(Mismatch::Right("a number")),
+        (Operand::Str(_), _) => Err(Mismatch::Right("a string")),
+        (Operand::Other, _) => Err(Mismatch::Left("a number")),
+    }
+}
+
+
+#[test]
+fn equality_test() {
+    use std::rc::Rc;
+    use vm::Data;
+    let text = |s : &str| Data::Str(Rc::from(s));
+
+    assert_eq!(equal(&Data::Num(1), &Data::Num(1)), Ok(true));
+    assert_eq!(equal(&text("a"), &text("b")), Ok(false));
+    assert_eq!(equal(&Data::Num(1), &text("a")), Err(Mismatch::Right("a number")));
+    assert_eq!(equal(&text("a"), &Data::Nil), Err(Mismatch::Right("a string")));
+    assert_eq!(equal(&Data::Nil, &Data::Num(1)), Err(Mismatch::Left("a number")));
+    assert_eq!(equal(&Data::Nil, &Data::Nil), Err(Mismatch::Left("a number")));
+}
@@ -0,0 +1,320 @@
+// Static checks of the patterns of every match in a program, run before it is evaluated
+// or compiled. A match that some value gets through is reported with such a value, and an
+// arm that no value can reach is reported too. Both come from the usefulness of a pattern
+// with regard to the arms above it (Maranget, "Warnings for pattern matching").
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use parser::syntax::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WarningKind {
+    NonExhaustive(String), // a value that no arm matches
+    Unreachable,           // an arm that matches nothing the arms above it do not
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub span: Span, // the whole match, or the pattern of the arm
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            WarningKind::NonExhaustive(value) => write!(f, "warning: match does not cover {}", value)?,
+            WarningKind::Unreachable => write!(f, "warning: unreachable arm")?,
+        }
+        write!(f, " at {:?}", self.span)
+    }
+}
+
+// What a pattern tests for, with anything bound by a variable as a wildcard
+#[derive(Clone, PartialEq)]
+enum Head {
+    Constructor(Rc<Constructor>),
+    Num(i32),
+    Str(Rc<str>),
+}
+
+#[derive(Clone)]
+enum Pat {
+    Wildcard,
+    Head(Head, Vec<Pat>),
+}
+
+impl fmt::Display for Pat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pat::Wildcard => write!(f, "_"),
+            Pat::Head(Head::Num(num), _) => write!(f, "{}", num),
+            Pat::Head(Head::Str(text), _) => write!(f, "{:?}", text),
+            Pat::Head(Head::Constructor(constructor), fields) => {
+                write!(f, "{}", constructor.name)?;
+                for field in fields {
+                    match field {
+                        Pat::Head(Head::Constructor(_), fields) if !fields.is_empty() => write!(f, " ({})", field)?,
+                        Pat::Head(Head::Num(num), _) if *num < 0 => write!(f, " ({})", num)?,
+                        _ => write!(f, " {}", field)?,
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+// A row of the pattern matrix: the patterns still to be matched, from left to right
+type Row = Vec<Pat>;
+
+// Keeps the constructors of the types declared so far, like the interpreter does
+#[derive(Default)]
+pub struct Checker {
+    constructors: HashMap<String, Rc<Constructor>>,
+    types: HashMap<String, Vec<Rc<Constructor>>>, // every constructor of the type, by tag
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker::default()
+    }
+
+    // a later declaration of a type replaces the earlier one
+    pub fn declare(&mut self, type_name : &str, constructors : &[Constructor]) {
+        let constructors : Vec<Rc<Constructor>> = constructors.iter().cloned().map(Rc::new).collect();
+        for constructor in &constructors {
+            self.constructors.insert(constructor.name.clone(), constructor.clone());
+        }
+        self.types.insert(type_name.to_string(), constructors);
+    }
+
+    // Types are declared before anything is checked, so that a function can match on
+    // a type declared later in the block, as it can when compiled
+    pub fn check_block(&mut self, ast : &BlockAst) -> Vec<Warning> {
+        let BlockAst::Block(statements) = ast;
+        for statement in statements {
+            if let StatementAst::Type(type_name, constructors, _) = statement {
+                self.declare(type_name, constructors);
+            }
+        }
+        let mut warnings = vec![];
+        for statement in statements {
+            match statement {
+                StatementAst::Exp(exp, _) | StatementAst::Assign(_, exp, _) => self.check_exp(exp, &mut warnings),
+                StatementAst::Type(_, _, _) => (),
+            }
+        }
+        warnings
+    }
+
+    fn check_exp(&self, ast : &ExpAst, warnings : &mut Vec<Warning>) {
+        match ast {
+            ExpAst::Add(t1, t2, _) | ExpAst::Sub(t1, t2, _) | ExpAst::Mul(t1, t2, _) | ExpAst::Div(t1, t2, _) | ExpAst::App(t1, t2, _) |
+            ExpAst::Compare(_, t1, t2, _) | ExpAst::And(t1, t2, _) | ExpAst::Or(t1, t2, _) | ExpAst::Concat(t1, t2, _) |
+            ExpAst::Cons(t1, t2, _) | ExpAst::Let(_, t1, t2, _) | ExpAst::LetRec(_, t1, t2, _) => {
+                self.check_exp(t1, warnings);
+                self.check_exp(t2, warnings);
+            },
+            ExpAst::Not(t, _) | ExpAst::Neg(t, _) => self.check_exp(t, warnings),
+            ExpAst::Fun(_, body, _) => self.check_exp(body, warnings),
+            ExpAst::If(cond_exp, then_exp, else_exp, _) => {
+                self.check_exp(cond_exp, warnings);
+                self.check_exp(then_exp, warnings);
+                self.check_exp(else_exp, warnings);
+            },
+            ExpAst::Var(_, _) | ExpAst::Num(_, _) | ExpAst::Str(_, _) | ExpAst::Nil(_) | ExpAst::Constructor(_, _) => (),
+            ExpAst::Match(exp, arms, span) => {
+                self.check_exp(exp, warnings);
+                for (_, body) in arms {
+                    self.check_exp(body, warnings);
+                }
+                self.check_match(arms, *span, warnings);
+            },
+        }
+    }
+
+    fn check_match(&self, arms : &[(Pattern, ExpAst)], span : Span, warnings : &mut Vec<Warning>) {
+        // a pattern with an unknown constructor fails to run anyway, and is reported then
+        let rows : Option<Vec<Row>> = arms.iter().map(|(pattern, _)| self.pat(pattern).map(|pat| vec![pat])).collect();
+        let rows = match rows {
+            Some(rows) => rows,
+            None => return,
+        };
+
+        for (i, row) in rows.iter().enumerate() {
+            if !self.useful(&rows[..i], row) {
+                warnings.push(Warning{kind: WarningKind::Unreachable, span: arms[i].0.span()});
+            }
+        }
+        if let Some(mut witness) = self.witness(&rows, 1) {
+            warnings.push(Warning{kind: WarningKind::NonExhaustive(witness.remove(0).to_string()), span});
+        }
+    }
+
+    fn pat(&self, pattern : &Pattern) -> Option<Pat> {
+        match pattern {
+            Pattern::Wildcard(_) | Pattern::Var(_, _) => Some(Pat::Wildcard),
+            Pattern::Num(num, _) => Some(Pat::Head(Head::Num(*num), vec![])),
+            Pattern::Str(text, _) => Some(Pat::Head(Head::Str(text.clone()), vec![])),
+            Pattern::Constructor(name, patterns, _) => {
+                let constructor = self.constructors.get(name)?;
+                if patterns.len() != constructor.arity() {
+                    return None;
+                }
+                let fields = patterns.iter().map(|pattern| self.pat(pattern)).collect::<Option<Vec<Pat>>>()?;
+                Some(Pat::Head(Head::Constructor(constructor.clone()), fields))
+            },
+        }
+    }
+
+    // the heads in the first column, in order of appearance
+    fn heads(rows : &[Row]) -> Vec<Head> {
+        let mut heads = vec![];
+        for row in rows {
+            if let Pat::Head(head, _) = &row[0] {
+                if !heads.contains(head) {
+                    heads.push(head.clone());
+                }
+            }
+        }
+        heads
+    }
+
+    // every constructor of the type, when the heads are all of them and nothing else
+    fn complete(&self, heads : &[Head]) -> Option<Vec<Rc<Constructor>>> {
+        let type_name = match heads.first() {
+            Some(Head::Constructor(constructor)) if heads.len() == constructor.siblings => &constructor.type_name,
+            _ => return None,
+        };
+        let same_type = |head : &Head| matches!(head, Head::Constructor(c) if c.type_name == *type_name);
+        if heads.iter().all(same_type) { self.types.get(type_name).cloned() } else { None }
+    }
+
+    fn arity(head : &Head) -> usize {
+        match head {
+            Head::Constructor(constructor) => constructor.arity(),
+            Head::Num(_) | Head::Str(_) => 0,
+        }
+    }
+
+    // The rows that match a value with the head, with its fields in place of the first column
+    fn specialize(rows : &[Row], head : &Head) -> Vec<Row> {
+        let arity = Checker::arity(head);
+        rows.iter().filter_map(|row| {
+            let mut specialized = match &row[0] {
+                Pat::Wildcard => vec![Pat::Wildcard; arity],
+                Pat::Head(h, fields) if h == head => fields.clone(),
+                Pat::Head(_, _) => return None,
+            };
+            specialized.extend_from_slice(&row[1..]);
+            Some(specialized)
+        }).collect()
+    }
+
+    // The rows that match a value whose head is in none of the others
+    fn default_rows(rows : &[Row]) -> Vec<Row> {
+        rows.iter().filter(|row| matches!(row[0], Pat::Wildcard)).map(|row| row[1..].to_vec()).collect()
+    }
+
+    // Whether some value matched by the row is not matched by any of the rows above it
+    fn useful(&self, rows : &[Row], row : &Row) -> bool {
+        if row.is_empty() {
+            return rows.is_empty();
+        }
+        match &row[0] {
+            Pat::Head(head, _) => {
+                let specialized = Checker::specialize(std::slice::from_ref(row), head).remove(0);
+                self.useful(&Checker::specialize(rows, head), &specialized)
+            },
+            Pat::Wildcard => match self.complete(&Checker::heads(rows)) {
+                Some(constructors) => constructors.into_iter().any(|constructor| {
+                    let head = Head::Constructor(constructor);
+                    let specialized = Checker::specialize(std::slice::from_ref(row), &head).remove(0);
+                    self.useful(&Checker::specialize(rows, &head), &specialized)
+                }),
+                None => self.useful(&Checker::default_rows(rows), &row[1..].to_vec()),
+            },
+        }
+    }
+
+    // Values for n columns that none of the rows match, if there are any
+    fn witness(&self, rows : &[Row], n : usize) -> Option<Row> {
+        if n == 0 {
+            return if rows.is_empty() { Some(vec![]) } else { None };
+        }
+        let heads = Checker::heads(rows);
+        if let Some(constructors) = self.complete(&heads) {
+            return constructors.into_iter().find_map(|constructor| {
+                let head = Head::Constructor(constructor);
+                let arity = Checker::arity(&head);
+                let mut witness = self.witness(&Checker::specialize(rows, &head), arity + n - 1)?;
+                let rest = witness.split_off(arity);
+                Some(Some(Pat::Head(head, witness)).into_iter().chain(rest).collect())
+            });
+        }
+
+        let rest = self.witness(&Checker::default_rows(rows), n - 1)?;
+        let missing = self.missing(&heads);
+        Some(Some(missing).into_iter().chain(rest).collect())
+    }
+
+    // A value with a head that is not among the given ones, or anything if there are none
+    fn missing(&self, heads : &[Head]) -> Pat {
+        match heads.first() {
+            None => Pat::Wildcard,
+            Some(Head::Constructor(constructor)) => {
+                let siblings = self.types.get(&constructor.type_name).map_or(&[][..], |c| &c[..]);
+                match siblings.iter().find(|c| !heads.contains(&Head::Constructor((*c).clone()))) {
+                    Some(c) => Pat::Head(Head::Constructor(c.clone()), vec![Pat::Wildcard; c.arity()]),
+                    None => Pat::Wildcard,
+                }
+            },
+            Some(Head::Num(_)) => {
+                let num = (0..).find(|n| !heads.contains(&Head::Num(*n))).unwrap_or(0);
+                Pat::Head(Head::Num(num), vec![])
+            },
+            Some(Head::Str(_)) => {
+                let text = (0..).map(|n| "?".repeat(n)).find(|t| !heads.contains(&Head::Str(Rc::from(t.as_str())))).unwrap_or_default();
+                Pat::Head(Head::Str(Rc::from(text)), vec![])
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+use parser;
+
+#[cfg(test)]
+fn check(source : &str) -> Vec<String> {
+    let ast = block_to_ast(parser::parse_source("match.sm", source).unwrap());
+    Checker::new().check_block(&ast).iter().map(|w| w.to_string()).collect()
+}
+
+#[test]
+fn exhaustiveness_test() {
+    let types = "type Option = None | Some x; type List = Nil | Cons head tail;";
+    let warnings = |body : &str| check(&format!("{{ {} {} }}", types, body));
+
+    assert!(warnings("f = |o| match o with | Some (Cons x _) -> x | Some Nil -> 0 | None -> 1 end").is_empty());
+    assert!(warnings("f = |o| match o with | Some _ -> 0 | x -> 1 end").is_empty());
+    assert_eq!(warnings("f = |o| match o with | Some (Cons x Nil) -> x | Some Nil -> 0 | None -> 1 end"),
+        ["warning: match does not cover Some (Cons _ (Cons _ _)) at 1:74-1:143"]);
+    assert_eq!(warnings("f = |o| match o with | None -> 0 end"), ["warning: match does not cover Some _ at 1:74-1:102"]);
+    assert_eq!(warnings("f = |n| match n with | 0 -> 0 | 1 -> 1 end"), ["warning: match does not cover 2 at 1:74-1:108"]);
+    assert_eq!(warnings(r#"f = |s| match s with | "" -> 0 end"#), [r#"warning: match does not cover "?" at 1:74-1:100"#]);
+    assert_eq!(warnings("f = |p| match p with | Cons 1 (Cons (-2) _) -> 0 end"), ["warning: match does not cover Nil at 1:74-1:118"]);
+
+    assert_eq!(warnings("f = |o| match o with | _ -> 0 | None -> 1 end"), ["warning: unreachable arm at 1:98-1:102"]);
+    assert_eq!(warnings("f = |o| match o with | Some x -> 0 | None -> 1 | Some (Some _) -> 2 end"),
+        ["warning: unreachable arm at 1:115-1:128"]);
+    assert_eq!(warnings("f = |n| match n with | 1 -> 0 | 1 -> 1 | _ -> 2 end"), ["warning: unreachable arm at 1:98-1:99"]);
+
+    // matches inside arms and functions are checked, and types can be declared after their use
+    assert_eq!(check("{ f = |o| match o with | A -> match o with | B -> 1 end | B -> 2 end; type T = A | B }"),
+        ["warning: match does not cover A at 1:31-1:56"]);
+    // constructors of two types together are not all those of either
+    assert_eq!(warnings("f = |o| match o with | None -> 0 | Nil -> 1 end"), ["warning: match does not cover Some _ at 1:74-1:113"]);
+    // unknown constructors are left to the engines to report
+    assert!(check("match 1 with | Some x -> x end").is_empty());
+}
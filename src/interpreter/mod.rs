@@ -1,116 +1,657 @@
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+use std::rc::Rc;
 use parser::syntax::*;
+use arithmetic;
+use arithmetic::{ArithError, BinOp, Overflow};
+use builtins;
+use builtins::{Builtin, BuiltinError};
+use equality;
+use equality::Mismatch;
+use limits::{LimitError, Limits, Stats};
+use list;
 
-// todo: have to have a reference to the parent environment
-type Environment = HashMap<String, Data>;
+// Local variables, as a chain of scopes that each bind one name and point to the
+// scope they were created in. Extending the environment never copies it, so a closure
+// simply keeps the chain that was current when it was created.
+#[derive(Clone, Default)]
+pub struct Environment {
+    scope: Option<Rc<Scope>>,
+    callers: Option<Rc<Callers>>, // looked up after the chain
+}
+
+struct Scope {
+    name: String,
+    value: Data,
+    parent: Option<Rc<Scope>>,
+    recursive: bool, // the value is a function whose environment is this scope, with the callers kept in the value
+}
+
+// The chains of the callers whose locals a function body sees, innermost first. Only the
+// chain of each is kept: the callers of a caller are in the list already, and keeping
+// them with it would hold on to every call made before.
+struct Callers {
+    env: Environment, // with no callers of its own
+    next: Option<Rc<Callers>>,
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment::default()
+    }
+
+    pub fn bind(&self, name : String, value : Data) -> Environment {
+        Environment{scope: Some(Rc::new(Scope{name, value, parent: self.scope.clone(), recursive: false})), callers: self.callers.clone()}
+    }
+
+    // The environment of a call of a closure defined in this environment. The body also
+    // sees the locals of the caller that the closure does not bind itself, as it always
+    // has: after its own chain come the callers the closure saw when it was created, then
+    // the caller and its own callers. A chain whose names are all hidden by what comes
+    // before it is left out, so recursion does not make the list, or lookups, longer.
+    pub fn call(&self, var : String, arg : Data, caller : &Environment) -> Environment {
+        let mut seen : Vec<&str> = Some(var.as_str()).into_iter().chain(self.scopes().map(|scope| scope.name.as_str())).collect();
+        let mut kept : Vec<Environment> = self.callers().chain(Some(caller))
+            .filter(|env| env.shows_any(&mut seen))
+            .map(|env| Environment{scope: env.scope.clone(), callers: None})
+            .collect();
+        // the callers of the caller are shared from the last one left out on
+        let links : Vec<&Rc<Callers>> = iter::successors(caller.callers.as_ref(), |link| link.next.as_ref()).collect();
+        let shown : Vec<bool> = links.iter().map(|link| link.env.shows_any(&mut seen)).collect();
+        let mut tail = caller.callers.clone();
+        if let Some(last) = shown.iter().rposition(|&shown| !shown) {
+            kept.extend((0..last).filter(|&i| shown[i]).map(|i| links[i].env.clone()));
+            tail = links[last].next.clone();
+        }
+        let callers = kept.into_iter().rev().fold(tail, |next, env| Some(Rc::new(Callers{env, next})));
+        Environment{scope: self.scope.clone(), callers}.bind(var, arg)
+    }
+
+    fn scopes(&self) -> impl Iterator<Item = &Rc<Scope>> {
+        iter::successors(self.scope.as_ref(), |scope| scope.parent.as_ref())
+    }
+
+    fn callers(&self) -> impl Iterator<Item = &Environment> {
+        iter::successors(self.callers.as_deref(), |link| link.next.as_deref()).map(|link| &link.env)
+    }
+
+    // whether the chain binds a name that is not seen yet; its names are seen from then on
+    fn shows_any<'a>(&'a self, seen : &mut Vec<&'a str>) -> bool {
+        let mut shows = false;
+        for scope in self.scopes() {
+            if !seen.contains(&scope.name.as_str()) {
+                seen.push(&scope.name);
+                shows = true;
+            }
+        }
+        shows
+    }
+
+    // binds a function that can refer to itself by the name
+    pub fn bind_rec(&self, name : String, var : String, body : Rc<ExpAst>) -> Environment {
+        let value = Data::Fun(var, Environment{scope: None, callers: self.callers.clone()}, body);
+        Environment{scope: Some(Rc::new(Scope{name, value, parent: self.scope.clone(), recursive: true})), callers: self.callers.clone()}
+    }
+
+    // the innermost binding shadows the outer ones, and the locals of the callers come last
+    pub fn get(&self, name : &str) -> Option<Data> {
+        Some(self).into_iter().chain(self.callers()).find_map(|env| env.find(name))
+    }
+
+    // looks the name up in the chain only
+    fn find(&self, name : &str) -> Option<Data> {
+        let scope = self.scopes().find(|scope| scope.name == name)?;
+        // the closure of a recursive function is only built when it is looked up,
+        // as storing it in the scope would need a reference cycle
+        Some(match &scope.value {
+            Data::Fun(var, env, body) if scope.recursive => {
+                Data::Fun(var.clone(), Environment{scope: Some(scope.clone()), callers: env.callers.clone()}, body.clone())
+            },
+            value => value.clone(),
+        })
+    }
+
+    // number of scopes a lookup of an unbound name goes through
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        Some(self).into_iter().chain(self.callers()).map(|env| env.scopes().count()).sum()
+    }
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for env in Some(self).into_iter().chain(self.callers()) {
+            map.entries(env.scopes().map(|scope| (&scope.name, &scope.value)));
+        }
+        map.finish()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Data {
     Num(i32),
-    Fun(String, Environment, ExpAst),
+    Str(Rc<str>),
+    Nil,
+    Cons(Rc<ListCell>),
+    Fun(String, Environment, Rc<ExpAst>),
+    Builtin(Builtin, Vec<Data>), // with the arguments it was given so far
+    Adt(Rc<Constructor>, Rc<[Data]>), // with the fields it was given so far
+}
+
+pub type ListCell = list::ListCell<Data>;
+
+impl equality::Value for Data {
+    fn operand(&self) -> equality::Operand<'_> {
+        match self {
+            Data::Num(n) => equality::Operand::Num(*n),
+            Data::Str(text) => equality::Operand::Str(text),
+            _ => equality::Operand::Other,
+        }
+    }
+}
+
+impl list::Value for Data {
+    fn nil() -> Data {
+        Data::Nil
+    }
+
+    fn cell(&self) -> Option<&Rc<ListCell>> {
+        match self {
+            Data::Cons(cell) => Some(cell),
+            _ => None,
+        }
+    }
+
+    fn into_cell(self) -> Option<Rc<ListCell>> {
+        match self {
+            Data::Cons(cell) => Some(cell),
+            _ => None,
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Data::Str(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn is_compound(&self) -> bool {
+        match self {
+            Data::Adt(c, fields) => !fields.is_empty() || c.arity() > 0,
+            Data::Num(num) => *num < 0,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RuntimeErrorKind {
+    UnboundVariable(String),
+    NotAFunction(Data),
+    TypeMismatch(&'static str, Data), // expected kind of value and the value actually found
+    DivisionByZero,
+    Overflow,
+    EmptyBlock,
+    IndexOutOfRange,
+    EmptyList,
+    UnknownConstructor(String),
+    ConstructorArity(String, usize, usize), // constructor, number of fields it has and number the pattern gives
+    MatchFailure(Data),                     // value that no pattern of a match matched
+    MissingArgument(Builtin),               // builtin run before it was given any argument
+    Limit(LimitError),
+}
+
+// Reported when evaluation cannot go on, with the calls that were in progress
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub span: Span,                // expression that failed
+    pub trace: Vec<(String, Span)>, // called function and call site, innermost first
+}
+
+impl RuntimeError {
+    fn new(kind : RuntimeErrorKind, span : Span) -> RuntimeError {
+        RuntimeError{kind, span, trace: vec![]}
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            RuntimeErrorKind::UnboundVariable(name) => write!(f, "unbound variable '{}'", name)?,
+            RuntimeErrorKind::NotAFunction(v) => write!(f, "{} is not a function", v.quoted())?,
+            RuntimeErrorKind::TypeMismatch(expected, got) => write!(f, "expected {} but got {}", expected, got.quoted())?,
+            RuntimeErrorKind::DivisionByZero => write!(f, "division by zero")?,
+            RuntimeErrorKind::Overflow => write!(f, "integer overflow")?,
+            RuntimeErrorKind::EmptyBlock => write!(f, "block has no statements")?,
+            RuntimeErrorKind::IndexOutOfRange => write!(f, "index out of range")?,
+            RuntimeErrorKind::EmptyList => write!(f, "the list is empty")?,
+            RuntimeErrorKind::UnknownConstructor(name) => write!(f, "unknown constructor '{}'", name)?,
+            RuntimeErrorKind::ConstructorArity(name, arity, count) => {
+                write!(f, "constructor '{}' has {} fields but the pattern gives {}", name, arity, count)?
+            },
+            RuntimeErrorKind::MatchFailure(v) => write!(f, "no pattern matches {}", v.quoted())?,
+            RuntimeErrorKind::MissingArgument(builtin) => write!(f, "{} was run without an argument", builtin.name())?,
+            RuntimeErrorKind::Limit(e) => write!(f, "{}", e)?,
+        }
+        write!(f, " at {:?}", self.span)?;
+        for (name, span) in &self.trace {
+            write!(f, "\n  in {} called at {:?}", name, span)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::Num(num) => write!(f, "{}", num),
+            Data::Str(text) => write!(f, "{}", text),
+            Data::Nil | Data::Cons(_) => list::fmt_list(f, self),
+            Data::Fun(..) | Data::Builtin(..) => write!(f, "<fun>"),
+            Data::Adt(constructor, fields) => list::fmt_adt(f, &constructor.name, constructor.arity(), fields),
+        }
+    }
+}
+
+impl Data {
+    fn with_field(constructor : Rc<Constructor>, fields : &[Data], field : Data) -> Data {
+        let fields : Vec<Data> = fields.iter().cloned().chain(Some(field)).collect();
+        Data::Adt(constructor, Rc::from(fields))
+    }
+
+    pub fn quoted(&self) -> String {
+        list::quoted(self)
+    }
 }
 
+impl From<BuiltinError> for RuntimeErrorKind {
+    fn from(e : BuiltinError) -> RuntimeErrorKind {
+        match e {
+            BuiltinError::IndexOutOfRange => RuntimeErrorKind::IndexOutOfRange,
+            BuiltinError::EmptyList => RuntimeErrorKind::EmptyList,
+        }
+    }
+}
+
+impl From<ArithError> for RuntimeErrorKind {
+    fn from(e : ArithError) -> RuntimeErrorKind {
+        match e {
+            ArithError::Overflow => RuntimeErrorKind::Overflow,
+            ArithError::DivisionByZero => RuntimeErrorKind::DivisionByZero,
+        }
+    }
+}
+
+// What is left to do after evaluating an expression up to its tail position
+enum Step {
+    Done(Data),
+    Call(Rc<ExpAst>, Environment, (String, Span)), // body to evaluate in its environment, callee and call site
+}
+
+fn compare(op : CmpOp, n1 : i32, n2 : i32) -> bool {
+    match op {
+        CmpOp::Equal => n1 == n2,
+        CmpOp::NotEqual => n1 != n2,
+        CmpOp::Less => n1 < n2,
+        CmpOp::LessEqual => n1 <= n2,
+        CmpOp::Greater => n1 > n2,
+        CmpOp::GreaterEqual => n1 >= n2,
+    }
+}
+
+// Name to show in traces for the function called by an application
+fn callee_name(ast : &ExpAst) -> String {
+    match ast {
+        ExpAst::Var(name, _) => name.clone(),
+        ExpAst::App(t1, _, _) => callee_name(t1),
+        _ => "<fun>".to_string(),
+    }
+}
+
+// Every evaluation step costs one unit of fuel. Nested evaluations are what the
+// interpreter keeps on the Rust stack, so they count against Limits::max_stack.
+#[derive(Default)]
 pub struct Interpreter {
-    env: Environment,
+    env: HashMap<String, Data>, // globals, looked up when no local binding matches
+    constructors: HashMap<String, Rc<Constructor>>, // of every type declared so far
+    overflow: Overflow,
+    limits: Limits,
+    stats: Cell<Stats>,         // of the last call to eval
+    depth: Cell<usize>,         // calls in progress
+    nesting: Cell<usize>,       // evaluations in progress
 }
 impl Interpreter {
     pub fn new() -> Interpreter {
-        Interpreter{env: HashMap::new()}
+        Interpreter::with_overflow(Overflow::default())
     }
 
-    fn eval_exp_ast(&self, ast : ExpAst, bind : &Environment) -> Option<Data> {
-        match ast {
-            ExpAst::Add(t1, t2) => {
-                match (self.eval_exp_ast(*t1, bind)?, self.eval_exp_ast(*t2, bind)?) {
-                    (Data::Num(n1), Data::Num(n2)) => Some(Data::Num(n1 + n2)),
-                    _ => None,
-                }
-            },
-            ExpAst::Sub(t1, t2) => {
-                match (self.eval_exp_ast(*t1, bind)?, self.eval_exp_ast(*t2, bind)?) {
-                    (Data::Num(n1), Data::Num(n2)) => Some(Data::Num(n1 - n2)),
-                    _ => None,
-                }
-            },
-            ExpAst::Mul(t1, t2) => {
-                match (self.eval_exp_ast(*t1, bind)?, self.eval_exp_ast(*t2, bind)?) {
-                    (Data::Num(n1), Data::Num(n2)) => Some(Data::Num(n1 * n2)),
-                    _ => None,
-                }
-            },
-            ExpAst::Div(t1, t2) => {
-                match (self.eval_exp_ast(*t1, bind)?, self.eval_exp_ast(*t2, bind)?) {
-                    (Data::Num(n1), Data::Num(n2)) => Some(Data::Num(n1 / n2)),
-                    _ => None,
-                }
+    pub fn with_overflow(overflow : Overflow) -> Interpreter {
+        Interpreter::with_limits(overflow, Limits::default())
+    }
+
+    pub fn with_limits(overflow : Overflow, limits : Limits) -> Interpreter {
+        Interpreter{overflow, limits, ..Interpreter::default()}
+    }
+
+    // Fuel and stack used by the last evaluation, whether it succeeded or not
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    fn update_stats(&self, f : impl FnOnce(&mut Stats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    fn eval_num(&self, ast : &ExpAst, bind : &Environment) -> Result<i32, RuntimeError> {
+        match self.eval_exp_ast(ast, bind)? {
+            Data::Num(num) => Ok(num),
+            v => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch("a number", v), ast.span())),
+        }
+    }
+
+    fn eval_str(&self, ast : &ExpAst, bind : &Environment) -> Result<Rc<str>, RuntimeError> {
+        match self.eval_exp_ast(ast, bind)? {
+            Data::Str(text) => Ok(text),
+            v => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch("a string", v), ast.span())),
+        }
+    }
+
+    // strings can only be compared for equality
+    fn eval_compare(&self, op : CmpOp, t1 : &ExpAst, t2 : &ExpAst, bind : &Environment) -> Result<bool, RuntimeError> {
+        if op == CmpOp::Equal || op == CmpOp::NotEqual {
+            let (v1, v2) = (self.eval_exp_ast(t1, bind)?, self.eval_exp_ast(t2, bind)?);
+            return match equality::equal(&v1, &v2) {
+                Ok(equal) => Ok(equal == (op == CmpOp::Equal)),
+                Err(Mismatch::Left(expected)) => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch(expected, v1), t1.span())),
+                Err(Mismatch::Right(expected)) => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch(expected, v2), t2.span())),
+            };
+        }
+        let n1 = self.eval_num(t1, bind)?;
+        Ok(compare(op, n1, self.eval_num(t2, bind)?))
+    }
+
+    // a builtin runs once it has all its arguments
+    fn apply_builtin(&self, builtin : Builtin, args : Vec<Data>, span : Span) -> Result<Data, RuntimeError> {
+        if args.len() < builtin.arity() {
+            return Ok(Data::Builtin(builtin, args));
+        }
+        let error = |kind| RuntimeError::new(kind, span);
+        let mismatch = |expected, v : &Data| error(RuntimeErrorKind::TypeMismatch(expected, v.clone()));
+        match (builtin, args.as_slice()) {
+            (Builtin::Length, [Data::Str(text)]) => Ok(Data::Num(builtins::length(text))),
+            (Builtin::Substring, [Data::Str(text), Data::Num(start), Data::Num(len)]) => {
+                let text = builtins::substring(text, *start, *len).map_err(|e| error(e.into()))?;
+                Ok(Data::Str(Rc::from(text)))
             },
-            ExpAst::App(t1, t2) => {
-                match (self.eval_exp_ast(*t1, bind)?, self.eval_exp_ast(*t2, bind)?) {
-                    (Data::Fun(var, env, body), v2) => {
-                        let mut new_bind = bind.clone();
-                        new_bind.extend(env);
-                        new_bind.insert(var, v2);
-                        self.eval_exp_ast(body, &new_bind)
-                    }
-                    _ => None
-                }
+            (Builtin::Head, [Data::Cons(cell)]) => Ok(cell.head.clone()),
+            (Builtin::Tail, [Data::Cons(cell)]) => Ok(cell.tail.clone()),
+            (Builtin::Head, [Data::Nil]) | (Builtin::Tail, [Data::Nil]) => Err(error(BuiltinError::EmptyList.into())),
+            (Builtin::IsEmpty, [Data::Nil]) => Ok(Data::Num(1)),
+            (Builtin::IsEmpty, [Data::Cons(_)]) => Ok(Data::Num(0)),
+            (Builtin::Head | Builtin::Tail | Builtin::IsEmpty, [v]) => Err(mismatch("a list", v)),
+            (_, [Data::Str(_), Data::Num(_), v]) | (_, [Data::Str(_), v, _]) => Err(mismatch("a number", v)),
+            (_, [v, ..]) => Err(mismatch("a string", v)),
+            (_, []) => Err(error(RuntimeErrorKind::MissingArgument(builtin))),
+        }
+    }
+
+    fn constructor(&self, name : &str, span : Span) -> Result<&Rc<Constructor>, RuntimeError> {
+        self.constructors.get(name).ok_or_else(|| RuntimeError::new(RuntimeErrorKind::UnknownConstructor(name.to_string()), span))
+    }
+
+    fn eval_cons(&self, t1 : &ExpAst, t2 : &ExpAst, bind : &Environment) -> Result<Data, RuntimeError> {
+        let head = self.eval_exp_ast(t1, bind)?;
+        match self.eval_exp_ast(t2, bind)? {
+            tail @ (Data::Nil | Data::Cons(_)) => Ok(Data::Cons(Rc::new(ListCell{head, tail}))),
+            v => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch("a list", v), t2.span())),
+        }
+    }
+
+    fn eval_concat(&self, t1 : &ExpAst, t2 : &ExpAst, bind : &Environment) -> Result<Data, RuntimeError> {
+        let s1 = self.eval_str(t1, bind)?;
+        let s2 = self.eval_str(t2, bind)?;
+        Ok(Data::Str(Rc::from(format!("{}{}", s1, s2))))
+    }
+
+    // a constructor is called with its fields like a function
+    fn eval_constructor(&self, name : &str, span : Span) -> Result<Data, RuntimeError> {
+        Ok(Data::Adt(self.constructor(name, span)?.clone(), Rc::from(vec![])))
+    }
+
+    // Binds the variables of the pattern in env if the value matches it
+    fn match_pattern(&self, pattern : &Pattern, v : &Data, env : &mut Environment) -> Result<bool, RuntimeError> {
+        match (pattern, v) {
+            (Pattern::Wildcard(_), _) => Ok(true),
+            (Pattern::Var(name, _), v) => {
+                *env = env.bind(name.clone(), v.clone());
+                Ok(true)
             },
-            ExpAst::Var(name) => {
-                match bind.get(&name) {
-                    Some(v) => Some(v.clone()),
-                    None => {
-                        let v = self.env.get(&name)?;
-                        Some(v.clone())
-                    }
+            (Pattern::Num(n, _), Data::Num(num)) => Ok(n == num),
+            (Pattern::Str(s, _), Data::Str(text)) => Ok(s == text),
+            (Pattern::Constructor(name, patterns, span), v) => {
+                let constructor = self.constructor(name, *span)?;
+                if patterns.len() != constructor.arity() {
+                    let kind = RuntimeErrorKind::ConstructorArity(name.clone(), constructor.arity(), patterns.len());
+                    return Err(RuntimeError::new(kind, *span));
                 }
-            },
-            ExpAst::Fun(vars, exp) => {
-                let local = bind.clone();
-                // todo: get &mut instead of cloning the current environment
-                Some(Data::Fun(vars, local, *exp))
-            },
-            ExpAst::Num(num) => Some(Data::Num(num)),
-            ExpAst::If(cond_ast, then_ast, else_ast) => {
-                match self.eval_exp_ast(*cond_ast, bind)? {
-                    Data::Num(num) => {
-                        if num != 0 {
-                            Some(self.eval_exp_ast(*then_ast, bind)?)
+                match v {
+                    Data::Adt(c, fields) if fields.len() == c.arity() => {
+                        if c.name != *name {
+                            return Ok(false);
                         }
-                        else {
-                            Some(self.eval_exp_ast(*else_ast, bind)?)
+                        for (pattern, field) in patterns.iter().zip(fields.iter()) {
+                            if !self.match_pattern(pattern, field, env)? {
+                                return Ok(false);
+                            }
                         }
+                        Ok(true)
                     },
-                    _ => None,
+                    v => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch("a value built by a constructor", v.clone()), *span)),
                 }
             },
-            _ => {
-                println!("??? {:?}", ast);
-                None
+            (Pattern::Num(_, span), v) => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch("a number", v.clone()), *span)),
+            (Pattern::Str(_, span), v) => Err(RuntimeError::new(RuntimeErrorKind::TypeMismatch("a string", v.clone()), *span)),
+        }
+    }
+
+    // the arm that matches and the environment its body is evaluated in
+    fn eval_match<'a>(&self, exp : &ExpAst, arms : &'a [(Pattern, ExpAst)], span : Span, bind : &Environment) -> Result<(Environment, &'a ExpAst), RuntimeError> {
+        let v = self.eval_exp_ast(exp, bind)?;
+        for (pattern, body_ast) in arms {
+            let mut env = bind.clone();
+            if self.match_pattern(pattern, &v, &mut env)? {
+                return Ok((env, body_ast));
+            }
+        }
+        Err(RuntimeError::new(RuntimeErrorKind::MatchFailure(v), span))
+    }
+
+    fn eval_arithmetic(&self, op : BinOp, t1 : &ExpAst, t2 : &ExpAst, span : Span, bind : &Environment) -> Result<Data, RuntimeError> {
+        let n1 = self.eval_num(t1, bind)?;
+        let n2 = self.eval_num(t2, bind)?;
+        arithmetic::apply(self.overflow, op, n1, n2)
+            .map(Data::Num)
+            .map_err(|e| RuntimeError::new(e.into(), span))
+    }
+
+    // a call of a closure is left to the caller, so that it can be made in tail position
+    fn eval_app(&self, t1 : &ExpAst, t2 : &ExpAst, span : Span, bind : &Environment) -> Result<Step, RuntimeError> {
+        let v = match (self.eval_exp_ast(t1, bind)?, self.eval_exp_ast(t2, bind)?) {
+            (Data::Fun(var, env, body), v2) => return Ok(Step::Call(body, env.call(var, v2, bind), (callee_name(t1), span))),
+            (Data::Builtin(builtin, mut args), v2) => {
+                args.push(v2);
+                self.apply_builtin(builtin, args, span)?
             },
+            // a constructor is complete once it has all its fields
+            (Data::Adt(constructor, fields), v2) if fields.len() < constructor.arity() => Data::with_field(constructor, &fields, v2),
+            (v1, _) => return Err(RuntimeError::new(RuntimeErrorKind::NotAFunction(v1), span)),
+        };
+        Ok(Step::Done(v))
+    }
+
+    // locals shadow globals, which shadow builtins
+    fn eval_var(&self, name : &str, span : Span, bind : &Environment) -> Result<Data, RuntimeError> {
+        let builtin = || Builtin::from_name(name).map(|builtin| Data::Builtin(builtin, vec![]));
+        bind.get(name).or_else(|| self.env.get(name).cloned()).or_else(builtin)
+            .ok_or_else(|| RuntimeError::new(RuntimeErrorKind::UnboundVariable(name.to_string()), span))
+    }
+
+    // Evaluates operands and other non-tail subexpressions, which do need a Rust stack frame.
+    // A call made by the expression shows up in the trace of any error raised inside it.
+    fn eval_exp_ast(&self, ast : &ExpAst, bind : &Environment) -> Result<Data, RuntimeError> {
+        let nesting = self.nesting.get() + 1;
+        self.limits.check_stack(nesting).map_err(|e| RuntimeError::new(RuntimeErrorKind::Limit(e), ast.span()))?;
+        self.nesting.set(nesting);
+        self.update_stats(|stats| stats.max_stack = stats.max_stack.max(nesting));
+
+        let mut call = None;
+        let result = self.eval_tail(ast, bind, &mut call);
+        self.nesting.set(nesting - 1);
+        if call.is_some() {
+            self.depth.set(self.depth.get() - 1);
+        }
+        result.map_err(|mut e| {
+            if let Some(call) = call {
+                e.trace.push(call);
+            }
+            e
+        })
+    }
+
+    // Trampoline: the body of a function called in tail position is evaluated by the loop
+    // here instead of a recursive call, so tail calls run in constant Rust stack. A tail call
+    // replaces the call being evaluated, the same way it would replace a stack frame.
+    fn eval_tail(&self, ast : &ExpAst, bind : &Environment, call : &mut Option<(String, Span)>) -> Result<Data, RuntimeError> {
+        let mut step = self.eval_step(ast, bind)?;
+        loop {
+            match step {
+                Step::Done(v) => return Ok(v),
+                Step::Call(body, env, callee) => {
+                    if call.is_none() {
+                        let depth = self.depth.get() + 1;
+                        self.limits.check_depth(depth).map_err(|e| RuntimeError::new(RuntimeErrorKind::Limit(e), callee.1))?;
+                        self.depth.set(depth);
+                        self.update_stats(|stats| stats.max_frames = stats.max_frames.max(depth));
+                    }
+                    *call = Some(callee);
+                    step = self.eval_step(&body, &env)?;
+                },
+            }
         }
     }
 
-    pub fn eval_statement_ast(&mut self, ast : StatementAst) -> Option<Data> {
+    // Evaluates the expression up to the call in tail position, if there is one
+    fn eval_step(&self, ast : &ExpAst, bind : &Environment) -> Result<Step, RuntimeError> {
+        let mut ast = ast;
+        let mut bind = bind;
+        let mut let_env; // environment of the body of the innermost let in tail position
+        loop {
+            let steps = self.stats.get().steps;
+            self.limits.check_fuel(steps).map_err(|e| RuntimeError::new(RuntimeErrorKind::Limit(e), ast.span()))?;
+            self.update_stats(|stats| stats.steps = steps + 1);
+
+            let v = match ast {
+                ExpAst::Add(t1, t2, span) => self.eval_arithmetic(BinOp::Add, t1, t2, *span, bind)?,
+                ExpAst::Sub(t1, t2, span) => self.eval_arithmetic(BinOp::Sub, t1, t2, *span, bind)?,
+                ExpAst::Mul(t1, t2, span) => self.eval_arithmetic(BinOp::Mul, t1, t2, *span, bind)?,
+                ExpAst::Div(t1, t2, span) => self.eval_arithmetic(BinOp::Div, t1, t2, *span, bind)?,
+                ExpAst::App(t1, t2, span) => return self.eval_app(t1, t2, *span, bind),
+                ExpAst::Var(name, span) => self.eval_var(name, *span, bind)?,
+                ExpAst::Constructor(name, span) => self.eval_constructor(name, *span)?,
+                ExpAst::Fun(var, exp, _) => Data::Fun(var.clone(), bind.clone(), exp.clone()),
+                ExpAst::Num(num, _) => Data::Num(*num),
+                ExpAst::Str(text, _) => Data::Str(text.clone()),
+                ExpAst::Nil(_) => Data::Nil,
+                ExpAst::Cons(t1, t2, _) => self.eval_cons(t1, t2, bind)?,
+                ExpAst::Concat(t1, t2, _) => self.eval_concat(t1, t2, bind)?,
+                ExpAst::Compare(op, t1, t2, _) => Data::Num(self.eval_compare(*op, t1, t2, bind)? as i32),
+                ExpAst::And(t1, t2, _) => Data::Num((self.eval_num(t1, bind)? != 0 && self.eval_num(t2, bind)? != 0) as i32),
+                ExpAst::Or(t1, t2, _) => Data::Num((self.eval_num(t1, bind)? != 0 || self.eval_num(t2, bind)? != 0) as i32),
+                ExpAst::Not(t, _) => Data::Num((self.eval_num(t, bind)? == 0) as i32),
+                ExpAst::Neg(t, span) => {
+                    let n = self.eval_num(t, bind)?;
+                    let v = arithmetic::apply(self.overflow, BinOp::Sub, 0, n).map_err(|e| RuntimeError::new(e.into(), *span))?;
+                    Data::Num(v)
+                },
+                ExpAst::If(cond_ast, then_ast, else_ast, _) => {
+                    ast = if self.eval_num(cond_ast, bind)? != 0 { then_ast } else { else_ast };
+                    continue;
+                },
+                ExpAst::Let(name, value_ast, body_ast, _) => {
+                    let value = self.eval_exp_ast(value_ast, bind)?;
+                    let_env = bind.bind(name.clone(), value);
+                    bind = &let_env;
+                    ast = body_ast;
+                    continue;
+                },
+                ExpAst::LetRec(name, value_ast, body_ast, _) => {
+                    // the parser only accepts a function as the value
+                    let_env = match &**value_ast {
+                        ExpAst::Fun(var, exp, _) => bind.bind_rec(name.clone(), var.clone(), exp.clone()),
+                        _ => {
+                            let value = self.eval_exp_ast(value_ast, bind)?;
+                            bind.bind(name.clone(), value)
+                        },
+                    };
+                    bind = &let_env;
+                    ast = body_ast;
+                    continue;
+                },
+                // the arms are tried in order, and the body of the first that matches is in tail position
+                ExpAst::Match(exp, arms, span) => {
+                    let (env, body_ast) = self.eval_match(exp, arms, *span, bind)?;
+                    let_env = env;
+                    bind = &let_env;
+                    ast = body_ast;
+                    continue;
+                },
+            };
+            return Ok(Step::Done(v));
+        }
+    }
+
+    pub fn eval_statement_ast(&mut self, ast : StatementAst) -> Result<Data, RuntimeError> {
         match ast {
-            StatementAst::Exp(exp_ast) => self.eval_exp_ast(*exp_ast, &HashMap::new()),
-            StatementAst::Assign(name, exp_ast) => {
-                match self.eval_exp_ast(*exp_ast, &HashMap::new()) {
-                    Some(val) => {
-                        self.env.insert(name, val.clone());
-                        Some(val)
-                    },
-                    None => None,
-                }
+            StatementAst::Exp(exp_ast, _) => self.eval_exp_ast(&exp_ast, &Environment::new()),
+            StatementAst::Assign(name, exp_ast, _) => {
+                let val = self.eval_exp_ast(&exp_ast, &Environment::new())?;
+                self.env.insert(name, val.clone());
+                Ok(val)
+            },
+            StatementAst::Type(_, constructors, _) => {
+                self.declare_constructors(&constructors);
+                Ok(Data::Num(0))
             },
         }
     }
 
-    pub fn eval(&mut self, ast : BlockAst) -> Option<Data> {
+    // a later declaration of a constructor replaces the earlier one
+    fn declare_constructors(&mut self, constructors : &[Constructor]) {
+        for constructor in constructors {
+            self.constructors.insert(constructor.name.clone(), Rc::new(constructor.clone()));
+        }
+    }
+
+    pub fn eval(&mut self, ast : BlockAst) -> Result<Data, RuntimeError> {
+        self.stats.set(Stats::default());
         match ast {
             BlockAst::Block(statement_asts) => {
-                let mut val = None;
+                // constructors can be used above the type that declares them, as in compiled code
+                for statement_ast in &statement_asts {
+                    if let StatementAst::Type(_, constructors, _) = statement_ast {
+                        self.declare_constructors(constructors);
+                    }
+                }
+                let mut val = Err(RuntimeError::new(RuntimeErrorKind::EmptyBlock, Span::default()));
                 for statement_ast in statement_asts {
-                    val = self.eval_statement_ast(statement_ast);
+                    val = Ok(self.eval_statement_ast(statement_ast)?);
                 }
                 val
             },
@@ -118,84 +659,355 @@ impl Interpreter {
     }
 }
 
+#[cfg(test)]
 use parser;
+
 #[test]
 fn test_function_definition_sum() {
     let mut interpreter = Interpreter::new();
-    let mut input = "sum = |n| if n then sum (n - 1) + n else 0 end".to_string();
+    let mut input = parser::combinator::Input::new("sum = |n| if n then sum (n - 1) + n else 0 end");
 
     let block = parser::Block::new().parse(&mut input);
     assert!(block.is_ok());
     let ast =  parser::syntax::block_to_ast(block.unwrap());
     let v = interpreter.eval(ast.clone());
-    assert!(v.is_some());
-    match v.unwrap() {
-        Data::Fun(_, _, _) => assert!(true),
-        _ => assert!(false),
+    match v {
+        Ok(Data::Fun(_, _, _)) => (),
+        v => panic!("expected a function but got {:?}", v),
     }
 
-    let mut input = "sum(10)".to_string();
+    let mut input = parser::combinator::Input::new("sum(10)");
     let block = parser::Block::new().parse(&mut input);
     assert!(block.is_ok());
     let ast =  parser::syntax::block_to_ast(block.unwrap());
     let v = interpreter.eval(ast.clone());
-    assert!(v.is_some());
-    match v.unwrap() {
-        Data::Num(num) => assert_eq!(num, 55),
-        _ => assert!(false),
+    match v {
+        Ok(Data::Num(num)) => assert_eq!(num, 55),
+        v => panic!("expected a number but got {:?}", v),
     }
 }
 
 #[test]
 fn test_function_definition_fib() {
     let mut interpreter = Interpreter::new();
-    let mut input = "fib = |n| if n then if n-1 then fib(n-1) + fib(n-2) else 1 end else 1 end".to_string();
+    let mut input = parser::combinator::Input::new("fib = |n| if n then if n-1 then fib(n-1) + fib(n-2) else 1 end else 1 end");
 
     let block = parser::Block::new().parse(&mut input);
     assert!(block.is_ok());
     let ast =  parser::syntax::block_to_ast(block.unwrap());
     let v = interpreter.eval(ast.clone());
-    assert!(v.is_some());
-    match v.unwrap() {
-        Data::Fun(_, _, _) => assert!(true),
-        _ => assert!(false),
+    match v {
+        Ok(Data::Fun(_, _, _)) => (),
+        v => panic!("expected a function but got {:?}", v),
     }
 
-    let mut input = "fib(6)".to_string();
+    let mut input = parser::combinator::Input::new("fib(6)");
     let block = parser::Block::new().parse(&mut input);
     assert!(block.is_ok());
     let ast =  parser::syntax::block_to_ast(block.unwrap());
     let v = interpreter.eval(ast.clone());
-    assert!(v.is_some());
-    match v.unwrap() {
-        Data::Num(num) => assert_eq!(num, 13),
-        _ => assert!(false),
+    match v {
+        Ok(Data::Num(num)) => assert_eq!(num, 13),
+        v => panic!("expected a number but got {:?}", v),
     }
 }
 
 #[test]
 fn test_closure() {
     let mut interpreter = Interpreter::new();
-    let mut input = "plus = |x| |y| x + y".to_string();
+    let mut input = parser::combinator::Input::new("plus = |x| |y| x + y");
 
     let block = parser::Block::new().parse(&mut input);
     assert!(block.is_ok());
     let ast =  parser::syntax::block_to_ast(block.unwrap());
     let v = interpreter.eval(ast.clone());
-    assert!(v.is_some());
-    match v.unwrap() {
-        Data::Fun(_, _, _) => assert!(true),
-        _ => assert!(false),
+    match v {
+        Ok(Data::Fun(_, _, _)) => (),
+        v => panic!("expected a function but got {:?}", v),
     }
 
-    let mut input = "plus 1 2".to_string();
+    let mut input = parser::combinator::Input::new("plus 1 2");
     let block = parser::Block::new().parse(&mut input);
     assert!(block.is_ok());
     let ast =  parser::syntax::block_to_ast(block.unwrap());
     let v = interpreter.eval(ast.clone());
-    assert!(v.is_some());
-    match v.unwrap() {
-        Data::Num(num) => assert_eq!(num, 3),
-        _ => assert!(false),
+    match v {
+        Ok(Data::Num(num)) => assert_eq!(num, 3),
+        v => panic!("expected a number but got {:?}", v),
+    }
+}
+
+#[test]
+fn test_overflow() {
+    let mut input = parser::combinator::Input::new("{ big = 2147483647; big + 1 }");
+    let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+
+    let mut interpreter = Interpreter::new();
+    match interpreter.eval(ast.clone()) {
+        Err(e) => assert_eq!(format!("{}", e), "integer overflow at 1:21-1:28"),
+        v => panic!("expected an overflow but got {:?}", v),
     }
+
+    let mut interpreter = Interpreter::with_overflow(Overflow::Wrapping);
+    match interpreter.eval(ast) {
+        Ok(Data::Num(num)) => assert_eq!(num, i32::MIN),
+        _ => panic!("expected a number"),
+    }
+
+    let mut input = parser::combinator::Input::new("(1 / 0)");
+    let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+    assert!(Interpreter::with_overflow(Overflow::Wrapping).eval(ast).is_err());
+}
+
+#[test]
+fn test_environment_chain() {
+    let outer = Environment::new().bind("x".to_string(), Data::Num(1));
+    let inner = outer.bind("x".to_string(), Data::Num(2)).bind("y".to_string(), Data::Num(3));
+    match (outer.get("x"), inner.get("x"), inner.get("y"), outer.get("y")) {
+        (Some(Data::Num(1)), Some(Data::Num(2)), Some(Data::Num(3)), None) => (),
+        v => panic!("unexpected lookups {:?}", v),
+    }
+
+    // closures see the bindings of their definition, not those of the caller
+    let mut input = parser::combinator::Input::new("{ make = |x| |y| x; k = make 1; x = 5; f = |x| k x; f 7 }");
+    let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+    match Interpreter::new().eval(ast) {
+        Ok(Data::Num(num)) => assert_eq!(num, 1),
+        v => panic!("expected a number but got {:?}", v),
+    }
+
+    // the body of a function also sees the locals of its callers, after its own
+    for &(source, expected) in &[("{ f = |u| y; g = |y| f 0; g 5 }", 5), ("{ h = |u| y; f = |u| h 0; g = |y| f 0; g 5 }", 5), ("{ f = |y| |u| y; g = |y| f 1 0; g 5 }", 1)] {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        match Interpreter::new().eval(ast) {
+            Ok(Data::Num(num)) => assert_eq!(num, expected, "{}", source),
+            v => panic!("expected a number but got {:?}", v),
+        }
+    }
+}
+
+#[test]
+fn test_deep_recursion() {
+    // each call that is not a tail call takes Rust stack, so give the evaluation enough of it
+    let result = std::thread::Builder::new().stack_size(1 << 30).spawn(|| {
+        let mut input = parser::combinator::Input::new("{ sum = |n| if n then sum (n - 1) + n else 0 end; sum 10000 }");
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        let mut interpreter = Interpreter::new();
+        (interpreter.eval(ast).map(|v| v.to_string()).map_err(|e| e.to_string()), interpreter.stats().max_frames)
+    }).unwrap().join().unwrap();
+    assert_eq!(result, (Ok("50005000".to_string()), 10001));
+
+    // the environment of the innermost call is no larger 10000 calls down than 10 calls
+    // down, so memory grows with the depth alone and a lookup takes as long at any depth
+    let innermost = |n : i32| std::thread::Builder::new().stack_size(1 << 30).spawn(move || {
+        let source = format!("{{ sum = |n| if n then head [sum (n - 1)] else |u| n end; sum {} }}", n);
+        let ast = parser::syntax::block_to_ast(parser::parse_source("sum.sm", &source).unwrap());
+        match Interpreter::new().eval(ast) {
+            Ok(Data::Fun(_, env, _)) => env.len(),
+            v => panic!("expected a function but got {:?}", v),
+        }
+    }).unwrap().join().unwrap();
+    assert_eq!(innermost(10000), innermost(10));
+    assert_eq!(innermost(10), 1);
+}
+
+#[test]
+fn test_runtime_errors() {
+    let eval = |source : &str| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        Interpreter::new().eval(ast).unwrap_err()
+    };
+
+    match eval("1 + x").kind {
+        RuntimeErrorKind::UnboundVariable(name) => assert_eq!(name, "x"),
+        k => panic!("unexpected error {:?}", k),
+    }
+    match eval("{ n = 3; n 1 }").kind {
+        RuntimeErrorKind::NotAFunction(Data::Num(3)) => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+    match eval("if |x| x then 1 else 2 end").kind {
+        RuntimeErrorKind::TypeMismatch("a number", Data::Fun(..)) => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+
+    let e = eval("{ f = |n| 10 / n; g = |n| f (n - 1) + 1; g 1 }");
+    match e.kind {
+        RuntimeErrorKind::DivisionByZero => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+    let trace : Vec<&str> = e.trace.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(trace, vec!["f", "g"]);
+    assert_eq!(format!("{}", e), "division by zero at 1:11-1:17\n  in f called at 1:27-1:35\n  in g called at 1:42-1:45");
+}
+
+#[test]
+fn test_tail_calls() {
+    let eval = |source : &str| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        Interpreter::new().eval(ast)
+    };
+
+    match eval("{ loop = |n| if n then loop (n - 1) else 0 end; loop 1000000 }") {
+        Ok(Data::Num(num)) => assert_eq!(num, 0),
+        v => panic!("expected a number but got {:?}", v),
+    }
+    match eval("{ count = |acc| |n| if n then count (acc + 1) (n - 1) else acc end; count 0 1000000 }") {
+        Ok(Data::Num(num)) => assert_eq!(num, 1000000),
+        v => panic!("expected a number but got {:?}", v),
+    }
+    match eval("{ even = |n| if n then odd (n - 1) else 1 end; odd = |n| if n then even (n - 1) else 0 end; even 1000001 }") {
+        Ok(Data::Num(num)) => assert_eq!(num, 0),
+        v => panic!("expected a number but got {:?}", v),
+    }
+
+    // the tail call from g to f replaced the call to g
+    let e = eval("{ f = |n| 10 / n; g = |n| f (n - 1); g 1 }").unwrap_err();
+    let trace : Vec<&str> = e.trace.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(trace, vec!["f"]);
+}
+
+#[test]
+fn test_limits() {
+    let eval = |source : &str, limits : Limits| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        let mut interpreter = Interpreter::with_limits(Overflow::Checked, limits);
+        let result = interpreter.eval(ast);
+        (result, interpreter.stats())
+    };
+    let kind = |result : Result<Data, RuntimeError>| result.unwrap_err().kind;
+
+    let forever = "{ loop = |n| loop n; loop 0 }";
+    let (result, stats) = eval(forever, Limits{fuel: Some(10000), ..Limits::default()});
+    match kind(result) {
+        RuntimeErrorKind::Limit(LimitError::OutOfFuel) => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+    assert_eq!(stats.steps, 10000);
+
+    let deep = "{ sum = |n| if n then sum (n - 1) + n else 0 end; sum 100000 }";
+    match kind(eval(deep, Limits{max_depth: Some(100), ..Limits::default()}).0) {
+        RuntimeErrorKind::Limit(LimitError::CallDepthExceeded) => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+    match kind(eval(deep, Limits{max_stack: Some(100), ..Limits::default()}).0) {
+        RuntimeErrorKind::Limit(LimitError::StackOverflow) => (),
+        k => panic!("unexpected error {:?}", k),
+    }
+
+    let (result, stats) = eval("{ sum = |n| if n then sum (n - 1) + n else 0 end; sum 10 }", Limits{fuel: Some(1000), max_depth: Some(11), max_stack: Some(100)});
+    match result {
+        Ok(Data::Num(num)) => assert_eq!(num, 55),
+        v => panic!("expected a number but got {:?}", v),
+    }
+    assert_eq!(stats.max_frames, 11);
+    assert!(stats.steps > 0 && stats.steps < 1000, "{:?}", stats);
+}
+
+#[test]
+fn test_comparison_and_boolean() {
+    let eval = |source : &str| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        match Interpreter::new().eval(ast) {
+            Ok(Data::Num(num)) => num,
+            v => panic!("expected a number but got {:?}", v),
+        }
+    };
+
+    assert_eq!(eval("1 + 1 == 2"), 1);
+    assert_eq!(eval("1 != 1"), 0);
+    assert_eq!(eval("(1 < 2) + (2 <= 2) + (3 > 2) + (2 >= 3)"), 3);
+    assert_eq!(eval("!0 && !(1 == 2) || 0"), 1);
+    assert_eq!(eval("0 || 0 && 1"), 0);
+    assert_eq!(eval("{ fib = |n| if n < 2 then 1 else fib (n - 1) + fib (n - 2) end; fib 10 }"), 89);
+
+    // the right operand is not evaluated when the left one decides
+    assert_eq!(eval("0 && 1 / 0"), 0);
+    assert_eq!(eval("2 || x"), 1);
+}
+
+#[test]
+fn test_negation() {
+    let eval = |source : &str, overflow : Overflow| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        Interpreter::with_overflow(overflow).eval(ast)
+    };
+    let num = |source : &str| match eval(source, Overflow::Checked) {
+        Ok(Data::Num(num)) => num,
+        v => panic!("expected a number but got {:?}", v),
+    };
+
+    assert_eq!(num("-5"), -5);
+    assert_eq!(num("- 5 * 2"), -10);
+    assert_eq!(num("3 - -2"), 5);
+    assert_eq!(num("{ inc = |x| x + 1; -inc 2 * 3 }"), -9);
+    assert_eq!(num("{ f = |x| x * 2; f (-1) }"), -2);
+    assert_eq!(num("{ x = 3; x -1 }"), 2);
+    assert_eq!(num("-2147483648"), i32::MIN);
+
+    match eval("{ min = -2147483648; -min }", Overflow::Checked) {
+        Err(RuntimeError{kind: RuntimeErrorKind::Overflow, ..}) => (),
+        v => panic!("expected an overflow but got {:?}", v),
+    }
+    match eval("{ min = -2147483648; -min }", Overflow::Wrapping) {
+        Ok(Data::Num(num)) => assert_eq!(num, i32::MIN),
+        v => panic!("expected a number but got {:?}", v),
+    }
+}
+
+#[test]
+fn test_let_and_multi_parameter_functions() {
+    let num = |source : &str| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        match Interpreter::new().eval(ast) {
+            Ok(Data::Num(num)) => num,
+            v => panic!("expected a number but got {:?}", v),
+        }
+    };
+
+    assert_eq!(num("{ f = |x, y, z| x - y * z; f 10 2 3 }"), 4);
+    assert_eq!(num("{ f = |x, y| x - y; g = f 10; g 3 }"), 7);
+    assert_eq!(num("let x = 2 in x * x"), 4);
+    assert_eq!(num("let x = 1 in let y = x + 1 in x + y"), 3);
+    assert_eq!(num("let x = 1 in let x = x + 10 in x"), 11);
+    assert_eq!(num("{ x = 5; y = let x = 1 in x; x + y }"), 6);
+    assert_eq!(num("let k = 3 in let add = |x| x + k in add 4"), 7);
+    assert_eq!(num("{ recur = 2; let recx = 1 in recur + recx }"), 3);
+
+    assert_eq!(num("let rec fact = |n| if n < 2 then 1 else n * fact (n - 1) end in fact 5"), 120);
+    assert_eq!(num("{ sum = |n| let rec go = |i, acc| if i > n then acc else go (i + 1) (acc + i) end in go 1 0; sum 10 }"), 55);
+    assert_eq!(num("let rec count = |acc, n| if n then count (acc + 1) (n - 1) else acc end in count 0 100000"), 100000);
+
+    // the value of a let rec must be a function, and keywords cannot be bound
+    for source in &["let rec x = 1 in x", "let in = 1 in 2", "|x, if| x"] {
+        assert!(parser::parse_source("let.sm", source).is_err(), "{}", source);
+    }
+}
+
+
+#[test]
+fn test_error_locations() {
+    let error = |source : &str| {
+        let mut input = parser::combinator::Input::new(source);
+        let ast = parser::syntax::block_to_ast(parser::Block::new().parse(&mut input).unwrap());
+        Interpreter::new().eval(ast).unwrap_err().to_string()
+    };
+
+    assert_eq!(error(r#"substring "abc" 2 2"#), "index out of range at 1:1-1:20");
+    assert_eq!(error(r#""a" ++ 1"#), "expected a string but got 1 at 1:8-1:9");
+    assert_eq!(error(r#""a" < "b""#), "expected a number but got \"a\" at 1:1-1:4");
+    assert_eq!(error("1 :: 2"), "expected a list but got 2 at 1:6-1:7");
+
+    let option = "type Option = None | Some x;";
+    assert_eq!(error(&format!("{{ {} match Some 1 with | None -> 0 end }}", option)), "no pattern matches Some 1 at 1:32-1:65");
+    assert_eq!(error("match 1 with | Some x -> x end"), "unknown constructor 'Some' at 1:16-1:22");
+    assert_eq!(error(&format!("{{ {} match None with | Some -> 0 end }}", option)), "constructor 'Some' has 1 fields but the pattern gives 0 at 1:50-1:54");
+    assert_eq!(error(&format!("{{ {} match 1 with | None -> 0 end }}", option)), "expected a value built by a constructor but got 1 at 1:47-1:51");
 }
@@ -0,0 +1,14 @@
+pub mod vm;
+pub mod parser;
+pub mod interpreter;
+pub mod compiler;
+pub mod arithmetic;
+pub mod limits;
+pub mod builtins;
+pub mod list;
+pub mod equality;
+pub mod exhaustiveness;
+pub mod types;
+
+#[cfg(test)]
+mod agreement;
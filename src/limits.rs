@@ -0,0 +1,73 @@
+// Limits on the fuel, calls and stack a run can use, and how much of them it used
+
+use std::fmt;
+
+// Every limit is off unless it is set
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Limits {
+    pub fuel: Option<u64>,        // steps the engine may take
+    pub max_depth: Option<usize>, // calls that may be in progress at once; tail calls do not count
+    pub max_stack: Option<usize>, // items on the VM stack, or nested evaluations in the interpreter
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LimitError {
+    OutOfFuel,
+    CallDepthExceeded,
+    StackOverflow,
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::OutOfFuel => write!(f, "out of fuel"),
+            LimitError::CallDepthExceeded => write!(f, "too many nested calls"),
+            LimitError::StackOverflow => write!(f, "stack overflow"),
+        }
+    }
+}
+
+// How much of its resources a run used, whether it succeeded or not
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub steps: u64,        // fuel used
+    pub max_stack: usize,  // largest stack, measured like Limits::max_stack
+    pub max_frames: usize, // deepest nesting of calls that had not returned
+}
+
+impl Limits {
+    pub fn check_fuel(&self, steps : u64) -> Result<(), LimitError> {
+        match self.fuel {
+            Some(fuel) if steps >= fuel => Err(LimitError::OutOfFuel),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_depth(&self, depth : usize) -> Result<(), LimitError> {
+        match self.max_depth {
+            Some(max) if depth > max => Err(LimitError::CallDepthExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_stack(&self, size : usize) -> Result<(), LimitError> {
+        match self.max_stack {
+            Some(max) if size > max => Err(LimitError::StackOverflow),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn limits_test() {
+    let limits = Limits{fuel: Some(10), max_depth: Some(2), max_stack: Some(3)};
+    assert_eq!(limits.check_fuel(9), Ok(()));
+    assert_eq!(limits.check_fuel(10), Err(LimitError::OutOfFuel));
+    assert_eq!(limits.check_depth(2), Ok(()));
+    assert_eq!(limits.check_depth(3), Err(LimitError::CallDepthExceeded));
+    assert_eq!(limits.check_stack(4), Err(LimitError::StackOverflow));
+
+    let unlimited = Limits::default();
+    assert_eq!(unlimited.check_fuel(u64::MAX), Ok(()));
+    assert_eq!(unlimited.check_stack(usize::MAX), Ok(()));
+}
@@ -0,0 +1,115 @@
+// Lists as cells that share their tails, and how values are shown inside lists and constructors
+use std::fmt;
+use std::iter;
+use std::mem;
+use std::rc::Rc;
+
+// What a list needs from the values of an engine
+pub trait Value: Sized {
+    fn nil() -> Self;
+    fn cell(&self) -> Option<&Rc<ListCell<Self>>>;
+    fn into_cell(self) -> Option<Rc<ListCell<Self>>>;
+    fn text(&self) -> Option<&str>; // the characters of a string
+    fn is_compound(&self) -> bool;  // shown in parentheses as the field of a constructor
+}
+
+pub struct ListCell<T: Value> {
+    pub head: T,
+    pub tail: T, // Nil or Cons
+}
+
+// the items of a list, first to last
+pub fn items<T: Value>(list : &T) -> impl Iterator<Item = &T> {
+    iter::successors(list.cell(), |cell| cell.tail.cell()).map(|cell| &cell.head)
+}
+
+// Values are shown as a program would print them, so strings are shown as their text,
+// except inside a list or a constructor where quotes tell them apart from other values
+pub fn quoted<T: Value + fmt::Display>(v : &T) -> String {
+    match v.text() {
+        Some(text) => format!("{:?}", text),
+        None => v.to_string(),
+    }
+}
+
+pub fn fmt_list<T: Value + fmt::Display>(f : &mut fmt::Formatter, list : &T) -> fmt::Result {
+    let items : Vec<String> = items(list).map(quoted).collect();
+    write!(f, "[{}]", items.join(", "))
+}
+
+// A constructor with fields is shown applied to them, like Some (Some 1)
+pub fn fmt_adt<T: Value + fmt::Display>(f : &mut fmt::Formatter, name : &str, arity : usize, fields : &[T]) -> fmt::Result {
+    if fields.len() < arity {
+        return write!(f, "<fun>");
+    }
+    write!(f, "{}", name)?;
+    for field in fields {
+        if field.is_compound() {
+            write!(f, " ({})", field)?;
+        }
+        else {
+            write!(f, " {}", quoted(field))?;
+        }
+    }
+    Ok(())
+}
+
+// Dropping a long list one cell at a time, as the default drop would recurse once per item
+impl<T: Value> Drop for ListCell<T> {
+    fn drop(&mut self) {
+        let mut tail = mem::replace(&mut self.tail, T::nil());
+        while let Some(cell) = tail.into_cell() {
+            match Rc::try_unwrap(cell) {
+                Ok(mut cell) => tail = mem::replace(&mut cell.tail, T::nil()),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// shown as the list of its items rather than as nested cells
+impl<T: Value + fmt::Debug> fmt::Debug for ListCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(iter::once(&self.head).chain(items(&self.tail))).finish()
+    }
+}
+
+impl<T: Value + PartialEq> PartialEq for ListCell<T> {
+    fn eq(&self, other : &ListCell<T>) -> bool {
+        let (mut a, mut b) = (self, other);
+        loop {
+            if a.head != b.head {
+                return false;
+            }
+            match (a.tail.cell(), b.tail.cell()) {
+                (Some(x), Some(y)) if Rc::ptr_eq(x, y) => return true,
+                (Some(x), Some(y)) => {
+                    a = x;
+                    b = y;
+                },
+                _ => return a.tail == b.tail,
+            }
+        }
+    }
+}
+
+
+#[test]
+fn list_test() {
+    use vm::{Data, VmError, VmErrorKind};
+    let build = |n| (0..n).fold(Data::Nil, |tail, i| Data::Cons(Rc::new(ListCell{head: Data::Num(i), tail})));
+
+    assert_eq!(build(3).to_string(), "[2, 1, 0]");
+    assert_eq!(format!("{:?}", build(3)), "Cons([Num(2), Num(1), Num(0)])");
+    let strings = Data::Cons(Rc::new(ListCell{head: Data::Str(Rc::from("a")), tail: Data::Nil}));
+    assert_eq!(strings.to_string(), r#"["a"]"#);
+
+    // long lists are compared, shown and dropped without running out of stack
+    let long = build(200000);
+    assert_eq!(long, build(200000));
+    assert_ne!(long, build(199999));
+    assert_eq!(long, long.clone());
+    assert!(format!("{:?}", long).starts_with("Cons([Num(199999), Num(199998), "));
+    let e = VmError{kind: VmErrorKind::TypeMismatch("a number", long.clone()), pc: 0, stack: vec![long]};
+    assert!(e.to_string().starts_with("expected a number but got [199999, "));
+}
@@ -1,56 +1,383 @@
+extern crate stackmachine;
+
+use std::env;
+use std::fs;
 use std::io;
+use std::io::Read;
 use std::io::Write;
-mod vm;
-mod parser;
-mod interpreter;
-mod compiler;
+use std::process;
+use stackmachine::arithmetic;
+use stackmachine::compiler;
+use stackmachine::exhaustiveness;
+use stackmachine::interpreter;
+use stackmachine::limits;
+use stackmachine::parser;
+use stackmachine::types;
+use stackmachine::vm;
 
-fn main() {
-    let mut interpreter = interpreter::Interpreter::new();
+const USAGE : &str = "usage: stackmachine [run|ast|asm] [FILE|-] [--engine=interp|vm] [--wrapping]
+                    [--fuel=N] [--max-depth=N] [--max-stack=N] [--stats]
+  run  evaluate the script and print its value (the default engine is vm)
+       --stats also prints the fuel and stack it used to stderr
+  ast  print the syntax tree of the script
+  asm  print the code compiled for the vm
+Without FILE, or with '-', the script is read from stdin.
+Without a command, an interactive session is started.";
+
+// exit codes
+const RUNTIME_ERROR : i32 = 1;
+const USAGE_ERROR : i32 = 2;
+const SYNTAX_ERROR : i32 = 3; // also used for scripts that do not compile
+
+#[derive(PartialEq)]
+enum Engine {
+    Interp,
+    Vm,
+}
+
+struct Options {
+    command: Option<String>,
+    path: Option<String>,
+    engine: Engine,
+    overflow: arithmetic::Overflow,
+    limits: limits::Limits,
+    stats: bool,
+}
+
+fn parse_args(args : &[String]) -> Result<Options, String> {
+    let mut options = Options{
+        command: None,
+        path: None,
+        engine: Engine::Vm,
+        overflow: arithmetic::Overflow::Checked,
+        limits: limits::Limits::default(),
+        stats: false,
+    };
+    let number = |arg : &str, value : &str| value.parse().map_err(|_| format!("bad number in '{}'", arg));
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--fuel=") {
+            options.limits.fuel = Some(number(arg, value)?);
+            continue;
+        }
+        if let Some(value) = arg.strip_prefix("--max-depth=") {
+            options.limits.max_depth = Some(number(arg, value)? as usize);
+            continue;
+        }
+        if let Some(value) = arg.strip_prefix("--max-stack=") {
+            options.limits.max_stack = Some(number(arg, value)? as usize);
+            continue;
+        }
+        match arg.as_str() {
+            "--stats" => options.stats = true,
+            "--engine=interp" => options.engine = Engine::Interp,
+            "--engine=vm" => options.engine = Engine::Vm,
+            "--wrapping" => options.overflow = arithmetic::Overflow::Wrapping,
+            "-h" | "--help" => return Err(String::new()),
+            "run" | "ast" | "asm" if options.command.is_none() => options.command = Some(arg.clone()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ if options.command.is_some() && options.path.is_none() => options.path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+    Ok(options)
+}
+
+// Returns the name to report errors against and the text of the script
+fn read_script(path : &Option<String>) -> io::Result<(String, String)> {
+    let mut text = String::new();
+    match path {
+        Some(path) if path != "-" => {
+            text = fs::read_to_string(path)?;
+            Ok((path.clone(), text))
+        },
+        _ => {
+            io::stdin().read_to_string(&mut text)?;
+            Ok(("stdin".to_string(), text))
+        },
+    }
+}
+
+// Reports every syntax error of the script at once
+fn parse(name : &str, text : &str) -> Result<parser::syntax::BlockAst, i32> {
+    let (ast, errors) = parser::parse_source_recovering(name, text);
+    if errors.is_empty() {
+        return Ok(ast);
+    }
+    for e in errors {
+        eprintln!("{}", e);
+    }
+    Err(SYNTAX_ERROR)
+}
+
+// Warnings do not keep the script from running
+fn check(checker : &mut exhaustiveness::Checker, ast : &parser::syntax::BlockAst) {
+    for warning in checker.check_block(ast) {
+        eprintln!("{}", warning);
+    }
+}
+
+fn compile(ast : &parser::syntax::BlockAst) -> Result<Vec<vm::Operator>, i32> {
+    let mut code = vec![];
+    compiler::compile_block(ast, &mut code).map_err(|e| {
+        eprintln!("{}", e);
+        SYNTAX_ERROR
+    })?;
+    Ok(code)
+}
+
+fn run(ast : parser::syntax::BlockAst, options : &Options) -> Result<(), i32> {
+    let (result, stats) = match options.engine {
+        Engine::Interp => {
+            let mut interpreter = interpreter::Interpreter::with_limits(options.overflow, options.limits);
+            let result = interpreter.eval(ast).map(|v| v.to_string()).map_err(|e| e.to_string());
+            (result, interpreter.stats())
+        },
+        Engine::Vm => {
+            let code = compile(&ast)?;
+            let config = vm::Config{overflow: options.overflow, limits: options.limits};
+            let (result, stats) = vm::process_with_stats(&code, &config);
+            (result.map(|v| v.to_string()).map_err(|e| e.to_string()), stats)
+        },
+    };
+    if options.stats {
+        eprintln!("fuel used: {}, max stack: {}, max calls: {}", stats.steps, stats.max_stack, stats.max_frames);
+    }
+    match result {
+        Ok(v) => {
+            println!("{}", v);
+            Ok(())
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(RUNTIME_ERROR)
+        },
+    }
+}
+
+fn execute(options : &Options) -> Result<(), i32> {
+    let command = options.command.as_deref();
+    let (name, text) = read_script(&options.path).map_err(|e| {
+        eprintln!("{}: {}", options.path.as_deref().unwrap_or("stdin"), e);
+        USAGE_ERROR
+    })?;
+    let ast = parse(&name, &text)?;
+    if command != Some("ast") {
+        check(&mut exhaustiveness::Checker::new(), &ast);
+    }
+
+    match command {
+        Some("ast") => println!("{:?}", ast),
+        Some("asm") => {
+            for (i, op) in compile(&ast)?.iter().enumerate() {
+                println!("{:4} {:?}", i, op);
+            }
+        },
+        _ => run(ast, options)?,
+    }
+    Ok(())
+}
+
+// The VM side of the REPL. The globals of earlier lines keep their slots in the scope and
+// their values on the stack, and the code of every line is kept, as closures point into it.
+struct Session {
+    scope: compiler::Scope,
+    code: Vec<vm::Operator>,
+    stack: Vec<vm::Data>,
+}
+impl Session {
+    fn new() -> Session {
+        Session{scope: compiler::Scope::new(), code: vec![], stack: vec![]}
+    }
+
+    // Returns the code compiled for the line and what running it gave. A line that fails
+    // leaves the globals as they were before it.
+    fn run(&mut self, ast : &parser::syntax::BlockAst, config : &vm::Config)
+            -> Result<(&[vm::Operator], Result<vm::Data, vm::VmError>), compiler::CompileError> {
+        let (scope, start, depth) = (self.scope.clone(), self.code.len(), self.stack.len());
+        if let Err(e) = compiler::compile_block_in(ast, &mut self.scope, &mut self.code) {
+            self.scope = scope;
+            self.code.truncate(start);
+            return Err(e);
+        }
+        let result = vm::resume(&self.code, start, &mut self.stack, config);
+        if result.is_err() {
+            self.scope = scope;
+            self.stack.truncate(depth);
+        }
+        Ok((&self.code[start..], result))
+    }
+}
+
+fn repl(options : &Options) {
+    let config = vm::Config{overflow: options.overflow, limits: options.limits};
+    let mut session = Session::new();
+    let mut interpreter = interpreter::Interpreter::with_limits(options.overflow, options.limits);
+    let mut checker = exhaustiveness::Checker::new();
+    let mut type_checker = types::TypeChecker::new();
     let mut expression = String::new();
 
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
         expression.clear();
-        io::stdin().read_line(&mut expression)
+        let read = io::stdin().read_line(&mut expression)
             .expect("Failed to read line");
+        if read == 0 {
+            println!();
+            return;
+        }
 
-        let parse_result = parser::Block::new().parse(&mut expression.trim().to_string());
+        let parse_result = parser::parse_source("stdin", expression.trim());
 
         match parse_result {
             Ok(block) => {
                 let ast = parser::syntax::block_to_ast(block);
                 println!("AST: {:?}", ast);
+
+                // like the warnings, a type error does not keep the statement from running
+                match type_checker.check_block(&ast) {
+                    Ok(types) => {
+                        for (name, ty) in types {
+                            println!("TYPE: {} : {}", name, ty);
+                        }
+                    },
+                    Err(e) => println!("TYPE: {}", e),
+                }
+                check(&mut checker, &ast);
                 
                 // evaluate
                 let v = interpreter.eval(ast.clone());
                 match v {
-                    Some(v) => {
-                        match v {
-                            interpreter::Data::Num(num) => {
-                                println!("EVALUATED: {}", num);
-                            },
-                            interpreter::Data::Fun(_, env, _) => {
-                                println!("EVALUATED: <fun>");
-                                println!("{:?}", env);
-                            },
+                    Ok(v) => {
+                        println!("EVALUATED: {}", v.quoted());
+                        if let interpreter::Data::Fun(_, env, _) = v {
+                            println!("{:?}", env);
                         }
                     },
-                    None => println!("error"),
+                    Err(e) => println!("EVALUATED: {}", e),
                 };
 
-                // compile
-                let mut code = vec![];
-                compiler::compile_block(&ast, &mut code);
-                //compiler::compile_statement(&ast, &mut code);
-                println!("ASSEMBLED: {:?}", code);
-
-                // code.push(vm::Operator::Print);
-                // code.push(vm::Operator::Pop);
-                // vm::process(&code);
+                // compile and execute
+                match session.run(&ast, &config) {
+                    Ok((code, result)) => {
+                        println!("ASSEMBLED: {:?}", code);
+                        match result {
+                            Ok(v) => println!("EXECUTED: {}", v.quoted()),
+                            Err(e) => println!("EXECUTED: {}", e),
+                        }
+                    },
+                    Err(e) => println!("ASSEMBLED: {}", e),
+                }
             },
-            Err(e) => println!("AST: {:?}", e),
+            Err(e) => println!("AST: {}", e),
         }
     }
 }
+
+fn main() {
+    let args : Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        // asking for help is not an error, so its usage goes to stdout
+        Err(message) if message.is_empty() => {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(message) => {
+            eprintln!("stackmachine: {}", message);
+            eprintln!("{}", USAGE);
+            process::exit(USAGE_ERROR);
+        },
+    };
+
+    if options.command.is_none() {
+        repl(&options);
+        return;
+    }
+    if let Err(code) = execute(&options) {
+        process::exit(code);
+    }
+}
+
+#[cfg(test)]
+fn args(args : &[&str]) -> Result<Options, String> {
+    parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>())
+}
+
+#[test]
+fn parse_args_test() {
+    let options = args(&[]).unwrap();
+    assert!(options.command.is_none() && options.path.is_none());
+    assert!(options.engine == Engine::Vm && !options.stats);
+    assert_eq!(options.overflow, arithmetic::Overflow::Checked);
+
+    let options = args(&["run", "script.sm", "--engine=interp", "--wrapping", "--fuel=10", "--max-depth=20", "--max-stack=30", "--stats"]).unwrap();
+    assert_eq!(options.command.as_deref(), Some("run"));
+    assert_eq!(options.path.as_deref(), Some("script.sm"));
+    assert!(options.engine == Engine::Interp && options.stats);
+    assert_eq!(options.overflow, arithmetic::Overflow::Wrapping);
+    assert_eq!((options.limits.fuel, options.limits.max_depth, options.limits.max_stack), (Some(10), Some(20), Some(30)));
+
+    // options can come before the command, and the path can be '-' for stdin
+    let options = args(&["--engine=interp", "asm", "-"]).unwrap();
+    assert_eq!((options.command.as_deref(), options.path.as_deref()), (Some("asm"), Some("-")));
+
+    // an empty message asks for the usage only
+    assert_eq!(args(&["--help"]).err(), Some(String::new()));
+    assert_eq!(args(&["run", "--engine=jit"]).err(), Some("unknown option '--engine=jit'".to_string()));
+    assert_eq!(args(&["run", "--fuel=lots"]).err(), Some("bad number in '--fuel=lots'".to_string()));
+    assert_eq!(args(&["script.sm"]).err(), Some("unexpected argument 'script.sm'".to_string()));
+    assert_eq!(args(&["run", "a.sm", "b.sm"]).err(), Some("unexpected argument 'b.sm'".to_string()));
+}
+
+// Runs a script from a file with the given command and engine, and returns its exit code
+#[cfg(test)]
+fn exit_code(name : &str, script : Option<&str>, command : &str, engine : &str) -> i32 {
+    let path = env::temp_dir().join(format!("stackmachine-{}-{}.sm", process::id(), name));
+    let _ = fs::remove_file(&path);
+    if let Some(script) = script {
+        fs::write(&path, script).unwrap();
+    }
+    let options = args(&[command, path.to_str().unwrap(), engine]).unwrap();
+    let code = execute(&options).err().unwrap_or(0);
+    let _ = fs::remove_file(&path);
+    code
+}
+
+#[test]
+fn exit_code_test() {
+    for engine in ["--engine=interp", "--engine=vm"].iter() {
+        assert_eq!(exit_code("ok", Some("{ x = 20; x + 1 }"), "run", engine), 0);
+        assert_eq!(exit_code("syntax", Some("{ x = ; }"), "run", engine), SYNTAX_ERROR);
+        assert_eq!(exit_code("runtime", Some("1 / 0"), "run", engine), RUNTIME_ERROR);
+        assert_eq!(exit_code("missing", None, "run", engine), USAGE_ERROR);
+    }
+    // a script that does not compile only fails where it is compiled
+    let unknown = "match 1 with | Some x -> x end";
+    assert_eq!(exit_code("compile", Some(unknown), "asm", "--engine=vm"), SYNTAX_ERROR);
+    assert_eq!(exit_code("compile-vm", Some(unknown), "run", "--engine=vm"), SYNTAX_ERROR);
+    assert_eq!(exit_code("compile-interp", Some(unknown), "run", "--engine=interp"), RUNTIME_ERROR);
+    assert_eq!(exit_code("ast", Some(unknown), "ast", "--engine=vm"), 0);
+}
+
+#[test]
+fn session_test() {
+    let config = vm::Config::default();
+    let mut session = Session::new();
+    let mut line = |source : &str| {
+        let ast = parser::syntax::block_to_ast(parser::parse_source("stdin", source).unwrap());
+        session.run(&ast, &config).map_err(|e| e.to_string())?.1.map(|v| v.to_string()).map_err(|e| e.to_string())
+    };
+
+    // globals and the closures they hold outlive the line that assigned them
+    assert_eq!(line("plus = |x, y| x + y"), Ok("<fun>".to_string()));
+    assert_eq!(line("plus 1 2"), Ok("3".to_string()));
+    assert_eq!(line("{ type Option = None | Some x; three = Some (plus 1 2) }"), Ok("Some 3".to_string()));
+    assert_eq!(line("{ plus = |x, y| x * y; plus 2 5 }"), Ok("10".to_string()));
+    assert_eq!(line("match three with | Some x -> plus x 2 | None -> 0 end"), Ok("6".to_string()));
+
+    // a line that fails declares none of its globals
+    assert!(line("{ n = 1; 1 / 0 }").unwrap_err().starts_with("division by zero"));
+    assert_eq!(line("m = n"), Err("1:5: unbound variable 'n'".to_string()));
+    assert_eq!(line("plus 3 3"), Ok("9".to_string()));
+}
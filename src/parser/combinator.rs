@@ -1,22 +1,215 @@
+#![allow(clippy::new_ret_no_self)] // parsers are built and combined as boxed trait objects
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 use std::string;
 
-// todo: implement Parser with function? Fn<T> (&str) -> Result((T, &str), ParseError)
+#[derive(Copy, Clone, PartialEq, Default)]
+pub struct Position {
+    pub offset: usize, // in bytes from the beginning of the source
+    pub line: u32,     // starting from 1
+    pub column: u32,   // in characters, starting from 1
+}
+
+impl fmt::Debug for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Cursor over the source text: parsers only move the offset forward, and
+// backtracking is a matter of copying the cursor back
+#[derive(Copy, Clone)]
+pub struct Input<'a> {
+    src: &'a str,
+    pos: Position,
+    memo: Option<&'a MemoTable>,
+    furthest: Option<&'a RefCell<Option<ParseError>>>,
+    reads: Option<&'a Cell<usize>>, // counts every character read, backtracking included
+}
+impl<'a> Input<'a> {
+    pub fn new(text : &'a str) -> Input<'a> {
+        Input{src: text, pos: Position{offset: 0, line: 1, column: 1}, memo: None, furthest: None, reads: None}
+    }
+
+    // Memo parsers only remember their results when the input carries a table
+    pub fn with_memo(text : &'a str, memo : &'a MemoTable) -> Input<'a> {
+        Input{memo: Some(memo), ..Input::new(text)}
+    }
+
+    // Errors of Try branches that were given up for a later branch are merged into the
+    // cell, so that it ends up with the furthest failure even if parsing went on
+    pub fn with_furthest_error(text : &'a str, furthest : &'a RefCell<Option<ParseError>>) -> Input<'a> {
+        Input{furthest: Some(furthest), ..Input::new(text)}
+    }
+
+    // The work a parse takes, which unlike its time does not depend on the machine
+    pub fn counting_reads(self, reads : &'a Cell<usize>) -> Input<'a> {
+        Input{reads: Some(reads), ..self}
+    }
+
+    pub fn position(&self) -> Position {
+        self.pos
+    }
+
+    // the text that has not been consumed yet
+    pub fn rest(&self) -> &'a str {
+        &self.src[self.pos.offset..]
+    }
+
+    pub fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    pub fn next_char(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        if let Some(reads) = self.reads {
+            reads.set(reads.get() + 1);
+        }
+        self.pos.offset += c.len_utf8();
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.column = 1;
+        }
+        else {
+            self.pos.column += 1;
+        }
+        Some(c)
+    }
+
+    // consumes the string if the input starts with it
+    pub fn consume(&mut self, str : &str) -> bool {
+        if !self.rest().starts_with(str) {
+            return false;
+        }
+        for _ in str.chars() {
+            self.next_char();
+        }
+        true
+    }
+
+    // failing is the common case while backtracking, so nothing is formatted here
+    pub fn error(&self, expected : Expected) -> ParseError {
+        let found = match self.peek() {
+            Some(c) => Found::Char(c),
+            None => Found::Eof,
+        };
+        ParseError {
+            filename: String::new(),
+            line: self.pos.line,
+            char: self.pos.column,
+            expected: vec![expected],
+            found,
+        }
+    }
+
+    fn recovered_from(&self, error : ParseError) {
+        if let Some(furthest) = self.furthest {
+            let mut furthest = furthest.borrow_mut();
+            *furthest = Some(match furthest.take() {
+                Some(e) => e.merge(error),
+                None => error,
+            });
+        }
+    }
+}
+
 pub trait Parser<T> {
-    fn parse(&self, input : &mut String) -> Result<T, ParseError>;
+    fn parse(&self, input : &mut Input) -> Result<T, ParseError>;
+}
+type ParserB<T> = Box<dyn Parser<T>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Char(char),
+    Str(&'static str),
+    OneOf(&'static str),
+    Digit,
+    Int32, // a literal that fits in an i32
+    Name,
+    Constructor, // a name that starts with an uppercase letter
+    Escape, // what can follow a backslash in a string literal
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Found {
+    Char(char),
+    Word(String),
+    Eof,
 }
-type ParserB<T> = Box<Parser<T>>;
 
+#[derive(Clone)]
 pub struct ParseError {
-    pub filename: string::String,
+    pub filename: string::String, // set by whoever knows where the input came from
     pub line: u32,
     pub char: u32,
-    pub explanation: string::String,
+    pub expected: Vec<Expected>, // everything that would have been accepted at this position
+    pub found: Found,
+}
+impl ParseError {
+    pub fn in_file(self, filename : &str) -> ParseError {
+        ParseError{filename: filename.to_string(), ..self}
+    }
+
+    // Keeps the error that got furthest into the input; if both failed at the same
+    // position, either of their expectations would have been accepted there
+    pub fn merge(mut self, other : ParseError) -> ParseError {
+        match (self.line, self.char).cmp(&(other.line, other.char)) {
+            cmp::Ordering::Less => other,
+            cmp::Ordering::Greater => self,
+            cmp::Ordering::Equal => {
+                if let Found::Word(_) = other.found {
+                    self.found = other.found;
+                }
+                for e in other.expected {
+                    if !self.expected.contains(&e) {
+                        self.expected.push(e);
+                    }
+                }
+                self
+            },
+        }
+    }
+
+    pub fn explanation(&self) -> string::String {
+        let expected : Vec<string::String> = self.expected.iter().map(|e| match e {
+            Expected::Char(c) => format!("'{}'", c),
+            Expected::Str(str) => format!("'{}'", str),
+            Expected::OneOf(chars) => format!("one of \"{}\"", chars.escape_default()),
+            Expected::Digit => "a digit".to_string(),
+            Expected::Int32 => format!("a number from {} to {}", i32::MIN, i32::MAX),
+            Expected::Name => "a name".to_string(),
+            Expected::Constructor => "a constructor".to_string(),
+            Expected::Escape => r#"an escape sequence (\\, \", \n, \t or \r)"#.to_string(),
+            Expected::Eof => "EOF".to_string(),
+        }).collect();
+        let expected = match expected.split_last() {
+            Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+            _ => expected.concat(),
+        };
+        let found = match &self.found {
+            Found::Char(c) => format!("'{}'", c.escape_default()),
+            Found::Word(word) => format!("'{}'", word),
+            Found::Eof => "EOF".to_string(),
+        };
+        format!("expected {} but got {}", expected, found)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{} {}", self.filename, self.line, self.char, self.explanation())
+    }
 }
 
 impl fmt::Debug for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}:{} {}", self.filename, self.line, self.char, self.explanation)
+        write!(f, "{}", self)
     }
 }
 
@@ -24,58 +217,42 @@ pub struct Char {
     pub c : char,
 }
 impl Char {
-    pub fn new(c : char) -> Box<Parser<char>> {
+    pub fn new(c : char) -> Box<dyn Parser<char>> {
         Box::new(Char{c})
     }
 }
 impl Parser<char> for Char {
-    fn parse(&self, input : &mut String) -> Result<char, ParseError> {
-        match input.chars().next() {
+    fn parse(&self, input : &mut Input) -> Result<char, ParseError> {
+        match input.peek() {
             Some(c) => {
                 if c == self.c {
-                    input.remove(0);
+                    input.next_char();
                     Ok(c)
                 }
                 else {
-                    Err(ParseError {
-                        filename: "stdin".to_string(),
-                        line: 0,
-                        char: 0,
-                        explanation: format!("expected '{}' but got '{}'", self.c, c),
-                    })
+                    Err(input.error(Expected::Char(self.c)))
                 }
             }
-            None => Err(ParseError {
-                filename: "stdin".to_string(),
-                line: 0,
-                char: 0,
-                explanation: format!("expected '{}' but got EOF", self.c),
-            })
+            None => Err(input.error(Expected::Char(self.c))),
         }
     }
 }
 
 pub struct Str {
-    pub str : String,
+    pub str : &'static str,
 }
 impl Str {
-    pub fn new(str : &str) -> Box<Parser<String>> {
-        Box::new(Str{str: str.to_string()})
+    pub fn new(str : &'static str) -> Box<dyn Parser<&'static str>> {
+        Box::new(Str{str})
     }
 }
-impl Parser<String> for Str {
-    fn parse(&self, input : &mut String) -> Result<String, ParseError> {
-        if input.starts_with(&self.str) {
-            input.drain(..self.str.len());
-            Ok(self.str.clone())
+impl Parser<&'static str> for Str {
+    fn parse(&self, input : &mut Input) -> Result<&'static str, ParseError> {
+        if input.consume(self.str) {
+            Ok(self.str)
         }
         else {
-            Err(ParseError {
-                filename: "stdin".to_string(),
-                line: 0,
-                char: 0,
-                explanation: format!("expected '{}'", self.str),
-            })
+            Err(input.error(Expected::Str(self.str)))
         }
     }
 }
@@ -84,20 +261,23 @@ pub struct Many<T> {
     pub p : ParserB<T>,
 }
 impl<T: 'static> Many<T> {
-    pub fn new(p : Box<Parser<T>>) -> Box<Parser<Vec<T>>> {
+    pub fn new(p : Box<dyn Parser<T>>) -> Box<dyn Parser<Vec<T>>> {
         Box::new(Many{p})
     }
 }
 impl<T> Parser<Vec<T>> for Many<T> {
-    fn parse(&self, input : &mut String) -> Result<Vec<T>, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<Vec<T>, ParseError> {
         let mut result = Vec::new();
-        
+
         loop {
+            let saved = *input;
             match self.p.parse(input) {
-                Ok(r) => {
-                    result.push(r);
+                Ok(r) => result.push(r),
+                Err(e) => {
+                    *input = saved;
+                    input.recovered_from(e);
+                    break;
                 },
-                Err(_) => break,
             };
         }
 
@@ -109,12 +289,12 @@ pub struct SkipMany<T> {
     pub p: ParserB<T>,
 }
 impl<T: 'static> SkipMany<T> {
-    pub fn new(p : Box<Parser<T>>) -> Box<Parser<()>> {
+    pub fn new(p : Box<dyn Parser<T>>) -> Box<dyn Parser<()>> {
         Box::new(SkipMany{p: Many::new(p)})
     }
 }
 impl<T> Parser<()> for SkipMany<T> {
-    fn parse(&self, input : &mut String) -> Result<(), ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<(), ParseError> {
         self.p.parse(input)?;
         Ok(())
     }
@@ -124,19 +304,23 @@ pub struct Many1<T> {
     pub p : ParserB<T>,
 }
 impl<T: 'static> Many1<T> {
-    pub fn new(p : Box<Parser<T>>) -> Box<Parser<Vec<T>>> {
+    pub fn new(p : Box<dyn Parser<T>>) -> Box<dyn Parser<Vec<T>>> {
         Box::new(Many1{p})
     }
 }
 impl<T> Parser<Vec<T>> for Many1<T> {
-    fn parse(&self, input : &mut String) -> Result<Vec<T>, ParseError> {
-        let mut rs = vec![];
-        rs.push(self.p.parse(input)?);
+    fn parse(&self, input : &mut Input) -> Result<Vec<T>, ParseError> {
+        let mut rs = vec![self.p.parse(input)?];
 
         loop {
+            let saved = *input;
             match self.p.parse(input) {
                 Ok(r) => rs.push(r),
-                Err(_) => break,
+                Err(e) => {
+                    *input = saved;
+                    input.recovered_from(e);
+                    break;
+                },
             };
         }
 
@@ -148,147 +332,314 @@ pub struct Try<T> {
     pub ps : Vec<ParserB<T>>,
 }
 impl<T: 'static> Try<T> {
-    pub fn new(ps : Vec<Box<Parser<T>>>) -> Box<Parser<T>> {
+    pub fn new(ps : Vec<Box<dyn Parser<T>>>) -> Box<dyn Parser<T>> {
         Box::new(Try{ps})
     }
 }
 impl<T> Parser<T> for Try<T> {
-    fn parse(&self, input : &mut String) -> Result<T, ParseError> {
-        let mut input_clone = input.clone();
-        let r = self.ps[0].parse(&mut input_clone);
-        if !r.is_ok() {
-            for p in &self.ps[1..] {
-                let mut input_clone = input.clone();
-                let r = p.parse(&mut input_clone);
-                if r.is_ok() {
-                    *input = input_clone;
-                    return r;
-                }
+    fn parse(&self, input : &mut Input) -> Result<T, ParseError> {
+        let start = *input;
+        let mut error = match self.ps[0].parse(input) {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        for p in &self.ps[1..] {
+            *input = start;
+            match p.parse(input) {
+                Ok(r) => {
+                    input.recovered_from(error);
+                    return Ok(r);
+                },
+                Err(e) => error = error.merge(e),
             }
         }
-        *input = input_clone;
-        r
+        *input = start;
+        Err(error)
+    }
+}
+
+type MemoKey = (&'static str, usize); // parser id and offset
+
+// Results of Memo parsers for one input, keyed by parser id and offset
+#[derive(Default)]
+pub struct MemoTable {
+    results: RefCell<HashMap<MemoKey, Box<dyn Any>>>,
+}
+impl MemoTable {
+    pub fn new() -> MemoTable {
+        MemoTable::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.borrow().is_empty()
+    }
+}
+
+// Packrat parsing, for grammars that go back over what they parsed: a parser wrapped
+// in Memo runs at most once per offset, and retrying it from the same place replays
+// the result and the end position. Results are shared with the table, not copied.
+pub struct Memo<T> {
+    pub id: &'static str,
+    pub p: ParserB<T>,
+}
+impl<T: 'static> Memo<T> {
+    pub fn new(id : &'static str, p : Box<dyn Parser<T>>) -> Box<dyn Parser<Rc<T>>> {
+        Box::new(Memo{id, p})
+    }
+}
+impl<T: 'static> Parser<Rc<T>> for Memo<T> {
+    fn parse(&self, input : &mut Input) -> Result<Rc<T>, ParseError> {
+        let table = match input.memo {
+            Some(table) => table,
+            None => return self.p.parse(input).map(Rc::new),
+        };
+        let key = (self.id, input.pos.offset);
+
+        if let Some(entry) = table.results.borrow().get(&key) {
+            let (result, pos) = entry.downcast_ref::<(Result<Rc<T>, ParseError>, Position)>()
+                .expect("memo ids must be unique per result type");
+            input.pos = *pos;
+            return result.clone();
+        }
+
+        let result = self.p.parse(input).map(Rc::new);
+        table.results.borrow_mut().insert(key, Box::new((result.clone(), input.pos)));
+        result
     }
 }
 
 pub struct Then<T1, T2> {
-    pub p1 : Box<Parser<T1>>,
-    pub p2 : Box<Parser<T2>>,
+    pub p1 : Box<dyn Parser<T1>>,
+    pub p2 : Box<dyn Parser<T2>>,
 }
 impl<T1: 'static, T2: 'static> Then<T1, T2> {
-    pub fn new(p1: Box<Parser<T1>>, p2: Box<Parser<T2>>) -> Box<Parser<T2>> {
+    pub fn new(p1: Box<dyn Parser<T1>>, p2: Box<dyn Parser<T2>>) -> Box<dyn Parser<T2>> {
         Box::new(Then{p1, p2})
     }
 }
 impl<T1, T2> Parser<T2> for Then<T1, T2> {
-    fn parse(&self, input : &mut String) -> Result<T2, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<T2, ParseError> {
         self.p1.parse(input)?;
         self.p2.parse(input)
     }
 }
 
 pub struct OneOf {
-    pub p: Box<Parser<char>>,
+    pub chars: &'static str,
 }
 impl OneOf {
-    pub fn new(chars: &str) -> Box<Parser<char>> {
-        let mut ps = vec![];
-        for c in chars.chars().collect::<Vec<char>>() {
-            ps.push(Char::new(c));
-        }
-        Box::new(OneOf{p: Try::new(ps)})
+    pub fn new(chars: &'static str) -> Box<dyn Parser<char>> {
+        Box::new(OneOf{chars})
     }
 }
 impl Parser<char> for OneOf {
-    fn parse(&self, input : &mut String) -> Result<char, ParseError> {
-        self.p.parse(input)
+    fn parse(&self, input : &mut Input) -> Result<char, ParseError> {
+        match input.peek() {
+            Some(c) if self.chars.contains(c) => {
+                input.next_char();
+                Ok(c)
+            },
+            _ => Err(input.error(Expected::OneOf(self.chars))),
+        }
     }
 }
 
+// Reads a run of digits as an i32; literals that do not fit are errors
 pub struct Digit {
-    pub p: Box<Parser<Vec<char>>>,
+    pub negative: bool, // the digits follow a minus sign, which lets i32::MIN be written
 }
 impl Digit {
-    pub fn new() -> Box<Parser<i32>> {
-        Box::new(Digit{p: Many1::new(OneOf::new("0123456789"))})
+    pub fn new() -> Box<dyn Parser<i32>> {
+        Box::new(Digit{negative: false})
+    }
+
+    pub fn negative() -> Box<dyn Parser<i32>> {
+        Box::new(Digit{negative: true})
     }
 }
 impl Parser<i32> for Digit {
-    fn parse(&self, input : &mut String) -> Result<i32, ParseError> {
-        let digit_str = self.p.parse(input)?;
-        let digit_str : String = digit_str.into_iter().collect();
-        Ok(digit_str.parse::<i32>().unwrap())
+    fn parse(&self, input : &mut Input) -> Result<i32, ParseError> {
+        let rest = input.rest();
+        let len = rest.find(|c : char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(input.error(Expected::Digit));
+        }
+        let digits = &rest[..len];
+        let mut num : i32 = 0;
+        for d in digits.bytes().map(|b| (b - b'0') as i32) {
+            let next = num.checked_mul(10).and_then(|n| if self.negative { n.checked_sub(d) } else { n.checked_add(d) });
+            match next {
+                Some(n) => num = n,
+                None => return Err(ParseError{found: Found::Word(digits.to_string()), ..input.error(Expected::Int32)}),
+            }
+        }
+        input.consume(digits);
+        Ok(num)
+    }
+}
+
+// Reads a string literal between double quotes, in which \\, \", \n, \t and \r are escapes
+pub struct Quoted {}
+impl Quoted {
+    pub fn new() -> Box<dyn Parser<String>> {
+        Box::new(Quoted{})
+    }
+}
+impl Parser<String> for Quoted {
+    fn parse(&self, input : &mut Input) -> Result<String, ParseError> {
+        Char::new('"').parse(input)?;
+        let mut text = String::new();
+        loop {
+            match input.next_char() {
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let c = match input.peek() {
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        _ => return Err(input.error(Expected::Escape)),
+                    };
+                    input.next_char();
+                    text.push(c);
+                },
+                Some(c) => text.push(c),
+                None => return Err(input.error(Expected::Char('"'))),
+            }
+        }
     }
 }
 
 pub struct Lower {
-    pub p: Box<Parser<char>>,
+    pub p: Box<dyn Parser<char>>,
 }
 impl Lower {
-    pub fn new() -> Box<Parser<char>> {
+    pub fn new() -> Box<dyn Parser<char>> {
         Box::new(Lower{p: OneOf::new("abcdefghijklmnopqrstuvwxyz")})
     }
 }
 impl Parser<char> for Lower {
-    fn parse(&self, input : &mut String) -> Result<char, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<char, ParseError> {
         self.p.parse(input)
     }
 }
 
-// pub struct Upper {}
-// impl Parser<char> for Upper {
-//     fn parse(&self, input : String) -> Result<(char, String), ParseError> {
-//         OneOf::new("ABCDEFGHIJKLMNOPQRSTUVWXYZ").parse(input)
-//     }
-// }
-
-// pub struct Letter {}
-// impl Parser<char> for Letter {
-//     fn parse(&self, input : String) -> Result<(char, String), ParseError> {
-//         Try{ps: vec![&Upper{}, &Lower{}]}.parse(input)
-//     }
-// }
+// Letters, digits, underscores and primes can all continue a name
+pub fn is_name_char(c : char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '\''
+}
 
-pub struct Space {
-    pub p: Box<Parser<char>>,
+// Reads a name, which starts with a lowercase letter or an underscore.
+// It can end with a '?', as predicates like empty? do.
+// Constructor names start with an uppercase letter instead and cannot end with a '?'.
+pub struct Name {
+    pub constructor: bool,
 }
-impl Space {
-    pub fn new() -> Box<Parser<char>> {
-        Box::new(Space{p: OneOf::new(" \t\n\r")})
+impl Name {
+    pub fn new() -> Box<dyn Parser<String>> {
+        Box::new(Name{constructor: false})
+    }
+
+    pub fn constructor() -> Box<dyn Parser<String>> {
+        Box::new(Name{constructor: true})
     }
 }
-impl Parser<char> for Space {
-    fn parse(&self, input : &mut String) -> Result<char, ParseError> {
-        self.p.parse(input)
+impl Parser<String> for Name {
+    fn parse(&self, input : &mut Input) -> Result<String, ParseError> {
+        match input.peek() {
+            Some(c) if self.constructor && c.is_ascii_uppercase() => (),
+            Some(c) if !self.constructor && (c.is_ascii_lowercase() || c == '_') => (),
+            _ => return Err(input.error(if self.constructor { Expected::Constructor } else { Expected::Name })),
+        }
+        let rest = input.rest();
+        let mut len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+        if !self.constructor && rest[len..].starts_with('?') {
+            len += 1;
+        }
+        let name = &rest[..len];
+        input.consume(name);
+        Ok(name.to_string())
     }
 }
 
-pub struct Spaces {
-    pub p: Box<Parser<()>>,
+// Matches a word that must not run on into a longer name, so that 'if' does not match 'iffy'
+pub struct Keyword {
+    pub word: &'static str,
+}
+impl Keyword {
+    pub fn new(word : &'static str) -> Box<dyn Parser<()>> {
+        Box::new(Keyword{word})
+    }
 }
+impl Parser<()> for Keyword {
+    fn parse(&self, input : &mut Input) -> Result<(), ParseError> {
+        let rest = input.rest();
+        if rest.starts_with(self.word) && !rest[self.word.len()..].starts_with(|c| is_name_char(c) || c == '?') {
+            input.consume(self.word);
+            Ok(())
+        }
+        else {
+            Err(input.error(Expected::Str(self.word)))
+        }
+    }
+}
+
+// Skips whitespace and comments in place; it runs before almost every token, so it does not allocate.
+// A comment is either '//' up to the end of the line or '/* */', which can be nested.
+pub struct Spaces {}
 impl Spaces {
-    pub fn new() -> Box<Parser<()>> {
-        Box::new(Spaces{p: SkipMany::new(Space::new())})
+    pub fn new() -> Box<dyn Parser<()>> {
+        Box::new(Spaces{})
+    }
+
+    fn skip_block_comment(input : &mut Input) -> Result<(), ParseError> {
+        let mut depth = 1;
+        while depth > 0 {
+            if input.consume("*/") {
+                depth -= 1;
+            }
+            else if input.consume("/*") {
+                depth += 1;
+            }
+            else if input.next_char().is_none() {
+                return Err(input.error(Expected::Str("*/")));
+            }
+        }
+        Ok(())
     }
 }
 impl Parser<()> for Spaces {
-    fn parse(&self, input : &mut String) -> Result<(), ParseError> {
-        self.p.parse(input)
+    fn parse(&self, input : &mut Input) -> Result<(), ParseError> {
+        loop {
+            match input.peek() {
+                Some(' ') | Some('\t') | Some('\n') | Some('\r') => {
+                    input.next_char();
+                },
+                Some('/') if input.consume("//") => {
+                    while input.peek().is_some_and(|c| c != '\n') {
+                        input.next_char();
+                    }
+                },
+                Some('/') if input.consume("/*") => Spaces::skip_block_comment(input)?,
+                _ => return Ok(()),
+            }
+        }
     }
 }
 
 pub struct Between<T1, T2, T3> {
-    pub left_p: Box<Parser<T1>>,
-    pub mid_p: Box<Parser<T2>>,
-    pub right_p: Box<Parser<T3>>,
+    pub left_p: Box<dyn Parser<T1>>,
+    pub mid_p: Box<dyn Parser<T2>>,
+    pub right_p: Box<dyn Parser<T3>>,
 }
 impl<T1: 'static, T2: 'static, T3: 'static> Between<T1, T2, T3> {
-    pub fn new(left_p : Box<Parser<T1>>, mid_p: Box<Parser<T2>>, right_p: Box<Parser<T3>>) -> Box<Parser<T2>> {
+    pub fn new(left_p : Box<dyn Parser<T1>>, mid_p: Box<dyn Parser<T2>>, right_p: Box<dyn Parser<T3>>) -> Box<dyn Parser<T2>> {
         Box::new(Between{left_p, mid_p, right_p})
     }
 }
 impl<T1, T2, T3> Parser<T2> for Between<T1, T2, T3> {
-    fn parse(&self, input : &mut String) -> Result<T2, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<T2, ParseError> {
         self.left_p.parse(input)?;
         let r = self.mid_p.parse(input)?;
         self.right_p.parse(input)?;
@@ -298,45 +649,39 @@ impl<T1, T2, T3> Parser<T2> for Between<T1, T2, T3> {
 
 pub struct Eof {}
 impl Eof {
-    pub fn new() -> Box<Parser<()>> {
+    pub fn new() -> Box<dyn Parser<()>> {
         Box::new(Eof{})
     }
 }
 impl Parser<()> for Eof {
-    fn parse(&self, input : &mut String) -> Result<(), ParseError> {
-        if input.len() == 0 {
-            Ok(())
-        }
-        else {
-            Err(ParseError {
-                filename: "stdin".to_string(),
-                line: 0,
-                char: 0,
-                explanation: format!("expected EOF but got {}", input.chars().next().unwrap()),
-            })
+    fn parse(&self, input : &mut Input) -> Result<(), ParseError> {
+        match input.peek() {
+            None => Ok(()),
+            Some(_) => Err(input.error(Expected::Eof)),
         }
     }
 }
 
 pub struct SepBy<T1, T2> {
-    pub p: Box<Parser<T1>>,
-    pub sep: Box<Parser<T2>>,
+    pub p: Box<dyn Parser<T1>>,
+    pub sep: Box<dyn Parser<T2>>,
 }
 impl<T1: 'static, T2: 'static> SepBy<T1, T2> {
-    pub fn new(p : Box<Parser<T1>>, sep : Box<Parser<T2>>) -> Box<Parser<Vec<T1>>> {
+    pub fn new(p : Box<dyn Parser<T1>>, sep : Box<dyn Parser<T2>>) -> Box<dyn Parser<Vec<T1>>> {
         Box::new(SepBy{p, sep})
     }
 }
 impl<T1, T2> Parser<Vec<T1>> for SepBy<T1, T2> {
-    fn parse(&self, input : &mut String) -> Result<Vec<T1>, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<Vec<T1>, ParseError> {
         let mut results = vec![];
 
         loop {
             let result = self.p.parse(input)?;
             results.push(result);
 
-            let sep_result = self.sep.parse(input);
-            if sep_result.is_err() {
+            let saved = *input;
+            if self.sep.parse(input).is_err() {
+                *input = saved;
                 break;
             }
         }
@@ -349,7 +694,7 @@ impl<T1, T2> Parser<Vec<T1>> for SepBy<T1, T2> {
 
 #[test]
 fn char_parser() {
-    let mut code = "123".to_string();
+    let mut code = Input::new("123");
     let p_one = Char::new('1');
     let result = p_one.parse(&mut code);
     assert!(result.is_ok(), "parse error");
@@ -358,7 +703,7 @@ fn char_parser() {
 
 #[test]
 fn many1_parser() {
-    let mut code = "11123".to_string();
+    let mut code = Input::new("11123");
     let p_one = Char::new('1');
     let p_ones = Many1::new(p_one);
     let ones = p_ones.parse(&mut code);
@@ -368,7 +713,7 @@ fn many1_parser() {
 
 #[test]
 fn try_parser() {
-    let mut code = "23".to_string();
+    let mut code = Input::new("23");
     let p_try = Try::new(vec![Char::new('1'), Char::new('2')]);
     let one_or_two = p_try.parse(&mut code);
     assert!(one_or_two.is_ok(), "parse error");
@@ -377,7 +722,7 @@ fn try_parser() {
 
 #[test]
 fn oneof_parser() {
-    let mut code = "23".to_string();
+    let mut code = Input::new("23");
     let p_one_or_two = OneOf::new("12");
     let one_or_two = p_one_or_two.parse(&mut code);
     assert!(one_or_two.is_ok(), "parse error");
@@ -386,10 +731,66 @@ fn oneof_parser() {
 
 #[test]
 fn digit_parser() {
-    let mut code = "456a12".to_string();
+    let mut code = Input::new("456a12");
     let i = Digit::new().parse(&mut code);
     assert!(i.is_ok(), "parse error");
     assert_eq!(i.unwrap(), 456);
+
+    assert_eq!(Digit::new().parse(&mut Input::new("2147483647")).unwrap(), i32::MAX);
+    assert_eq!(Digit::negative().parse(&mut Input::new("2147483648")).unwrap(), i32::MIN);
+    let mut input = Input::new("2147483648;");
+    let e = Digit::new().parse(&mut input).unwrap_err();
+    assert_eq!(e.explanation(), "expected a number from -2147483648 to 2147483647 but got '2147483648'");
+    assert_eq!(input.rest(), "2147483648;");
+}
+
+#[test]
+fn quoted_parser() {
+    let mut input = Input::new(r#""a \"quoted\" line\n\tand a \\" rest"#);
+    assert_eq!(Quoted::new().parse(&mut input).unwrap(), "a \"quoted\" line\n\tand a \\");
+    assert_eq!(input.rest(), " rest");
+    assert_eq!(Quoted::new().parse(&mut Input::new(r#""""#)).unwrap(), "");
+
+    let e = Quoted::new().parse(&mut Input::new(r#""bad \q""#)).unwrap_err();
+    assert_eq!(e.explanation(), r#"expected an escape sequence (\\, \", \n, \t or \r) but got 'q'"#);
+    let e = Quoted::new().parse(&mut Input::new(r#""open"#)).unwrap_err();
+    assert_eq!(e.explanation(), r#"expected '"' but got EOF"#);
+}
+
+#[test]
+fn name_and_keyword_parser() {
+    let mut input = Input::new("is_even2' + 1");
+    assert_eq!(Name::new().parse(&mut input).unwrap(), "is_even2'");
+    assert_eq!(input.rest(), " + 1");
+    assert!(Name::new().parse(&mut Input::new("2x")).is_err());
+    assert!(Name::new().parse(&mut Input::new("'x")).is_err());
+    let mut input = Input::new("empty?? x");
+    assert_eq!(Name::new().parse(&mut input).unwrap(), "empty?");
+    assert_eq!(input.rest(), "? x");
+    let mut input = Input::new("Some_2? x");
+    assert_eq!(Name::constructor().parse(&mut input).unwrap(), "Some_2");
+    assert_eq!(input.rest(), "? x");
+    assert!(Name::constructor().parse(&mut Input::new("some")).is_err());
+    assert!(Name::new().parse(&mut Input::new("Some")).is_err());
+
+    let mut input = Input::new("iffy");
+    assert!(Keyword::new("if").parse(&mut input).is_err());
+    assert_eq!(input.rest(), "iffy");
+    let mut input = Input::new("if(x)");
+    assert!(Keyword::new("if").parse(&mut input).is_ok());
+    assert_eq!(input.rest(), "(x)");
+}
+
+#[test]
+fn spaces_parser() {
+    let mut input = Input::new("  // to the end of the line\n /* a /* nested */ comment */\t1 / 2");
+    assert!(Spaces::new().parse(&mut input).is_ok());
+    assert_eq!(input.rest(), "1 / 2");
+    assert_eq!((input.position().line, input.position().column), (2, 31));
+
+    let mut input = Input::new("/* /* */ 1");
+    let e = Spaces::new().parse(&mut input).unwrap_err();
+    assert_eq!(e.explanation(), "expected '*/' but got EOF");
 }
 
 #[test]
@@ -400,15 +801,15 @@ fn combination_parser() {
     let many_c = Many1::new(Char::new('c'));
     let b_or_c = Try::new(vec![many_b, many_c]);
     
-    let mut input = "aaabd".to_string();
-    whitespaces.parse(&mut input);
+    let mut input = Input::new("aaabd");
+    assert!(whitespaces.parse(&mut input).is_ok());
     let parse_result = many_a.parse(&mut input);
     assert!(parse_result.is_ok(), "parse error");
     let parse_result = b_or_c.parse(&mut input);
     assert!(parse_result.is_ok(), "parse error");
 
-    let mut input = "cd".to_string();
-    whitespaces.parse(&mut input);
+    let mut input = Input::new("cd");
+    assert!(whitespaces.parse(&mut input).is_ok());
     let parse_result = many_a.parse(&mut input);
     assert!(parse_result.is_ok(), "parse error");
     let parse_result = b_or_c.parse(&mut input);
@@ -417,7 +818,7 @@ fn combination_parser() {
 
 #[test]
 fn str_parser() {
-    let mut code = "helloworld".to_string();
+    let mut code = Input::new("helloworld");
     let hello_p = Str::new("hello");
     let world_p = Str::new("world");
 
@@ -426,3 +827,43 @@ fn str_parser() {
     let parse_result = world_p.parse(&mut code);
     assert!(parse_result.is_ok(), "parse error");
 }
+
+#[test]
+fn backtrack_test() {
+    let mut input = Input::new("abc");
+    let p = Try::new(vec![Str::new("abd"), Str::new("ab")]);
+    assert_eq!(p.parse(&mut input).unwrap(), "ab");
+    assert_eq!(input.rest(), "c");
+
+    let p = Try::new(vec![Str::new("cd"), Str::new("ce")]);
+    assert!(p.parse(&mut input).is_err());
+    assert_eq!(input.rest(), "c");
+}
+
+#[test]
+fn position_test() {
+    let mut input = Input::new("ab\ncd");
+    let p = Many1::new(Lower::new());
+    assert!(p.parse(&mut input).is_ok());
+    assert_eq!(input.position(), Position{offset: 2, line: 1, column: 3});
+    assert!(Char::new('\n').parse(&mut input).is_ok());
+    let e = Char::new('x').parse(&mut input).unwrap_err().in_file("test.sm");
+    assert_eq!((e.line, e.char), (2, 1));
+    assert_eq!(e.expected, vec![Expected::Char('x')]);
+    assert_eq!(e.found, Found::Char('c'));
+    assert_eq!(e.to_string(), "test.sm:2:1 expected 'x' but got 'c'");
+}
+
+#[test]
+fn try_expected_test() {
+    let p = Try::new(vec![Char::new('a'), Char::new('b'), Char::new('a'), Char::new('c')]);
+    let e = p.parse(&mut Input::new("x")).unwrap_err();
+    assert_eq!(e.expected, vec![Expected::Char('a'), Expected::Char('b'), Expected::Char('c')]);
+    assert_eq!(e.explanation(), "expected 'a', 'b' or 'c' but got 'x'");
+
+    // the alternative that got furthest wins
+    let p = Try::new(vec![Char::new('x'), Then::new(Char::new('a'), Char::new('b'))]);
+    let e = p.parse(&mut Input::new("ac")).unwrap_err();
+    assert_eq!((e.line, e.char), (1, 2));
+    assert_eq!(e.expected, vec![Expected::Char('b')]);
+}
@@ -1,87 +1,153 @@
+#![allow(clippy::new_ret_no_self)] // parsers are built and combined as boxed trait objects
+
 pub mod combinator;
 use parser::combinator::*;
 pub mod syntax;
+use std::cell::RefCell;
 
 //---- Expression --------------------------------------------------------------------
 pub struct Num {}
 impl Num {
-    pub fn new() -> Box<Parser<syntax::Term>> {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
         Box::new(Num{})
     }
 }
 impl Parser<syntax::Term> for Num {
-    fn parse(&self, input : &mut String) -> Result<syntax::Term, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
         Spaces::new().parse(input)?;
+        let start = input.position();
         let num = Digit::new().parse(input)?;
-        Ok(syntax::Term::Num(num))
+        Ok(syntax::Term::Num(num, syntax::Span::new(start, input.position())))
     }
 }
 
-pub struct Var {}
-impl Var {
-    pub fn new() -> Box<Parser<syntax::Term>> {
-        Box::new(Var{})
+pub struct StrLiteral {}
+impl StrLiteral {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
+        Box::new(StrLiteral{})
+    }
+}
+impl Parser<syntax::Term> for StrLiteral {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let text = Quoted::new().parse(input)?;
+        Ok(syntax::Term::Str(text, syntax::Span::new(start, input.position())))
     }
+}
 
-    fn is_reserved_name(name : &str) -> bool {
-        let reserved = vec![
-            "if",
-            "then",
-            "else",
-            "end"
-        ];
+// [a, b, c], or [] for the empty list
+pub struct ListLiteral {}
+impl ListLiteral {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
+        Box::new(ListLiteral{})
+    }
+}
+impl Parser<syntax::Term> for ListLiteral {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        Char::new('[').parse(input)?;
+        Spaces::new().parse(input)?;
+        let items = if input.consume("]") {
+            vec![]
+        }
+        else {
+            let items = SepBy::new(Expression::new(), Then::new(Spaces::new(), Char::new(','))).parse(input)?;
+            Spaces::new().parse(input)?;
+            Char::new(']').parse(input)?;
+            items
+        };
+        Ok(syntax::Term::List(items, syntax::Span::new(start, input.position())))
+    }
+}
+
+// Every reserved word of the language. Syntax that needs a new keyword adds it here,
+// which keeps it from being used as a name.
+pub const KEYWORDS : &[&str] = &[
+    "if",
+    "then",
+    "else",
+    "end",
+    "let",
+    "rec",
+    "in",
+    "type",
+    "match",
+    "with",
+];
 
-        reserved.iter().any(|x| *x == name)
+fn keyword(word : &'static str) -> Box<dyn Parser<()>> {
+    debug_assert!(KEYWORDS.contains(&word), "'{}' is missing from KEYWORDS", word);
+    Keyword::new(word)
+}
+
+// A name that a value can be bound to, which is any name but a keyword
+pub struct Identifier {}
+impl Identifier {
+    pub fn new() -> Box<dyn Parser<String>> {
+        Box::new(Identifier{})
     }
 }
-impl Parser<syntax::Term> for Var {
-    fn parse(&self, input : &mut String) -> Result<syntax::Term, ParseError> {
+impl Parser<String> for Identifier {
+    fn parse(&self, input : &mut Input) -> Result<String, ParseError> {
         Spaces::new().parse(input)?;
-        let var = Many1::new(Lower::new()).parse(input)?;
-        let name = var.into_iter().collect::<String>();
+        let start = *input;
+        let name = Name::new().parse(input)?;
 
-        if !Var::is_reserved_name(&name) {
-            Ok(syntax::Term::Var(name))
+        if !KEYWORDS.contains(&name.as_str()) {
+            Ok(name)
         }
         else {
-            Err (ParseError {
-                filename: "".to_string(),
-                line: 0,
-                char: 0,
-                explanation: "".to_string(),
-            })
+            Err(ParseError{found: Found::Word(name), ..start.error(Expected::Name)})
         }
     }
 }
 
+pub struct Var {}
+impl Var {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
+        Box::new(Var{})
+    }
+}
+impl Parser<syntax::Term> for Var {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let name = Identifier::new().parse(input)?;
+        Ok(syntax::Term::Var(name, syntax::Span::new(start, input.position())))
+    }
+}
+
 pub struct Fun {}
 impl Fun {
-    pub fn new() -> Box<Parser<syntax::Term>> {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
         Box::new(Fun{})
     }
 }
 impl Parser<syntax::Term> for Fun {
-    fn parse(&self, input : &mut String) -> Result<syntax::Term, ParseError> {
-        let name = Between::new(
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let params = Between::new(
             Char::new('|'),
-            Many1::new(Lower::new()),
-            Char::new('|')
+            SepBy::new(Identifier::new(), Then::new(Spaces::new(), Char::new(','))),
+            Then::new(Spaces::new(), Char::new('|'))
         ).parse(input)?;
         let exp = Expression::new().parse(input)?;
-        let name = name.into_iter().collect::<String>();
 
-        Ok(syntax::Term::Function(name, Box::new(exp)))
+        Ok(syntax::Term::Function(params, Box::new(exp), syntax::Span::new(start, input.position())))
     }
 }
 
 pub struct ParenedExpression {}
 impl ParenedExpression {
-    pub fn new() -> Box<Parser<syntax::Term>> {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
         Box::new(ParenedExpression{})
     }
 }
 impl Parser<syntax::Term> for ParenedExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Term, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
         let exp = Between::new(
             Then::new(Spaces::new(), Char::new('(')),
             Then::new(Spaces::new(), Expression::new()),
@@ -93,44 +159,144 @@ impl Parser<syntax::Term> for ParenedExpression {
 
 pub struct IfExpression {}
 impl IfExpression {
-    pub fn new () -> Box<Parser<syntax::Term>> {
+    pub fn new () -> Box<dyn Parser<syntax::Term>> {
         Box::new(IfExpression{})
     }
 }
 impl Parser<syntax::Term> for IfExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Term, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
         Spaces::new().parse(input)?;
-        Str::new("if").parse(input)?;
+        let start = input.position();
+        keyword("if").parse(input)?;
         Spaces::new().parse(input)?;
         let cond_exp = Expression::new().parse(input)?;
         Spaces::new().parse(input)?;
-        Str::new("then").parse(input)?;
+        keyword("then").parse(input)?;
         Spaces::new().parse(input)?;
         let then_exp = Expression::new().parse(input)?;
         Spaces::new().parse(input)?;
-        Str::new("else").parse(input)?;
+        keyword("else").parse(input)?;
         Spaces::new().parse(input)?;
         let else_exp = Expression::new().parse(input)?;
         Spaces::new().parse(input)?;
-        Str::new("end").parse(input)?;
+        keyword("end").parse(input)?;
+
+        let span = syntax::Span::new(start, input.position());
+        Ok(syntax::Term::If(Box::new(cond_exp), Box::new(then_exp), Box::new(else_exp), span))
+    }
+}
+
+// let x = e1 in e2, or let rec f = |x| e1 in e2 where f can be called in its own body
+pub struct LetExpression {}
+impl LetExpression {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
+        Box::new(LetExpression{})
+    }
+
+    fn body(input : &mut Input) -> Result<Box<syntax::Exp>, ParseError> {
+        Spaces::new().parse(input)?;
+        keyword("in").parse(input)?;
+        Ok(Box::new(Expression::new().parse(input)?))
+    }
+}
+impl Parser<syntax::Term> for LetExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        keyword("let").parse(input)?;
+        Spaces::new().parse(input)?;
 
-        Ok(syntax::Term::If(Box::new(cond_exp), Box::new(then_exp), Box::new(else_exp)))
+        let rec = keyword("rec").parse(input).is_ok();
+        let name = Identifier::new().parse(input)?;
+        Spaces::new().parse(input)?;
+        Char::new('=').parse(input)?;
+        if rec {
+            let function = Fun::new().parse(input)?;
+            let body = LetExpression::body(input)?;
+            Ok(syntax::Term::LetRec(name, Box::new(function), body, syntax::Span::new(start, input.position())))
+        }
+        else {
+            let value = Expression::new().parse(input)?;
+            let body = LetExpression::body(input)?;
+            Ok(syntax::Term::Let(name, Box::new(value), body, syntax::Span::new(start, input.position())))
+        }
+    }
+}
+
+pub struct ConstructorTerm {}
+impl ConstructorTerm {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
+        Box::new(ConstructorTerm{})
+    }
+}
+impl Parser<syntax::Term> for ConstructorTerm {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let name = Name::constructor().parse(input)?;
+        Ok(syntax::Term::Constructor(name, syntax::Span::new(start, input.position())))
+    }
+}
+
+// match e with | p1 -> e1 | p2 -> e2 end, where the first arm whose pattern matches is taken
+pub struct MatchExpression {}
+impl MatchExpression {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
+        Box::new(MatchExpression{})
+    }
+}
+impl Parser<syntax::Term> for MatchExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        keyword("match").parse(input)?;
+        let exp = Expression::new().parse(input)?;
+        Spaces::new().parse(input)?;
+        keyword("with").parse(input)?;
+        let arms = Many1::new(MatchArm::new()).parse(input)?;
+        Spaces::new().parse(input)?;
+        keyword("end").parse(input)?;
+
+        Ok(syntax::Term::Match(Box::new(exp), arms, syntax::Span::new(start, input.position())))
+    }
+}
+
+pub struct MatchArm {}
+impl MatchArm {
+    pub fn new() -> Box<dyn Parser<(syntax::Pattern, syntax::Exp)>> {
+        Box::new(MatchArm{})
+    }
+}
+impl Parser<(syntax::Pattern, syntax::Exp)> for MatchArm {
+    fn parse(&self, input : &mut Input) -> Result<(syntax::Pattern, syntax::Exp), ParseError> {
+        Spaces::new().parse(input)?;
+        Char::new('|').parse(input)?;
+        let pattern = Pattern::new().parse(input)?;
+        Spaces::new().parse(input)?;
+        Str::new("->").parse(input)?;
+        let exp = Expression::new().parse(input)?;
+        Ok((pattern, exp))
     }
 }
 
 pub struct Term {}
 impl Term {
-    pub fn new() -> Box<Parser<syntax::Term>> {
+    pub fn new() -> Box<dyn Parser<syntax::Term>> {
         Box::new(Term{})
     }
 }
 impl Parser<syntax::Term> for Term {
-    fn parse(&self, input : &mut String) -> Result<syntax::Term, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Term, ParseError> {
         Try::new(vec![
             IfExpression::new(),
+            LetExpression::new(),
+            MatchExpression::new(),
             Fun::new(),
             ParenedExpression::new(),
             Num::new(),
+            StrLiteral::new(),
+            ListLiteral::new(),
+            ConstructorTerm::new(),
             Var::new(),
         ]).parse(input)
     }
@@ -138,12 +304,12 @@ impl Parser<syntax::Term> for Term {
 
 pub struct AppExpression {}
 impl AppExpression {
-    pub fn new() -> Box<Parser<syntax::Exp5>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp5>> {
         Box::new(AppExpression{})
     }
 }
 impl Parser<syntax::Exp5> for AppExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp5, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp5, ParseError> {
         Spaces::new().parse(input)?;
         let term = Term::new().parse(input)?;
         Spaces::new().parse(input)?;
@@ -154,24 +320,24 @@ impl Parser<syntax::Exp5> for AppExpression {
 
 pub struct EmptyExpression5 {}
 impl EmptyExpression5 {
-    pub fn new() -> Box<Parser<syntax::Exp5>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp5>> {
         Box::new(EmptyExpression5{})
     }
 }
 impl Parser<syntax::Exp5> for EmptyExpression5 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp5, ParseError> {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::Exp5, ParseError> {
         Ok(syntax::Exp5::Empty)
     }
 }
 
 struct Expression5 {}
 impl Expression5 {
-    pub fn new() -> Box<Parser<syntax::Exp5>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp5>> {
         Box::new(Expression5{})
     }
 }
 impl Parser<syntax::Exp5> for Expression5 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp5, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp5, ParseError> {
         Try::new(vec![
             AppExpression::new(),
             EmptyExpression5::new(),
@@ -179,14 +345,57 @@ impl Parser<syntax::Exp5> for Expression5 {
     }
 }
 
-pub struct Expression4 {}
-impl Expression4 {
-    pub fn new() -> Box<Parser<syntax::Exp4>> {
-        Box::new(Expression4{})
+pub struct NotExpression {}
+impl NotExpression {
+    pub fn new() -> Box<dyn Parser<syntax::Exp4>> {
+        Box::new(NotExpression{})
     }
 }
-impl Parser<syntax::Exp4> for Expression4 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp4, ParseError> {
+impl Parser<syntax::Exp4> for NotExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp4, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        Char::new('!').parse(input)?;
+        let exp4 = Expression4::new().parse(input)?;
+        let span = syntax::Span::new(start, input.position());
+        Ok(syntax::Exp4::Not(Box::new(exp4), span))
+    }
+}
+
+pub struct NegExpression {}
+impl NegExpression {
+    pub fn new() -> Box<dyn Parser<syntax::Exp4>> {
+        Box::new(NegExpression{})
+    }
+}
+impl Parser<syntax::Exp4> for NegExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp4, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        Char::new('-').parse(input)?;
+
+        // digits right after the sign are a negative literal, so that i32::MIN can be written
+        if let Ok(num) = Digit::negative().parse(input) {
+            let term = syntax::Term::Num(num, syntax::Span::new(start, input.position()));
+            Spaces::new().parse(input)?;
+            let exp5 = Expression5::new().parse(input)?;
+            return Ok(syntax::Exp4::Exp4(Box::new(term), Box::new(exp5)));
+        }
+
+        let exp4 = Expression4::new().parse(input)?;
+        let span = syntax::Span::new(start, input.position());
+        Ok(syntax::Exp4::Neg(Box::new(exp4), span))
+    }
+}
+
+pub struct AppExpression4 {}
+impl AppExpression4 {
+    pub fn new() -> Box<dyn Parser<syntax::Exp4>> {
+        Box::new(AppExpression4{})
+    }
+}
+impl Parser<syntax::Exp4> for AppExpression4 {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp4, ParseError> {
         Spaces::new().parse(input)?;
         let term = Term::new().parse(input)?;
         Spaces::new().parse(input)?;
@@ -195,14 +404,32 @@ impl Parser<syntax::Exp4> for Expression4 {
     }
 }
 
+// prefix operators apply to a whole application: !f x is !(f x) and -f x is -(f x).
+// As an argument, a negative number needs parentheses: f -1 is f minus 1.
+pub struct Expression4 {}
+impl Expression4 {
+    pub fn new() -> Box<dyn Parser<syntax::Exp4>> {
+        Box::new(Expression4{})
+    }
+}
+impl Parser<syntax::Exp4> for Expression4 {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp4, ParseError> {
+        Try::new(vec![
+            NotExpression::new(),
+            NegExpression::new(),
+            AppExpression4::new(),
+        ]).parse(input)
+    }
+}
+
 pub struct MulExpression {}
 impl MulExpression {
-    pub fn new() -> Box<Parser<syntax::Exp3>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp3>> {
         Box::new(MulExpression{})
     }
 }
 impl Parser<syntax::Exp3> for MulExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp3, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp3, ParseError> {
         Spaces::new().parse(input)?;
         Char::new('*').parse(input)?;
         Spaces::new().parse(input)?;
@@ -215,12 +442,12 @@ impl Parser<syntax::Exp3> for MulExpression {
 
 pub struct DivExpression {}
 impl DivExpression {
-    pub fn new() -> Box<Parser<syntax::Exp3>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp3>> {
         Box::new(DivExpression{})
     }
 }
 impl Parser<syntax::Exp3> for DivExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp3, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp3, ParseError> {
         Spaces::new().parse(input)?;
         Char::new('/').parse(input)?;
         Spaces::new().parse(input)?;
@@ -233,24 +460,24 @@ impl Parser<syntax::Exp3> for DivExpression {
 
 pub struct EmptyExpression3 {}
 impl EmptyExpression3 {
-    pub fn new() -> Box<Parser<syntax::Exp3>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp3>> {
         Box::new(EmptyExpression3{})
     }
 }
 impl Parser<syntax::Exp3> for EmptyExpression3 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp3, ParseError> {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::Exp3, ParseError> {
         Ok(syntax::Exp3::Empty)
     }
 }
 
 struct Expression3 {}
 impl Expression3 {
-    pub fn new() -> Box<Parser<syntax::Exp3>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp3>> {
         Box::new(Expression3{})
     }
 }
 impl Parser<syntax::Exp3> for Expression3 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp3, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp3, ParseError> {
         Try::new(vec![
             MulExpression::new(),
             DivExpression::new(),
@@ -261,12 +488,12 @@ impl Parser<syntax::Exp3> for Expression3 {
 
 pub struct Expression2 {}
 impl Expression2 {
-    pub fn new() -> Box<Parser<syntax::Exp2>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp2>> {
         Box::new(Expression2{})
     }
 }
 impl Parser<syntax::Exp2> for Expression2 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp2, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp2, ParseError> {
         Spaces::new().parse(input)?;
         let exp4 = Expression4::new().parse(input)?;
         Spaces::new().parse(input)?;
@@ -277,12 +504,12 @@ impl Parser<syntax::Exp2> for Expression2 {
 
 pub struct AddExpression {}
 impl AddExpression {
-    pub fn new() -> Box<Parser<syntax::Exp1>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp1>> {
         Box::new(AddExpression{})
     }
 }
 impl Parser<syntax::Exp1> for AddExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp1, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp1, ParseError> {
         Spaces::new().parse(input)?;
         Char::new('+').parse(input)?;
         let exp2 = Expression2::new().parse(input)?;
@@ -293,12 +520,12 @@ impl Parser<syntax::Exp1> for AddExpression {
 
 pub struct SubExpression {}
 impl SubExpression {
-    pub fn new() -> Box<Parser<syntax::Exp1>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp1>> {
         Box::new(SubExpression{})
     }
 }
 impl Parser<syntax::Exp1> for SubExpression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp1, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp1, ParseError> {
         Spaces::new().parse(input)?;
         Char::new('-').parse(input)?;
         let exp2 = Expression2::new().parse(input)?;
@@ -307,27 +534,44 @@ impl Parser<syntax::Exp1> for SubExpression {
     }
 }
 
+pub struct ConcatExpression {}
+impl ConcatExpression {
+    pub fn new() -> Box<dyn Parser<syntax::Exp1>> {
+        Box::new(ConcatExpression{})
+    }
+}
+impl Parser<syntax::Exp1> for ConcatExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp1, ParseError> {
+        Spaces::new().parse(input)?;
+        Str::new("++").parse(input)?;
+        let exp2 = Expression2::new().parse(input)?;
+        let exp1 = Expression1::new().parse(input)?;
+        Ok(syntax::Exp1::Concat(Box::new(exp2), Box::new(exp1)))
+    }
+}
+
 pub struct EmptyExpression1 {}
 impl EmptyExpression1 {
-    pub fn new() -> Box<Parser<syntax::Exp1>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp1>> {
         Box::new(EmptyExpression1{})
     }
 }
 impl Parser<syntax::Exp1> for EmptyExpression1 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp1, ParseError> {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::Exp1, ParseError> {
         Ok(syntax::Exp1::Empty)
     }
 }
 
 struct Expression1 {}
 impl Expression1 {
-    pub fn new() -> Box<Parser<syntax::Exp1>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp1>> {
         Box::new(Expression1{})
     }
 }
 impl Parser<syntax::Exp1> for Expression1 {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp1, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp1, ParseError> {
         Try::new(vec![
+            ConcatExpression::new(),
             AddExpression::new(),
             SubExpression::new(),
             EmptyExpression1::new(),
@@ -335,31 +579,502 @@ impl Parser<syntax::Exp1> for Expression1 {
     }
 }
 
+pub struct ArithExpression {}
+impl ArithExpression {
+    pub fn new() -> Box<dyn Parser<syntax::ArithExp>> {
+        Box::new(ArithExpression{})
+    }
+}
+impl Parser<syntax::ArithExp> for ArithExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::ArithExp, ParseError> {
+        Spaces::new().parse(input)?;
+        let exp2 = Expression2::new().parse(input)?;
+        Spaces::new().parse(input)?;
+        let exp1 = Expression1::new().parse(input)?;
+        Ok(syntax::ArithExp::ArithExp(Box::new(exp2), Box::new(exp1)))
+    }
+}
+
+pub struct ConsRestExpression {}
+impl ConsRestExpression {
+    pub fn new() -> Box<dyn Parser<syntax::ConsRest>> {
+        Box::new(ConsRestExpression{})
+    }
+}
+impl Parser<syntax::ConsRest> for ConsRestExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::ConsRest, ParseError> {
+        Spaces::new().parse(input)?;
+        Str::new("::").parse(input)?;
+        let exp = ConsExpression::new().parse(input)?;
+        Ok(syntax::ConsRest::Cons(Box::new(exp)))
+    }
+}
+
+pub struct EmptyConsRest {}
+impl EmptyConsRest {
+    pub fn new() -> Box<dyn Parser<syntax::ConsRest>> {
+        Box::new(EmptyConsRest{})
+    }
+}
+impl Parser<syntax::ConsRest> for EmptyConsRest {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::ConsRest, ParseError> {
+        Ok(syntax::ConsRest::Empty)
+    }
+}
+
+pub struct ConsExpression {}
+impl ConsExpression {
+    pub fn new() -> Box<dyn Parser<syntax::ConsExp>> {
+        Box::new(ConsExpression{})
+    }
+}
+impl Parser<syntax::ConsExp> for ConsExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::ConsExp, ParseError> {
+        let exp = ArithExpression::new().parse(input)?;
+        let rest = Try::new(vec![
+            ConsRestExpression::new(),
+            EmptyConsRest::new(),
+        ]).parse(input)?;
+        Ok(syntax::ConsExp::ConsExp(Box::new(exp), Box::new(rest)))
+    }
+}
+
+pub struct CmpOperator {}
+impl CmpOperator {
+    pub fn new() -> Box<dyn Parser<syntax::CmpOp>> {
+        Box::new(CmpOperator{})
+    }
+}
+impl Parser<syntax::CmpOp> for CmpOperator {
+    fn parse(&self, input : &mut Input) -> Result<syntax::CmpOp, ParseError> {
+        // longer operators first, so that "<=" is not read as "<"
+        let operators = [
+            ("==", syntax::CmpOp::Equal),
+            ("!=", syntax::CmpOp::NotEqual),
+            ("<=", syntax::CmpOp::LessEqual),
+            (">=", syntax::CmpOp::GreaterEqual),
+            ("<", syntax::CmpOp::Less),
+            (">", syntax::CmpOp::Greater),
+        ];
+        Spaces::new().parse(input)?;
+        for (str, op) in operators.iter() {
+            if input.consume(str) {
+                return Ok(*op);
+            }
+        }
+        let expected = operators.iter().map(|(str, _)| Expected::Str(str)).collect();
+        Err(ParseError{expected, ..input.error(Expected::Eof)})
+    }
+}
+
+pub struct CmpRestExpression {}
+impl CmpRestExpression {
+    pub fn new() -> Box<dyn Parser<syntax::CmpRest>> {
+        Box::new(CmpRestExpression{})
+    }
+}
+impl Parser<syntax::CmpRest> for CmpRestExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::CmpRest, ParseError> {
+        let op = CmpOperator::new().parse(input)?;
+        let exp = ConsExpression::new().parse(input)?;
+        Ok(syntax::CmpRest::Cmp(op, Box::new(exp)))
+    }
+}
+
+pub struct EmptyCmpRest {}
+impl EmptyCmpRest {
+    pub fn new() -> Box<dyn Parser<syntax::CmpRest>> {
+        Box::new(EmptyCmpRest{})
+    }
+}
+impl Parser<syntax::CmpRest> for EmptyCmpRest {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::CmpRest, ParseError> {
+        Ok(syntax::CmpRest::Empty)
+    }
+}
+
+pub struct CmpExpression {}
+impl CmpExpression {
+    pub fn new() -> Box<dyn Parser<syntax::CmpExp>> {
+        Box::new(CmpExpression{})
+    }
+}
+impl Parser<syntax::CmpExp> for CmpExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::CmpExp, ParseError> {
+        let exp = ConsExpression::new().parse(input)?;
+        let rest = Try::new(vec![
+            CmpRestExpression::new(),
+            EmptyCmpRest::new(),
+        ]).parse(input)?;
+        Ok(syntax::CmpExp::CmpExp(Box::new(exp), Box::new(rest)))
+    }
+}
+
+pub struct AndRestExpression {}
+impl AndRestExpression {
+    pub fn new() -> Box<dyn Parser<syntax::AndRest>> {
+        Box::new(AndRestExpression{})
+    }
+}
+impl Parser<syntax::AndRest> for AndRestExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::AndRest, ParseError> {
+        Spaces::new().parse(input)?;
+        Str::new("&&").parse(input)?;
+        let exp = CmpExpression::new().parse(input)?;
+        let rest = Try::new(vec![
+            AndRestExpression::new(),
+            EmptyAndRest::new(),
+        ]).parse(input)?;
+        Ok(syntax::AndRest::And(Box::new(exp), Box::new(rest)))
+    }
+}
+
+pub struct EmptyAndRest {}
+impl EmptyAndRest {
+    pub fn new() -> Box<dyn Parser<syntax::AndRest>> {
+        Box::new(EmptyAndRest{})
+    }
+}
+impl Parser<syntax::AndRest> for EmptyAndRest {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::AndRest, ParseError> {
+        Ok(syntax::AndRest::Empty)
+    }
+}
+
+pub struct AndExpression {}
+impl AndExpression {
+    pub fn new() -> Box<dyn Parser<syntax::AndExp>> {
+        Box::new(AndExpression{})
+    }
+}
+impl Parser<syntax::AndExp> for AndExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::AndExp, ParseError> {
+        let exp = CmpExpression::new().parse(input)?;
+        let rest = Try::new(vec![
+            AndRestExpression::new(),
+            EmptyAndRest::new(),
+        ]).parse(input)?;
+        Ok(syntax::AndExp::AndExp(Box::new(exp), Box::new(rest)))
+    }
+}
+
+pub struct OrRestExpression {}
+impl OrRestExpression {
+    pub fn new() -> Box<dyn Parser<syntax::OrRest>> {
+        Box::new(OrRestExpression{})
+    }
+}
+impl Parser<syntax::OrRest> for OrRestExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::OrRest, ParseError> {
+        Spaces::new().parse(input)?;
+        Str::new("||").parse(input)?;
+        let exp = AndExpression::new().parse(input)?;
+        let rest = Try::new(vec![
+            OrRestExpression::new(),
+            EmptyOrRest::new(),
+        ]).parse(input)?;
+        Ok(syntax::OrRest::Or(Box::new(exp), Box::new(rest)))
+    }
+}
+
+pub struct EmptyOrRest {}
+impl EmptyOrRest {
+    pub fn new() -> Box<dyn Parser<syntax::OrRest>> {
+        Box::new(EmptyOrRest{})
+    }
+}
+impl Parser<syntax::OrRest> for EmptyOrRest {
+    fn parse(&self, _input : &mut Input) -> Result<syntax::OrRest, ParseError> {
+        Ok(syntax::OrRest::Empty)
+    }
+}
+
+// Precedence from loosest to tightest: || && comparisons :: + - ++ * / ! application
 pub struct Expression {}
 impl Expression {
-    pub fn new() -> Box<Parser<syntax::Exp>> {
+    pub fn new() -> Box<dyn Parser<syntax::Exp>> {
         Box::new(Expression{})
     }
 }
 impl Parser<syntax::Exp> for Expression {
-    fn parse(&self, input : &mut String) -> Result<syntax::Exp, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Exp, ParseError> {
+        let exp = AndExpression::new().parse(input)?;
+        let rest = Try::new(vec![
+            OrRestExpression::new(),
+            EmptyOrRest::new(),
+        ]).parse(input)?;
+        Ok(syntax::Exp::Exp(Box::new(exp), Box::new(rest)))
+    }
+}
+
+//---- Pattern --------------------------------------------------------------------
+// _ matches anything, and any other name matches anything and binds it
+pub struct VarPattern {}
+impl VarPattern {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(VarPattern{})
+    }
+}
+impl Parser<syntax::Pattern> for VarPattern {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
         Spaces::new().parse(input)?;
-        let exp2 = Expression2::new().parse(input)?;
+        let start = input.position();
+        let name = Identifier::new().parse(input)?;
+        let span = syntax::Span::new(start, input.position());
+        if name == "_" {
+            Ok(syntax::Pattern::Wildcard(span))
+        }
+        else {
+            Ok(syntax::Pattern::Var(name, span))
+        }
+    }
+}
+
+pub struct NumPattern {}
+impl NumPattern {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(NumPattern{})
+    }
+}
+impl Parser<syntax::Pattern> for NumPattern {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
         Spaces::new().parse(input)?;
-        let exp1 = Expression1::new().parse(input)?;
-        Ok(syntax::Exp::Exp(Box::new(exp2), Box::new(exp1)))
+        let start = input.position();
+        let num = match Char::new('-').parse(input) {
+            Ok(_) => Digit::negative().parse(input)?,
+            Err(e) => Digit::new().parse(input).map_err(|digit| e.merge(digit))?,
+        };
+        Ok(syntax::Pattern::Num(num, syntax::Span::new(start, input.position())))
+    }
+}
+
+pub struct StrPattern {}
+impl StrPattern {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(StrPattern{})
+    }
+}
+impl Parser<syntax::Pattern> for StrPattern {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let text = Quoted::new().parse(input)?;
+        Ok(syntax::Pattern::Str(text.into(), syntax::Span::new(start, input.position())))
+    }
+}
+
+// A constructor followed by the patterns for its fields, like Some (Some x).
+// As an argument of another constructor it has no fields unless it is parenthesized.
+pub struct ConstructorPattern {
+    pub fields: bool,
+}
+impl ConstructorPattern {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(ConstructorPattern{fields: true})
+    }
+
+    pub fn without_fields() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(ConstructorPattern{fields: false})
+    }
+}
+impl Parser<syntax::Pattern> for ConstructorPattern {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let name = Name::constructor().parse(input)?;
+        let fields = if self.fields {
+            Many::new(PatternAtom::new()).parse(input)?
+        }
+        else {
+            vec![]
+        };
+        Ok(syntax::Pattern::Constructor(name, fields, syntax::Span::new(start, input.position())))
+    }
+}
+
+pub struct ParenedPattern {}
+impl ParenedPattern {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(ParenedPattern{})
+    }
+}
+impl Parser<syntax::Pattern> for ParenedPattern {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
+        Between::new(
+            Then::new(Spaces::new(), Char::new('(')),
+            Pattern::new(),
+            Then::new(Spaces::new(), Char::new(')')),
+        ).parse(input)
+    }
+}
+
+pub struct PatternAtom {}
+impl PatternAtom {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(PatternAtom{})
+    }
+}
+impl Parser<syntax::Pattern> for PatternAtom {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
+        Try::new(vec![
+            ParenedPattern::new(),
+            NumPattern::new(),
+            StrPattern::new(),
+            ConstructorPattern::without_fields(),
+            VarPattern::new(),
+        ]).parse(input)
+    }
+}
+
+pub struct Pattern {}
+impl Pattern {
+    pub fn new() -> Box<dyn Parser<syntax::Pattern>> {
+        Box::new(Pattern{})
+    }
+}
+impl Parser<syntax::Pattern> for Pattern {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Pattern, ParseError> {
+        Try::new(vec![
+            ConstructorPattern::new(),
+            PatternAtom::new(),
+        ]).parse(input)
+    }
+}
+
+//---- Type --------------------------------------------------------------------
+// A type variable like a, which stands for any type
+pub struct TypeVar {}
+impl TypeVar {
+    pub fn new() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(TypeVar{})
+    }
+}
+impl Parser<syntax::TypeExp> for TypeVar {
+    fn parse(&self, input : &mut Input) -> Result<syntax::TypeExp, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let name = Identifier::new().parse(input)?;
+        Ok(syntax::TypeExp::Var(name, syntax::Span::new(start, input.position())))
+    }
+}
+
+// A named type followed by its parameters, like Option a.
+// As a parameter of another type it has none unless it is parenthesized.
+pub struct TypeName {
+    pub params: bool,
+}
+impl TypeName {
+    pub fn new() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(TypeName{params: true})
+    }
+
+    pub fn without_params() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(TypeName{params: false})
+    }
+}
+impl Parser<syntax::TypeExp> for TypeName {
+    fn parse(&self, input : &mut Input) -> Result<syntax::TypeExp, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let name = Name::constructor().parse(input)?;
+        let params = if self.params {
+            Many::new(TypeAtom::new()).parse(input)?
+        }
+        else {
+            vec![]
+        };
+        Ok(syntax::TypeExp::Name(name, params, syntax::Span::new(start, input.position())))
+    }
+}
+
+// [a] is the type of lists of a
+pub struct ListType {}
+impl ListType {
+    pub fn new() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(ListType{})
+    }
+}
+impl Parser<syntax::TypeExp> for ListType {
+    fn parse(&self, input : &mut Input) -> Result<syntax::TypeExp, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let element = Between::new(
+            Char::new('['),
+            TypeExpression::new(),
+            Then::new(Spaces::new(), Char::new(']')),
+        ).parse(input)?;
+        Ok(syntax::TypeExp::List(Box::new(element), syntax::Span::new(start, input.position())))
+    }
+}
+
+pub struct ParenedType {}
+impl ParenedType {
+    pub fn new() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(ParenedType{})
+    }
+}
+impl Parser<syntax::TypeExp> for ParenedType {
+    fn parse(&self, input : &mut Input) -> Result<syntax::TypeExp, ParseError> {
+        Between::new(
+            Then::new(Spaces::new(), Char::new('(')),
+            TypeExpression::new(),
+            Then::new(Spaces::new(), Char::new(')')),
+        ).parse(input)
+    }
+}
+
+pub struct TypeAtom {}
+impl TypeAtom {
+    pub fn new() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(TypeAtom{})
+    }
+}
+impl Parser<syntax::TypeExp> for TypeAtom {
+    fn parse(&self, input : &mut Input) -> Result<syntax::TypeExp, ParseError> {
+        Try::new(vec![
+            ParenedType::new(),
+            ListType::new(),
+            TypeName::without_params(),
+            TypeVar::new(),
+        ]).parse(input)
+    }
+}
+
+// a -> b -> c is a -> (b -> c)
+pub struct TypeExpression {}
+impl TypeExpression {
+    pub fn new() -> Box<dyn Parser<syntax::TypeExp>> {
+        Box::new(TypeExpression{})
+    }
+}
+impl Parser<syntax::TypeExp> for TypeExpression {
+    fn parse(&self, input : &mut Input) -> Result<syntax::TypeExp, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let param = Try::new(vec![
+            TypeName::new(),
+            TypeAtom::new(),
+        ]).parse(input)?;
+
+        let mut rest = *input;
+        if Then::new(Spaces::new(), Str::new("->")).parse(&mut rest).is_err() {
+            return Ok(param);
+        }
+        *input = rest;
+        let result = TypeExpression::new().parse(input)?;
+        Ok(syntax::TypeExp::Fun(Box::new(param), Box::new(result), syntax::Span::new(start, input.position())))
     }
 }
 
 //---- Statement --------------------------------------------------------------------
 pub struct ExpressionStatement {}
 impl ExpressionStatement {
-    pub fn new() -> Box<Parser<syntax::Statement>> {
+    pub fn new() -> Box<dyn Parser<syntax::Statement>> {
         Box::new(ExpressionStatement{})
     }
 }
 impl Parser<syntax::Statement> for ExpressionStatement {
-    fn parse(&self, input : &mut String) -> Result<syntax::Statement, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Statement, ParseError> {
         Spaces::new().parse(input)?;
         let exp = Expression::new().parse(input)?;
         Ok(syntax::Statement::ExpressionStatement(Box::new(exp)))
@@ -368,33 +1083,76 @@ impl Parser<syntax::Statement> for ExpressionStatement {
 
 pub struct AssignmentStatement {}
 impl AssignmentStatement {
-    pub fn new() -> Box<Parser<syntax::Statement>> {
+    pub fn new() -> Box<dyn Parser<syntax::Statement>> {
         Box::new(AssignmentStatement{})
     }
 }
 impl Parser<syntax::Statement> for AssignmentStatement {
-    fn parse(&self, input : &mut String) -> Result<syntax::Statement, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Statement, ParseError> {
         Spaces::new().parse(input)?;
-        let var = Many1::new(Lower::new()).parse(input)?;
+        let start = input.position();
+        let name = Identifier::new().parse(input)?;
         Spaces::new().parse(input)?;
         Char::new('=').parse(input)?;
         Spaces::new().parse(input)?;
         let exp = Expression::new().parse(input)?;
 
-        let name = var.iter().collect::<String>();
-        Ok(syntax::Statement::AssignmentStatement(name, Box::new(exp)))
+        let span = syntax::Span::new(start, input.position());
+        Ok(syntax::Statement::AssignmentStatement(name, Box::new(exp), span))
+    }
+}
+
+// type Option = None | Some a declares a type by its constructors, each followed by the types of its fields
+pub struct TypeStatement {}
+impl TypeStatement {
+    pub fn new() -> Box<dyn Parser<syntax::Statement>> {
+        Box::new(TypeStatement{})
+    }
+}
+impl Parser<syntax::Statement> for TypeStatement {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Statement, ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        keyword("type").parse(input)?;
+        Spaces::new().parse(input)?;
+        let name = Name::constructor().parse(input)?;
+        Spaces::new().parse(input)?;
+        Char::new('=').parse(input)?;
+        Spaces::new().parse(input)?;
+        input.consume("|"); // the first constructor can be preceded by a '|' too
+        let constructors = SepBy::new(ConstructorDeclaration::new(), Then::new(Spaces::new(), Char::new('|'))).parse(input)?;
+
+        let span = syntax::Span::new(start, input.position());
+        Ok(syntax::Statement::TypeStatement(name, constructors, span))
+    }
+}
+
+pub struct ConstructorDeclaration {}
+impl ConstructorDeclaration {
+    pub fn new() -> Box<dyn Parser<(String, Vec<syntax::TypeExp>, syntax::Span)>> {
+        Box::new(ConstructorDeclaration{})
+    }
+}
+impl Parser<(String, Vec<syntax::TypeExp>, syntax::Span)> for ConstructorDeclaration {
+    fn parse(&self, input : &mut Input) -> Result<(String, Vec<syntax::TypeExp>, syntax::Span), ParseError> {
+        Spaces::new().parse(input)?;
+        let start = input.position();
+        let name = Name::constructor().parse(input)?;
+        let fields = Many::new(TypeAtom::new()).parse(input)?;
+        Ok((name, fields, syntax::Span::new(start, input.position())))
     }
 }
 
 pub struct Statement {}
 impl Statement {
-    pub fn new() -> Box<Parser<syntax::Statement>> {
+    pub fn new() -> Box<dyn Parser<syntax::Statement>> {
         Box::new(Statement{})
     }
 }
 impl Parser<syntax::Statement> for Statement {
-    fn parse(&self, input : &mut String) -> Result<syntax::Statement, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Statement, ParseError> {
         let statement = Try::new(vec![
+            TypeStatement::new(),
             AssignmentStatement::new(),
             ExpressionStatement::new(),
         ]).parse(input)?;
@@ -404,14 +1162,19 @@ impl Parser<syntax::Statement> for Statement {
 }
 
 //---- Block --------------------------------------------------------------------
+// Parses a whole program, reporting errors against the given file name
+pub fn parse_source(filename : &str, text : &str) -> Result<syntax::Block, ParseError> {
+    Block::new().parse(&mut Input::new(text)).map_err(|e| e.in_file(filename))
+}
+
 pub struct SingleExpressionBlock {}
 impl SingleExpressionBlock {
-    pub fn new() -> Box<Parser<syntax::Block>> {
+    pub fn new() -> Box<dyn Parser<syntax::Block>> {
         Box::new(SingleExpressionBlock{})
     }
 }
 impl Parser<syntax::Block> for SingleExpressionBlock {
-    fn parse(&self, input : &mut String) -> Result<syntax::Block, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Block, ParseError> {
         Spaces::new().parse(input)?;
         let statement = Statement::new().parse(input)?;
         Ok(syntax::Block::Block(vec![statement]))
@@ -420,18 +1183,19 @@ impl Parser<syntax::Block> for SingleExpressionBlock {
 
 pub struct MultiExpressionBlock {}
 impl MultiExpressionBlock {
-    pub fn new() -> Box<Parser<syntax::Block>> {
+    pub fn new() -> Box<dyn Parser<syntax::Block>> {
         Box::new(MultiExpressionBlock{})
     }
 }
 impl Parser<syntax::Block> for MultiExpressionBlock {
-    fn parse(&self, input : &mut String) -> Result<syntax::Block, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Block, ParseError> {
         Spaces::new().parse(input)?;
         Char::new('{').parse(input)?;
         Spaces::new().parse(input)?;
         let statements = SepBy::new(Then::new(Spaces::new(), Statement::new()), Then::new(Spaces::new(), Char::new(';'))).parse(input)?;
          Spaces::new().parse(input)?;
         Char::new('}').parse(input)?;
+        Spaces::new().parse(input)?; // a file can end with a newline or a comment
         Eof::new().parse(input)?;
         Ok(syntax::Block::Block(statements))
      }
@@ -439,12 +1203,328 @@ impl Parser<syntax::Block> for MultiExpressionBlock {
 
 pub struct Block {}
 impl Block {
-    pub fn new() -> Box<Parser<syntax::Block>> {
+    pub fn new() -> Box<dyn Parser<syntax::Block>> {
         Box::new(Block{})
     }
 }
 impl Parser<syntax::Block> for Block {
-    fn parse(&self, input : &mut String) -> Result<syntax::Block, ParseError> {
+    fn parse(&self, input : &mut Input) -> Result<syntax::Block, ParseError> {
         Try::new(vec![MultiExpressionBlock::new(), SingleExpressionBlock::new()]).parse(input)
    }
-}
\ No newline at end of file
+}
+
+//---- Recovery --------------------------------------------------------------------
+// Parses as much of a program as it can, for tools that want every syntax error at once.
+// A statement that fails to parse is reported and skipped; parsing resumes at the next
+// statement, or stops at the closing '}'.
+pub fn parse_source_recovering(filename : &str, text : &str) -> (syntax::BlockAst, Vec<ParseError>) {
+    let furthest = RefCell::new(None);
+    let mut input = Input::with_furthest_error(text, &furthest);
+    let mut statements = vec![];
+    let mut errors = vec![];
+
+    skip_spaces(&mut input);
+    let braced = input.consume("{");
+    skip_spaces(&mut input);
+    let mut more = !(braced && input.peek() == Some('}'));
+
+    while more {
+        // optional parts of the grammar swallow their errors, so the failure that got
+        // furthest into the statement explains it better than the one returned
+        furthest.replace(None);
+        match Statement::new().parse(&mut input) {
+            Ok(statement) => {
+                skip_spaces(&mut input);
+                match input.peek() {
+                    Some(';') if braced => {
+                        input.next_char();
+                        statements.push(statement);
+                        continue;
+                    },
+                    Some('}') if braced => {
+                        statements.push(statement);
+                        break;
+                    },
+                    None if !braced => {
+                        statements.push(statement);
+                        break;
+                    },
+                    // whatever parsed is only the beginning of a broken statement
+                    _ if braced => {
+                        let e = input.error(Expected::Char(';'));
+                        errors.push(furthest_error(&furthest, e.merge(input.error(Expected::Char('}')))));
+                    },
+                    _ => errors.push(furthest_error(&furthest, input.error(Expected::Eof))),
+                }
+            },
+            Err(e) => {
+                let e = furthest_error(&furthest, e);
+                skip_to(&mut input, &e);
+                errors.push(e);
+            },
+        }
+        more = braced && synchronize(&mut input, &furthest, &mut errors);
+    }
+
+    if braced {
+        skip_spaces(&mut input);
+        if input.consume("}") {
+            skip_spaces(&mut input);
+            if input.peek().is_some() {
+                errors.push(input.error(Expected::Eof));
+            }
+        }
+        else if input.peek().is_none() {
+            errors.push(input.error(Expected::Char('}')));
+        }
+    }
+
+    let errors = errors.into_iter().map(|e| e.in_file(filename)).collect();
+    (syntax::block_to_ast(syntax::Block::Block(statements)), errors)
+}
+
+fn skip_spaces(input : &mut Input) {
+    Spaces::new().parse(input).unwrap_or(());
+}
+
+// optional parts of the grammar swallow their errors, so the failure that got
+// furthest into the statement explains it better than the one returned
+fn furthest_error(furthest : &RefCell<Option<ParseError>>, e : ParseError) -> ParseError {
+    furthest.take().map_or(e.clone(), |f| f.merge(e))
+}
+
+// moves the input to where the error was found
+fn skip_to(input : &mut Input, e : &ParseError) {
+    while (input.position().line, input.position().column) < (e.line, e.char) && input.next_char().is_some() {}
+}
+
+// Operators that can follow the 'end' of an expression, longest first
+const OPERATORS : [&str; 14] = ["++", "::", "==", "!=", "<=", ">=", "&&", "||", "*", "/", "+", "-", "<", ">"];
+
+// Skips the rest of a broken statement, from where it broke. Words are skipped whole,
+// and an 'end' closes the 'if' or 'match' the error was in: an operator after it is
+// parsed again, so that errors in the rest of the statement are reported too.
+// Returns true if a ';' was found and another statement follows.
+fn synchronize(input : &mut Input, furthest : &RefCell<Option<ParseError>>, errors : &mut Vec<ParseError>) -> bool {
+    loop {
+        skip_spaces(input);
+        match input.peek() {
+            Some(';') => {
+                input.next_char();
+                return true;
+            },
+            Some('}') | None => return false,
+            Some(c) if is_name_char(c) => {
+                let word = input.rest().chars().take_while(|c| is_name_char(*c)).count();
+                let end = input.rest().starts_with("end") && word == 3;
+                for _ in 0..word {
+                    input.next_char();
+                }
+                skip_spaces(input);
+                if end && OPERATORS.iter().any(|op| input.consume(op)) {
+                    furthest.replace(None);
+                    if let Err(e) = Expression::new().parse(input) {
+                        let e = furthest_error(furthest, e);
+                        skip_to(input, &e);
+                        errors.push(e);
+                    }
+                }
+            },
+            Some(_) => {
+                input.next_char();
+            },
+        }
+    }
+}
+
+// Returns the number of statements of a script of that many lines, and the characters
+// read to parse it for each character of the script
+#[cfg(test)]
+fn parse_lines(lines : usize) -> (usize, f64) {
+    let mut source = "{\n".to_string();
+    for i in 0..lines {
+        if i % 100 == 0 {
+            source.push_str("f = |n| if n then (n - 1) * 2 else f (n + 1) end;\n");
+        }
+        else {
+            source.push_str(&format!("x = (x + {}) * y;\n", i));
+        }
+    }
+    source.push_str("f x\n}");
+
+    let reads = std::cell::Cell::new(0);
+    match Block::new().parse(&mut Input::new(&source).counting_reads(&reads)) {
+        Ok(syntax::Block::Block(statements)) => (statements.len(), reads.get() as f64 / source.len() as f64),
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn large_script_test() {
+    // each character is read a bounded number of times, however long the script is
+    let (statements, reads) = parse_lines(10000);
+    assert_eq!(statements, 10001);
+    let (_, short_reads) = parse_lines(100);
+    assert!(reads < 1.1 * short_reads, "10k lines took {} reads per character and 100 lines {}", reads, short_reads);
+}
+
+// A block that is a number in that many parentheses
+#[cfg(test)]
+fn nested_parens(depth : usize) -> String {
+    "{ ".to_string() + &"(".repeat(depth) + "1" + &")".repeat(depth) + " }"
+}
+
+#[test]
+fn nested_parens_test() {
+    // every character is read once, however deep the parentheses go
+    for depth in [1, 100].iter() {
+        let source = nested_parens(*depth);
+        let reads = std::cell::Cell::new(0);
+        assert!(Block::new().parse(&mut Input::new(&source).counting_reads(&reads)).is_ok());
+        assert_eq!(reads.get(), source.len());
+    }
+}
+
+#[cfg(test)]
+use std::rc::Rc;
+
+#[test]
+fn memo_test() {
+    // a term parsed again from the same place is replayed from the table, not read again
+    let table = MemoTable::new();
+    let reads = std::cell::Cell::new(0);
+    let input = Input::with_memo("(f 1) x", &table).counting_reads(&reads);
+    let term = Memo::new("term", Term::new());
+    let (mut first, mut second) = (input, input);
+    let parsed = term.parse(&mut first).unwrap();
+    assert_eq!(reads.get(), 5);
+    let replayed = term.parse(&mut second).unwrap();
+    assert_eq!(reads.get(), 5);
+    assert!(Rc::ptr_eq(&parsed, &replayed));
+    assert_eq!(second.rest(), " x");
+
+    // without a table the term is parsed again
+    let mut third = Input::new("(f 1) x").counting_reads(&reads);
+    assert!(!Rc::ptr_eq(&parsed, &term.parse(&mut third).unwrap()));
+    assert_eq!(reads.get(), 10);
+}
+
+#[test]
+fn recovery_test() {
+    let source = "{\n  x = 1 + ;\n  y = if x then 2 els 3 end;\n  z = 4\n  w = 5;\n  v = |a| a * 2;\n  v z\n}";
+    let (ast, errors) = parse_source_recovering("typos.sm", source);
+    let syntax::BlockAst::Block(statements) = ast;
+    assert_eq!(statements.len(), 2);
+    assert_eq!(errors.len(), 3);
+
+    let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(messages[0], "typos.sm:2:11 expected '!', '-', 'if', 'let', 'match', '|', '(', a digit, '\"', '[', a constructor or a name but got ';'");
+    assert_eq!(messages[1], "typos.sm:3:25 expected 'if', 'let', 'match', '|', '(', a digit, '\"', '[', a constructor, a name, '*', '/', '++', '+', '-', '::', '==', '!=', '<=', '>=', '<', '>', '&&', '||' or 'else' but got 'end'");
+    assert_eq!(messages[2], "typos.sm:5:5 expected 'if', 'let', 'match', '|', '(', a digit, '\"', '[', a constructor, a name, '*', '/', '++', '+', '-', '::', '==', '!=', '<=', '>=', '<', '>', '&&', '||', ';' or '}' but got '='");
+    match &statements[0] {
+        syntax::StatementAst::Assign(name, _, _) => assert_eq!(name, "v"),
+        s => panic!("expected the assignment to v but got {:?}", s),
+    }
+}
+
+#[test]
+fn recovery_at_end_test() {
+    // the statement goes on after the 'end' of the broken 'if', and its next error is reported too
+    let source = "{ x = if y then 1 els 2 end + (3 +); z = 4 }";
+    let (ast, errors) = parse_source_recovering("end.sm", source);
+    let syntax::BlockAst::Block(statements) = ast;
+    assert_eq!(statements.len(), 1);
+    let messages : Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0], "end.sm:1:25 expected 'if', 'let', 'match', '|', '(', a digit, '\"', '[', a constructor, a name, '*', '/', '++', '+', '-', '::', '==', '!=', '<=', '>=', '<', '>', '&&', '||' or 'else' but got 'end'");
+    assert_eq!(messages[1], "end.sm:1:35 expected '!', '-', 'if', 'let', 'match', '|', '(', a digit, '\"', '[', a constructor or a name but got ')'");
+}
+
+#[test]
+fn recovery_without_errors_test() {
+    let source = "{ f = |n| n * 2; f 21 }";
+    let (ast, errors) = parse_source_recovering("ok.sm", source);
+    assert!(errors.is_empty());
+    let expected = syntax::block_to_ast(parse_source("ok.sm", source).unwrap());
+    assert_eq!(format!("{:?}", ast), format!("{:?}", expected));
+
+    let (_, errors) = parse_source_recovering("open.sm", "{ x = 1; y = ");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[1].to_string(), "open.sm:1:14 expected '}' but got EOF");
+}
+
+#[test]
+fn literal_range_test() {
+    assert!(parse_source("test.sm", "-2147483648").is_ok());
+    let (_, errors) = parse_source_recovering("test.sm", "{ x = 2147483648 }");
+    assert_eq!(errors[0].to_string(), "test.sm:1:7 expected '!', '-', 'if', 'let', 'match', '|', '(', a number from -2147483648 to 2147483647, '\"', '[', a constructor or a name but got '2147483648'");
+}
+
+#[test]
+fn identifier_test() {
+    let source = "{ fib2 = 1; is_even = 2; camelCase = 3; x' = 4; _tmp = 5; iffy = 6; fib2 + is_even + camelCase + x' + _tmp + iffy }";
+    let ast = syntax::block_to_ast(parse_source("names.sm", source).unwrap());
+    let syntax::BlockAst::Block(statements) = ast;
+    let names : Vec<String> = statements.iter().filter_map(|s| match s {
+        syntax::StatementAst::Assign(name, _, _) => Some(name.clone()),
+        _ => None,
+    }).collect();
+    assert_eq!(names, ["fib2", "is_even", "camelCase", "x'", "_tmp", "iffy"]);
+
+    // keywords cannot be bound, wherever the binding is
+    for keyword in KEYWORDS {
+        for source in &[format!("{} = 3", keyword), format!("|{}| 1", keyword), format!("|x, {}| 1", keyword), format!("let {} = 1 in 2", keyword)] {
+            let (_, errors) = parse_source_recovering("keywords.sm", source);
+            assert!(!errors.is_empty(), "{}", source);
+        }
+    }
+    let (_, errors) = parse_source_recovering("keywords.sm", "{ y = 1; then = 3 }");
+    assert_eq!(errors[0].to_string(), "keywords.sm:1:10 expected 'type', a name, '!', '-', 'if', 'let', 'match', '|', '(', a digit, '\"', '[' or a constructor but got 'then'");
+}
+
+#[test]
+fn comment_test() {
+    let source = "// sums the numbers up to n\n{\n  sum = |n| /* base case */ if n then sum (n - 1) + n else 0 end; // recursive\n  /* sum 3; /* nested */ */\n  sum 10 / 2 // halved\n}\n";
+    let ast = syntax::block_to_ast(parse_source("comments.sm", source).unwrap());
+    let syntax::BlockAst::Block(statements) = ast;
+    assert_eq!(statements.len(), 2);
+
+    let (_, errors) = parse_source_recovering("comments.sm", "{ x = 1; /* unterminated }");
+    assert_eq!(errors[0].to_string(), "comments.sm:1:27 expected '*/' but got EOF");
+}
+
+#[test]
+fn match_test() {
+    let source = "{ type Shape = | Circle r | Rect w h | Dot; match s with | Rect 1 (-2) -> 0 | Circle _ -> \"c\" | x -> x end }";
+    let ast = syntax::block_to_ast(parse_source("match.sm", source).unwrap());
+    let syntax::BlockAst::Block(statements) = ast;
+    match &statements[0] {
+        syntax::StatementAst::Type(name, constructors, _) => {
+            assert_eq!(name, "Shape");
+            let shapes : Vec<(&str, usize, usize)> = constructors.iter().map(|c| (c.name.as_str(), c.arity(), c.tag)).collect();
+            assert_eq!(shapes, [("Circle", 1, 0), ("Rect", 2, 1), ("Dot", 0, 2)]);
+            assert!(constructors.iter().all(|c| c.siblings == 3 && c.type_name == "Shape"));
+        },
+        s => panic!("expected a type declaration but got {:?}", s),
+    }
+    match &statements[1] {
+        syntax::StatementAst::Exp(exp, _) => match &**exp {
+            syntax::ExpAst::Match(_, arms, _) => {
+                let patterns : Vec<String> = arms.iter().map(|(pattern, _)| format!("{:?}", pattern)).collect();
+                assert!(patterns[0].starts_with("Constructor(\"Rect\", [Num(1, "), "{}", patterns[0]);
+                assert!(patterns[0].contains("Num(-2, "), "{}", patterns[0]);
+                assert!(patterns[1].starts_with("Constructor(\"Circle\", [Wildcard("), "{}", patterns[1]);
+                assert!(patterns[2].starts_with("Var(\"x\", "), "{}", patterns[2]);
+            },
+            e => panic!("expected a match but got {:?}", e),
+        },
+        s => panic!("expected an expression but got {:?}", s),
+    }
+
+    // arms need a leading '|', types and constructors are capitalized, and match is a keyword
+    for source in &["match x with Some y -> y end", "match x with end", "type option = None", "type Option = none", "match = 1"] {
+        assert!(parse_source("match.sm", source).is_err(), "{}", source);
+    }
+    let (_, errors) = parse_source_recovering("match.sm", "match x with | Some + -> 1 end");
+    assert_eq!(errors[0].to_string(), "match.sm:1:21 expected '(', '-', a digit, '\"', a constructor, a name or '->' but got '+'");
+}
@@ -1,75 +1,270 @@
-#[derive(Debug)]
+use std::fmt;
+use std::rc::Rc;
+use parser::combinator::Position;
+
+// Range of the source text a node was parsed from
+#[derive(Copy, Clone, PartialEq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+impl Span {
+    pub fn new(start : Position, end : Position) -> Span {
+        Span{start, end}
+    }
+
+    // from the start of this span to the end of the other
+    pub fn to(&self, other : Span) -> Span {
+        Span{start: self.start, end: other.end}
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}-{:?}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Term {
-    Num(i32),
-    Var(String),
-    Function(String, Box<Exp>),
+    Num(i32, Span),
+    Str(String, Span),
+    List(Vec<Exp>, Span),
+    Var(String, Span),
+    Constructor(String, Span),
+    Function(Vec<String>, Box<Exp>, Span),
     Paren(Box<Exp>),
-    If(Box<Exp>, Box<Exp>, Box<Exp>),
+    If(Box<Exp>, Box<Exp>, Box<Exp>, Span),
+    Let(String, Box<Exp>, Box<Exp>, Span),
+    LetRec(String, Box<Term>, Box<Exp>, Span), // the value is always a Function
+    Match(Box<Exp>, Vec<(Pattern, Exp)>, Span),
+}
+
+// Patterns are the same in the syntax tree and the AST
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Wildcard(Span),
+    Var(String, Span),
+    Num(i32, Span),
+    Str(Rc<str>, Span),
+    Constructor(String, Vec<Pattern>, Span),
 }
+impl Pattern {
+    pub fn span(&self) -> Span {
+        match self {
+            Pattern::Wildcard(span) | Pattern::Var(_, span) | Pattern::Num(_, span) | Pattern::Str(_, span) => *span,
+            Pattern::Constructor(_, _, span) => *span,
+        }
+    }
 
-#[derive(Debug)]
+    // names bound by the pattern, from left to right
+    pub fn variables(&self, names : &mut Vec<String>) {
+        match self {
+            Pattern::Var(name, _) => names.push(name.clone()),
+            Pattern::Constructor(_, patterns, _) => {
+                for pattern in patterns {
+                    pattern.variables(names);
+                }
+            },
+            Pattern::Wildcard(_) | Pattern::Num(_, _) | Pattern::Str(_, _) => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Exp5 {
     App(Box<Term>, Box<Exp5>),
     Empty,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Exp4 {
     Exp4(Box<Term>, Box<Exp5>),
+    Not(Box<Exp4>, Span),
+    Neg(Box<Exp4>, Span),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Exp3 {
     Mul(Box<Exp4>, Box<Exp3>),
     Div(Box<Exp4>, Box<Exp3>),
     Empty,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Exp2 {
     Exp2(Box<Exp4>, Box<Exp3>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Exp1 {
     Add(Box<Exp2>, Box<Exp1>),
     Sub(Box<Exp2>, Box<Exp1>),
+    Concat(Box<Exp2>, Box<Exp1>),
     Empty,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub enum ArithExp {
+    ArithExp(Box<Exp2>, Box<Exp1>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CmpOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+// :: is right associative, so 1 :: 2 :: [] is 1 :: (2 :: [])
+#[derive(Debug, Clone)]
+pub enum ConsRest {
+    Cons(Box<ConsExp>),
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConsExp {
+    ConsExp(Box<ArithExp>, Box<ConsRest>),
+}
+
+// comparisons do not chain, so there is at most one operator
+#[derive(Debug, Clone)]
+pub enum CmpRest {
+    Cmp(CmpOp, Box<ConsExp>),
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub enum CmpExp {
+    CmpExp(Box<ConsExp>, Box<CmpRest>),
+}
+
+#[derive(Debug, Clone)]
+pub enum AndRest {
+    And(Box<CmpExp>, Box<AndRest>),
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub enum AndExp {
+    AndExp(Box<CmpExp>, Box<AndRest>),
+}
+
+#[derive(Debug, Clone)]
+pub enum OrRest {
+    Or(Box<AndExp>, Box<OrRest>),
+    Empty,
+}
+
+#[derive(Debug, Clone)]
 pub enum Exp {
-    Exp(Box<Exp2>, Box<Exp1>),
+    Exp(Box<AndExp>, Box<OrRest>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Statement {
     ExpressionStatement(Box<Exp>),
-    AssignmentStatement(String, Box<Exp>),
+    AssignmentStatement(String, Box<Exp>, Span),
+    TypeStatement(String, Vec<(String, Vec<TypeExp>, Span)>, Span), // type name and its constructors with their fields
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Block {
     Block(Vec<Statement>),
 }
 
 #[derive(Debug, Clone)]
 pub enum ExpAst {
-    Add(Box<ExpAst>, Box<ExpAst>),
-    Sub(Box<ExpAst>, Box<ExpAst>),
-    Mul(Box<ExpAst>, Box<ExpAst>),
-    Div(Box<ExpAst>, Box<ExpAst>),
-    App(Box<ExpAst>, Box<ExpAst>),
-    Var(String),
-    Num(i32),
-    Fun(String, Box<ExpAst>),
-    If(Box<ExpAst>, Box<ExpAst>, Box<ExpAst>),
+    Add(Box<ExpAst>, Box<ExpAst>, Span),
+    Sub(Box<ExpAst>, Box<ExpAst>, Span),
+    Mul(Box<ExpAst>, Box<ExpAst>, Span),
+    Div(Box<ExpAst>, Box<ExpAst>, Span),
+    App(Box<ExpAst>, Box<ExpAst>, Span),
+    Var(String, Span),
+    Num(i32, Span),
+    Str(Rc<str>, Span),
+    Concat(Box<ExpAst>, Box<ExpAst>, Span),
+    Nil(Span),
+    Cons(Box<ExpAst>, Box<ExpAst>, Span), // the tail has to be a list
+    Constructor(String, Span),
+    Fun(String, Rc<ExpAst>, Span), // the body is shared with the closures made from it
+    If(Box<ExpAst>, Box<ExpAst>, Box<ExpAst>, Span),
+    Compare(CmpOp, Box<ExpAst>, Box<ExpAst>, Span),
+    And(Box<ExpAst>, Box<ExpAst>, Span), // the right operand is only evaluated if the left one is true
+    Or(Box<ExpAst>, Box<ExpAst>, Span),  // the right operand is only evaluated if the left one is false
+    Not(Box<ExpAst>, Span),
+    Neg(Box<ExpAst>, Span),
+    Let(String, Box<ExpAst>, Box<ExpAst>, Span),
+    LetRec(String, Box<ExpAst>, Box<ExpAst>, Span), // the value is always a Fun, which can refer to itself
+    Match(Box<ExpAst>, Vec<(Pattern, ExpAst)>, Span), // the first arm whose pattern matches is taken
+}
+impl ExpAst {
+    pub fn span(&self) -> Span {
+        match self {
+            ExpAst::Add(_, _, span) | ExpAst::Sub(_, _, span) | ExpAst::Mul(_, _, span) | ExpAst::Div(_, _, span) => *span,
+            ExpAst::App(_, _, span) => *span,
+            ExpAst::Var(_, span) | ExpAst::Num(_, span) | ExpAst::Str(_, span) => *span,
+            ExpAst::Concat(_, _, span) | ExpAst::Cons(_, _, span) => *span,
+            ExpAst::Nil(span) => *span,
+            ExpAst::Fun(_, _, span) => *span,
+            ExpAst::If(_, _, _, span) => *span,
+            ExpAst::Compare(_, _, _, span) | ExpAst::And(_, _, span) | ExpAst::Or(_, _, span) => *span,
+            ExpAst::Not(_, span) | ExpAst::Neg(_, span) => *span,
+            ExpAst::Let(_, _, _, span) | ExpAst::LetRec(_, _, _, span) => *span,
+            ExpAst::Constructor(_, span) | ExpAst::Match(_, _, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum StatementAst {
-    Exp(Box<ExpAst>),
-    Assign(String, Box<ExpAst>),
+    Exp(Box<ExpAst>, Span),
+    Assign(String, Box<ExpAst>, Span),
+    Type(String, Vec<Constructor>, Span),
+}
+
+// The type of a field, written the way types are shown: a, Int, Option a, [a] or a -> b.
+// The type variables of its fields are the parameters of the declared type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExp {
+    Var(String, Span),
+    Name(String, Vec<TypeExp>, Span), // a named type applied to its parameters
+    List(Box<TypeExp>, Span),
+    Fun(Box<TypeExp>, Box<TypeExp>, Span),
+}
+impl TypeExp {
+    pub fn span(&self) -> Span {
+        match self {
+            TypeExp::Var(_, span) | TypeExp::Name(_, _, span) | TypeExp::List(_, span) | TypeExp::Fun(_, _, span) => *span,
+        }
+    }
+}
+
+// A constructor of a declared type, which builds a value out of its fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constructor {
+    pub name: String,
+    pub fields: Vec<TypeExp>,
+    pub type_name: String,
+    pub tag: usize,      // position in the declaration, which tells the constructors of a type apart
+    pub siblings: usize, // number of constructors of the type
+    pub span: Span,
+}
+impl Constructor {
+    pub fn arity(&self) -> usize {
+        self.fields.len()
+    }
+}
+impl StatementAst {
+    pub fn span(&self) -> Span {
+        match self {
+            StatementAst::Exp(_, span) | StatementAst::Assign(_, _, span) | StatementAst::Type(_, _, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -79,15 +274,33 @@ pub enum BlockAst {
 
 fn term_to_ast(term : Term) -> ExpAst {
     match term {
-        Term::Num(num) => ExpAst::Num(num),
+        Term::Num(num, span) => ExpAst::Num(num, span),
+        Term::Str(text, span) => ExpAst::Str(Rc::from(text), span),
+        Term::List(items, span) => {
+            // [a, b] is a :: b :: []
+            items.into_iter().rev().fold(ExpAst::Nil(span), |tail, item| {
+                ExpAst::Cons(Box::new(exp_to_ast(item)), Box::new(tail), span)
+            })
+        },
         Term::Paren(exp) => exp_to_ast(*exp),
-        Term::Var(name) => ExpAst::Var(name),
-        Term::Function(var, exp) => ExpAst::Fun(var, Box::new(exp_to_ast(*exp))),
-        Term::If(cond, then_exp, else_exp) => {
+        Term::Var(name, span) => ExpAst::Var(name, span),
+        Term::Constructor(name, span) => ExpAst::Constructor(name, span),
+        Term::Function(vars, exp, span) => {
+            // a function of several parameters is curried: |x, y| e is |x| |y| e
+            let body = exp_to_ast(*exp);
+            vars.into_iter().rev().fold(body, |body, var| ExpAst::Fun(var, Rc::new(body), span))
+        },
+        Term::If(cond, then_exp, else_exp, span) => {
             let cond_ast = exp_to_ast(*cond);
             let then_exp_ast = exp_to_ast(*then_exp);
             let else_exp_ast = exp_to_ast(*else_exp);
-            ExpAst::If(Box::new(cond_ast), Box::new(then_exp_ast), Box::new(else_exp_ast))
+            ExpAst::If(Box::new(cond_ast), Box::new(then_exp_ast), Box::new(else_exp_ast), span)
+        },
+        Term::Let(name, value, body, span) => ExpAst::Let(name, Box::new(exp_to_ast(*value)), Box::new(exp_to_ast(*body)), span),
+        Term::LetRec(name, value, body, span) => ExpAst::LetRec(name, Box::new(term_to_ast(*value)), Box::new(exp_to_ast(*body)), span),
+        Term::Match(exp, arms, span) => {
+            let arms = arms.into_iter().map(|(pattern, body)| (pattern, exp_to_ast(body))).collect();
+            ExpAst::Match(Box::new(exp_to_ast(*exp)), arms, span)
         },
     }
 }
@@ -96,7 +309,8 @@ fn exp5_to_ast(exp5 : Exp5, ast : ExpAst) -> ExpAst {
     match exp5 {
         Exp5::App(term, exp5) => {
             let term_ast = term_to_ast(*term);
-            let ast = ExpAst::App(Box::new(ast), Box::new(term_ast));
+            let span = ast.span().to(term_ast.span());
+            let ast = ExpAst::App(Box::new(ast), Box::new(term_ast), span);
             exp5_to_ast(*exp5, ast)
         },
         Exp5::Empty => ast,
@@ -104,21 +318,28 @@ fn exp5_to_ast(exp5 : Exp5, ast : ExpAst) -> ExpAst {
 }
 
 fn exp4_to_ast(exp4 : Exp4) -> ExpAst {
-    let Exp4::Exp4(term, exp5) = exp4;
-    let term_ast = term_to_ast(*term);
-    exp5_to_ast(*exp5, term_ast)
+    match exp4 {
+        Exp4::Exp4(term, exp5) => {
+            let term_ast = term_to_ast(*term);
+            exp5_to_ast(*exp5, term_ast)
+        },
+        Exp4::Not(exp4, span) => ExpAst::Not(Box::new(exp4_to_ast(*exp4)), span),
+        Exp4::Neg(exp4, span) => ExpAst::Neg(Box::new(exp4_to_ast(*exp4)), span),
+    }
 }
 
 fn exp3_to_ast(exp3 : Exp3, ast : ExpAst) -> ExpAst {
     match exp3 {
         Exp3::Mul(exp4, exp3) => {
             let term_ast = exp4_to_ast(*exp4);
-            let ast = ExpAst::Mul(Box::new(ast), Box::new(term_ast));
+            let span = ast.span().to(term_ast.span());
+            let ast = ExpAst::Mul(Box::new(ast), Box::new(term_ast), span);
             exp3_to_ast(*exp3, ast)
         },
         Exp3::Div(exp4, exp3) => {
             let term_ast = exp4_to_ast(*exp4);
-            let ast = ExpAst::Div(Box::new(ast), Box::new(term_ast));
+            let span = ast.span().to(term_ast.span());
+            let ast = ExpAst::Div(Box::new(ast), Box::new(term_ast), span);
             exp3_to_ast(*exp3, ast)
         },
         Exp3::Empty => ast,
@@ -135,28 +356,109 @@ fn exp1_to_ast(exp1 : Exp1, ast : ExpAst) -> ExpAst {
     match exp1 {
         Exp1::Add(exp2, exp1) => {
             let exp2_ast = exp2_to_ast(*exp2);
-            let ast = ExpAst::Add(Box::new(ast), Box::new(exp2_ast));
+            let span = ast.span().to(exp2_ast.span());
+            let ast = ExpAst::Add(Box::new(ast), Box::new(exp2_ast), span);
             exp1_to_ast(*exp1, ast)
         },
         Exp1::Sub(exp2, exp1) => {
             let exp2_ast = exp2_to_ast(*exp2);
-            let ast = ExpAst::Sub(Box::new(ast), Box::new(exp2_ast));
+            let span = ast.span().to(exp2_ast.span());
+            let ast = ExpAst::Sub(Box::new(ast), Box::new(exp2_ast), span);
+            exp1_to_ast(*exp1, ast)
+        },
+        Exp1::Concat(exp2, exp1) => {
+            let exp2_ast = exp2_to_ast(*exp2);
+            let span = ast.span().to(exp2_ast.span());
+            let ast = ExpAst::Concat(Box::new(ast), Box::new(exp2_ast), span);
             exp1_to_ast(*exp1, ast)
         },
         Exp1::Empty => ast,
     }
 }
 
-pub fn exp_to_ast(exp : Exp) -> ExpAst {
-    let Exp::Exp(exp2, exp1) = exp;
+fn arith_exp_to_ast(exp : ArithExp) -> ExpAst {
+    let ArithExp::ArithExp(exp2, exp1) = exp;
     let exp2_ast = exp2_to_ast(*exp2);
     exp1_to_ast(*exp1, exp2_ast)
 }
 
+fn cons_exp_to_ast(exp : ConsExp) -> ExpAst {
+    let ConsExp::ConsExp(head, rest) = exp;
+    let head_ast = arith_exp_to_ast(*head);
+    match *rest {
+        ConsRest::Cons(tail) => {
+            let tail_ast = cons_exp_to_ast(*tail);
+            let span = head_ast.span().to(tail_ast.span());
+            ExpAst::Cons(Box::new(head_ast), Box::new(tail_ast), span)
+        },
+        ConsRest::Empty => head_ast,
+    }
+}
+
+fn cmp_exp_to_ast(exp : CmpExp) -> ExpAst {
+    let CmpExp::CmpExp(left, rest) = exp;
+    let left_ast = cons_exp_to_ast(*left);
+    match *rest {
+        CmpRest::Cmp(op, right) => {
+            let right_ast = cons_exp_to_ast(*right);
+            let span = left_ast.span().to(right_ast.span());
+            ExpAst::Compare(op, Box::new(left_ast), Box::new(right_ast), span)
+        },
+        CmpRest::Empty => left_ast,
+    }
+}
+
+fn and_rest_to_ast(rest : AndRest, ast : ExpAst) -> ExpAst {
+    match rest {
+        AndRest::And(exp, rest) => {
+            let exp_ast = cmp_exp_to_ast(*exp);
+            let span = ast.span().to(exp_ast.span());
+            let ast = ExpAst::And(Box::new(ast), Box::new(exp_ast), span);
+            and_rest_to_ast(*rest, ast)
+        },
+        AndRest::Empty => ast,
+    }
+}
+
+fn and_exp_to_ast(exp : AndExp) -> ExpAst {
+    let AndExp::AndExp(exp, rest) = exp;
+    let exp_ast = cmp_exp_to_ast(*exp);
+    and_rest_to_ast(*rest, exp_ast)
+}
+
+fn or_rest_to_ast(rest : OrRest, ast : ExpAst) -> ExpAst {
+    match rest {
+        OrRest::Or(exp, rest) => {
+            let exp_ast = and_exp_to_ast(*exp);
+            let span = ast.span().to(exp_ast.span());
+            let ast = ExpAst::Or(Box::new(ast), Box::new(exp_ast), span);
+            or_rest_to_ast(*rest, ast)
+        },
+        OrRest::Empty => ast,
+    }
+}
+
+pub fn exp_to_ast(exp : Exp) -> ExpAst {
+    let Exp::Exp(exp, rest) = exp;
+    let exp_ast = and_exp_to_ast(*exp);
+    or_rest_to_ast(*rest, exp_ast)
+}
+
 pub fn statement_to_ast(statement : Statement) -> StatementAst {
     match statement {
-        Statement::ExpressionStatement(exp) => StatementAst::Exp(Box::new(exp_to_ast(*exp))),
-        Statement::AssignmentStatement(name, exp) => StatementAst::Assign(name, Box::new(exp_to_ast(*exp))),
+        Statement::ExpressionStatement(exp) => {
+            let exp_ast = exp_to_ast(*exp);
+            let span = exp_ast.span();
+            StatementAst::Exp(Box::new(exp_ast), span)
+        },
+        Statement::AssignmentStatement(name, exp, span) => StatementAst::Assign(name, Box::new(exp_to_ast(*exp)), span),
+        Statement::TypeStatement(type_name, constructors, span) => {
+            let siblings = constructors.len();
+            let constructors = constructors.into_iter().enumerate().map(|(tag, (name, fields, span))| {
+                Constructor{name, fields, type_name: type_name.clone(), tag, siblings, span}
+            }).collect();
+            StatementAst::Type(type_name, constructors, span)
+        },
     }
 }
 
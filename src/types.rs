@@ -0,0 +1,755 @@
+// Hindley-Milner type inference, run before a program is evaluated so that a function
+// applied to the wrong kind of value is reported without running it. Every expression
+// gets its most general type, and a name bound by let or by an assignment can be used
+// at any instance of its type (Damas and Milner, "Principal type-schemes for functional
+// programs"). Types are unified in place through a substitution of the type variables.
+// The one restriction on top of that is equality: == and != take two numbers or two
+// strings, and operands whose type is still unknown are taken to be numbers, so that
+// |a, b| a == b is Int -> Int -> Int and comparing lists is reported as a mismatch with Int.
+
+use std::collections::HashMap;
+use std::fmt;
+use builtins::Builtin;
+use parser::syntax::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Str,
+    List(Box<Type>),
+    Fun(Box<Type>, Box<Type>),
+    Adt(String, Vec<Type>), // a declared type applied to its parameters
+    Var(usize),
+}
+
+impl Type {
+    fn fun(param : Type, result : Type) -> Type {
+        Type::Fun(Box::new(param), Box::new(result))
+    }
+
+    fn list(element : Type) -> Type {
+        Type::List(Box::new(element))
+    }
+
+    // Variables are named a, b, c... in the order they appear, which `names` keeps
+    // so that two types shown together name the same variable the same way
+    fn show(&self, names : &mut Vec<usize>, f : &mut fmt::Formatter, parens : Parens) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "Int"),
+            Type::Str => write!(f, "String"),
+            Type::List(element) => {
+                write!(f, "[")?;
+                element.show(names, f, Parens::None)?;
+                write!(f, "]")
+            },
+            Type::Fun(param, result) => {
+                if parens != Parens::None {
+                    write!(f, "(")?;
+                }
+                param.show(names, f, Parens::Fun)?;
+                write!(f, " -> ")?;
+                result.show(names, f, Parens::None)?;
+                if parens != Parens::None {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            },
+            Type::Adt(name, params) if params.is_empty() => write!(f, "{}", name),
+            Type::Adt(name, params) => {
+                if parens == Parens::All {
+                    write!(f, "(")?;
+                }
+                write!(f, "{}", name)?;
+                for param in params {
+                    write!(f, " ")?;
+                    param.show(names, f, Parens::All)?;
+                }
+                if parens == Parens::All {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            },
+            Type::Var(var) => {
+                let n = match names.iter().position(|v| v == var) {
+                    Some(n) => n,
+                    None => {
+                        names.push(*var);
+                        names.len() - 1
+                    },
+                };
+                write!(f, "{}", (b'a' + (n % 26) as u8) as char)?;
+                if n >= 26 {
+                    write!(f, "{}", n / 26)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn free_variables(&self, vars : &mut Vec<usize>) {
+        match self {
+            Type::Int | Type::Str => (),
+            Type::List(element) => element.free_variables(vars),
+            Type::Fun(param, result) => {
+                param.free_variables(vars);
+                result.free_variables(vars);
+            },
+            Type::Adt(_, params) => {
+                for param in params {
+                    param.free_variables(vars);
+                }
+            },
+            Type::Var(var) => {
+                if !vars.contains(var) {
+                    vars.push(*var);
+                }
+            },
+        }
+    }
+
+    fn substitute(&self, vars : &HashMap<usize, Type>) -> Type {
+        match self {
+            Type::Int | Type::Str => self.clone(),
+            Type::List(element) => Type::list(element.substitute(vars)),
+            Type::Fun(param, result) => Type::fun(param.substitute(vars), result.substitute(vars)),
+            Type::Adt(name, params) => Type::Adt(name.clone(), params.iter().map(|param| param.substitute(vars)).collect()),
+            Type::Var(var) => vars.get(var).cloned().unwrap_or_else(|| self.clone()),
+        }
+    }
+}
+
+// Where a type is shown: function types need parentheses as the parameter of a function,
+// and applied types too as the parameter of another type
+#[derive(Clone, Copy, PartialEq)]
+enum Parens {
+    None,
+    Fun,
+    All,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.show(&mut vec![], f, Parens::None)
+    }
+}
+
+// A type that holds for any type in place of its variables, like a -> a for |x| x
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheme {
+    pub vars: Vec<usize>,
+    pub ty: Type,
+}
+
+impl Scheme {
+    fn mono(ty : Type) -> Scheme {
+        Scheme{vars: vec![], ty}
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeErrorKind {
+    Mismatch(Box<Type>, Box<Type>),     // the type that was expected and the one found
+    InfiniteType(Box<Type>, Box<Type>), // a variable that would have to contain itself
+    UnboundVariable(String),
+    UnknownConstructor(String),
+    ConstructorArity(String, usize, usize), // constructor, number of fields it has and number the pattern gives
+    UnknownType(String),
+    TypeArity(String, usize, usize), // type, number of parameters it has and number it is given
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    pub kind: TypeErrorKind,
+    pub span: Span,
+}
+
+impl TypeError {
+    fn new(kind : TypeErrorKind, span : Span) -> TypeError {
+        TypeError{kind, span}
+    }
+}
+
+// Shows two types with their variables named together
+struct Pair<'a>(&'a str, &'a Type, &'a str, &'a Type);
+
+impl<'a> fmt::Display for Pair<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = vec![];
+        write!(f, "{}", self.0)?;
+        self.1.show(&mut names, f, Parens::None)?;
+        write!(f, "{}", self.2)?;
+        self.3.show(&mut names, f, Parens::None)
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            TypeErrorKind::Mismatch(expected, got) => write!(f, "{}", Pair("expected ", expected, " but got ", got))?,
+            TypeErrorKind::InfiniteType(var, ty) => write!(f, "{}", Pair("cannot build the infinite type ", var, " = ", ty))?,
+            TypeErrorKind::UnboundVariable(name) => write!(f, "unbound variable '{}'", name)?,
+            TypeErrorKind::UnknownConstructor(name) => write!(f, "unknown constructor '{}'", name)?,
+            TypeErrorKind::ConstructorArity(name, arity, count) => {
+                write!(f, "constructor '{}' has {} fields but the pattern gives {}", name, arity, count)?
+            },
+            TypeErrorKind::UnknownType(name) => write!(f, "unknown type '{}'", name)?,
+            TypeErrorKind::TypeArity(name, arity, count) => {
+                write!(f, "type '{}' has {} parameters but is given {}", name, arity, count)?
+            },
+        }
+        write!(f, " at {:?}", self.span)
+    }
+}
+
+fn builtin_scheme(builtin : Builtin) -> Scheme {
+    let a = Type::Var(0);
+    match builtin {
+        Builtin::Length => Scheme::mono(Type::fun(Type::Str, Type::Int)),
+        Builtin::Substring => Scheme::mono(Type::fun(Type::Str, Type::fun(Type::Int, Type::fun(Type::Int, Type::Str)))),
+        Builtin::Head => Scheme{vars: vec![0], ty: Type::fun(Type::list(a.clone()), a)},
+        Builtin::Tail => Scheme{vars: vec![0], ty: Type::fun(Type::list(a.clone()), Type::list(a))},
+        Builtin::IsEmpty => Scheme{vars: vec![0], ty: Type::fun(Type::list(a), Type::Int)},
+    }
+}
+
+// Why two types do not unify, before it is reported against the whole types
+enum Failure {
+    Mismatch,
+    Occurs(usize, Type),
+}
+
+// Keeps the types of the globals and constructors declared so far, so that an
+// interactive session can check each line against the ones before it
+#[derive(Default)]
+pub struct TypeChecker {
+    globals: HashMap<String, Scheme>,
+    constructors: HashMap<String, (usize, Scheme)>, // arity and type of each constructor as a function of its fields
+    types: HashMap<String, usize>,                 // number of parameters of each declared type
+    substitution: Vec<Option<Type>>,              // what each type variable stands for, if it is known yet
+}
+
+impl TypeChecker {
+    pub fn new() -> TypeChecker {
+        TypeChecker::default()
+    }
+
+    // Returns the type of every statement, with the name it binds, or - for an expression.
+    // Nothing is kept of a block that does not type check.
+    pub fn check_block(&mut self, ast : &BlockAst) -> Result<Vec<(String, Type)>, TypeError> {
+        let saved = (self.globals.clone(), self.constructors.clone(), self.types.clone());
+        let result = self.infer_block(ast);
+        if result.is_err() {
+            self.globals = saved.0;
+            self.constructors = saved.1;
+            self.types = saved.2;
+        }
+        self.substitution.clear();
+        result
+    }
+
+    fn infer_block(&mut self, ast : &BlockAst) -> Result<Vec<(String, Type)>, TypeError> {
+        let BlockAst::Block(statements) = ast;
+        self.substitution.clear();
+
+        // types are declared before anything is checked, and can refer to each other
+        for statement in statements {
+            if let StatementAst::Type(type_name, constructors, _) = statement {
+                let mut params = vec![];
+                for constructor in constructors {
+                    for field in &constructor.fields {
+                        Self::type_variables(field, &mut params);
+                    }
+                }
+                self.types.insert(type_name.clone(), params.len());
+            }
+        }
+        for statement in statements {
+            if let StatementAst::Type(type_name, constructors, _) = statement {
+                self.declare(type_name, constructors)?;
+            }
+        }
+        // a global can be used before its assignment, as it is in a recursive function;
+        // one that an earlier block assigned keeps its type, which other globals rely on
+        let mut new_globals = vec![];
+        for statement in statements {
+            if let StatementAst::Assign(name, _, _) = statement {
+                if !self.globals.contains_key(name) {
+                    let placeholder = self.fresh();
+                    self.globals.insert(name.clone(), Scheme::mono(placeholder));
+                    new_globals.push(name);
+                }
+            }
+        }
+
+        let mut types = vec![];
+        for statement in statements {
+            match statement {
+                StatementAst::Exp(exp, _) => {
+                    let ty = self.infer(exp, &mut vec![])?;
+                    types.push(("-".to_string(), ty));
+                },
+                StatementAst::Assign(name, exp, _) => {
+                    let ty = self.infer(exp, &mut vec![])?;
+                    let previous = self.globals.remove(name).expect("globals assigned in a block are declared first");
+                    self.check_as_general(&previous, &ty, exp.span())?;
+                    let scheme = if previous.vars.is_empty() && new_globals.contains(&name) { self.generalize(&ty, &[]) } else { previous };
+                    self.globals.insert(name.clone(), scheme);
+                    types.push((name.clone(), ty));
+                },
+                StatementAst::Type(_, constructors, _) => {
+                    for constructor in constructors {
+                        let scheme = self.constructors[&constructor.name].1.clone();
+                        let ty = self.instantiate(&scheme);
+                        types.push((constructor.name.clone(), ty));
+                    }
+                },
+            }
+        }
+
+        // once the block is done, the types of its globals are known as well as they can be
+        for name in new_globals {
+            let ty = self.resolve(&self.globals[name].ty);
+            let mut vars = vec![];
+            ty.free_variables(&mut vars);
+            self.globals.insert(name.clone(), Scheme{vars, ty});
+        }
+        Ok(types.into_iter().map(|(name, ty)| (name, self.resolve(&ty))).collect())
+    }
+
+    // The value assigned again to a global must have the type the global already has, so
+    // that what was checked against that type still holds. When the type stands for any
+    // type in place of some variables, so must the type of the value.
+    fn check_as_general(&mut self, previous : &Scheme, ty : &Type, span : Span) -> Result<(), TypeError> {
+        let vars : HashMap<usize, Type> = previous.vars.iter().map(|var| (*var, self.fresh())).collect();
+        let expected = previous.ty.substitute(&vars);
+        self.unify(&expected, ty, span)?;
+
+        let mut in_scope = vec![];
+        for scheme in self.globals.values() {
+            self.scheme_variables(&scheme.ty, &scheme.vars, &mut in_scope);
+        }
+        let mut seen = vec![];
+        for var in vars.values() {
+            match self.resolve(var) {
+                Type::Var(var) if !seen.contains(&var) && !in_scope.contains(&var) => seen.push(var),
+                _ => return Err(TypeError::new(TypeErrorKind::Mismatch(Box::new(previous.ty.clone()), Box::new(self.resolve(ty))), span)),
+            }
+        }
+        Ok(())
+    }
+
+    // The type variables of a field are the parameters of its type, in order of appearance
+    fn type_variables(field : &TypeExp, params : &mut Vec<String>) {
+        match field {
+            TypeExp::Var(name, _) => {
+                if !params.contains(name) {
+                    params.push(name.clone());
+                }
+            },
+            TypeExp::Name(_, fields, _) => {
+                for field in fields {
+                    Self::type_variables(field, params);
+                }
+            },
+            TypeExp::List(element, _) => Self::type_variables(element, params),
+            TypeExp::Fun(param, result, _) => {
+                Self::type_variables(param, params);
+                Self::type_variables(result, params);
+            },
+        }
+    }
+
+    // Each constructor is a function from its fields to the declared type
+    fn declare(&mut self, type_name : &str, constructors : &[Constructor]) -> Result<(), TypeError> {
+        let mut names = vec![];
+        for constructor in constructors {
+            for field in &constructor.fields {
+                Self::type_variables(field, &mut names);
+            }
+        }
+        let params : Vec<(String, Type)> = names.into_iter().enumerate().map(|(var, name)| (name, Type::Var(var))).collect();
+        let result = Type::Adt(type_name.to_string(), params.iter().map(|(_, param)| param.clone()).collect());
+        for constructor in constructors {
+            let mut ty = result.clone();
+            for field in constructor.fields.iter().rev() {
+                ty = Type::fun(self.field_type(field, &params)?, ty);
+            }
+            let scheme = Scheme{vars: (0..params.len()).collect(), ty};
+            self.constructors.insert(constructor.name.clone(), (constructor.arity(), scheme));
+        }
+        Ok(())
+    }
+
+    fn field_type(&self, field : &TypeExp, params : &[(String, Type)]) -> Result<Type, TypeError> {
+        match field {
+            TypeExp::Var(name, _) => Ok(params.iter().find(|(param, _)| param == name).expect("every type variable is a parameter").1.clone()),
+            TypeExp::List(element, _) => Ok(Type::list(self.field_type(element, params)?)),
+            TypeExp::Fun(param, result, _) => Ok(Type::fun(self.field_type(param, params)?, self.field_type(result, params)?)),
+            TypeExp::Name(name, fields, span) => {
+                let arity = match name.as_str() {
+                    "Int" | "String" => 0,
+                    _ => *self.types.get(name).ok_or_else(|| TypeError::new(TypeErrorKind::UnknownType(name.clone()), *span))?,
+                };
+                if fields.len() != arity {
+                    return Err(TypeError::new(TypeErrorKind::TypeArity(name.clone(), arity, fields.len()), *span));
+                }
+                match name.as_str() {
+                    "Int" => Ok(Type::Int),
+                    "String" => Ok(Type::Str),
+                    _ => {
+                        let fields = fields.iter().map(|field| self.field_type(field, params)).collect::<Result<_, _>>()?;
+                        Ok(Type::Adt(name.clone(), fields))
+                    },
+                }
+            },
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.substitution.push(None);
+        Type::Var(self.substitution.len() - 1)
+    }
+
+    // the type with every variable that is known replaced by what it stands for
+    fn resolve(&self, ty : &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.substitution[*var] {
+                Some(ty) => self.resolve(ty),
+                None => ty.clone(),
+            },
+            Type::Int | Type::Str => ty.clone(),
+            Type::List(element) => Type::list(self.resolve(element)),
+            Type::Fun(param, result) => Type::fun(self.resolve(param), self.resolve(result)),
+            Type::Adt(name, params) => Type::Adt(name.clone(), params.iter().map(|param| self.resolve(param)).collect()),
+        }
+    }
+
+    fn instantiate(&mut self, scheme : &Scheme) -> Type {
+        let vars : HashMap<usize, Type> = scheme.vars.iter().map(|var| (*var, self.fresh())).collect();
+        self.resolve(&scheme.ty.substitute(&vars))
+    }
+
+    // The variables of the type that nothing in scope refers to can stand for any type
+    fn generalize(&self, ty : &Type, locals : &[(String, Scheme)]) -> Scheme {
+        let mut in_scope = vec![];
+        for scheme in self.globals.values().chain(locals.iter().map(|(_, scheme)| scheme)) {
+            self.scheme_variables(&scheme.ty, &scheme.vars, &mut in_scope);
+        }
+        let ty = self.resolve(ty);
+        let mut vars = vec![];
+        ty.free_variables(&mut vars);
+        vars.retain(|var| !in_scope.contains(var));
+        Scheme{vars, ty}
+    }
+
+    // The variables of a scheme that it does not stand for any type in place of. A scheme
+    // of an earlier block has only variables of its own, which are not in the substitution.
+    fn scheme_variables(&self, ty : &Type, bound : &[usize], vars : &mut Vec<usize>) {
+        match ty {
+            Type::Var(var) if bound.contains(var) => (),
+            Type::Var(var) => match &self.substitution[*var] {
+                Some(ty) => self.scheme_variables(ty, &[], vars),
+                None => vars.push(*var),
+            },
+            Type::Int | Type::Str => (),
+            Type::List(element) => self.scheme_variables(element, bound, vars),
+            Type::Fun(param, result) => {
+                self.scheme_variables(param, bound, vars);
+                self.scheme_variables(result, bound, vars);
+            },
+            Type::Adt(_, params) => {
+                for param in params {
+                    self.scheme_variables(param, bound, vars);
+                }
+            },
+        }
+    }
+
+    // Makes the found type the same as the expected one, or reports both
+    fn unify(&mut self, expected : &Type, got : &Type, span : Span) -> Result<(), TypeError> {
+        self.unify_types(expected, got).map_err(|failure| {
+            let kind = match failure {
+                Failure::Mismatch => TypeErrorKind::Mismatch(Box::new(self.resolve(expected)), Box::new(self.resolve(got))),
+                Failure::Occurs(var, ty) => TypeErrorKind::InfiniteType(Box::new(Type::Var(var)), Box::new(self.resolve(&ty))),
+            };
+            TypeError::new(kind, span)
+        })
+    }
+
+    fn unify_types(&mut self, t1 : &Type, t2 : &Type) -> Result<(), Failure> {
+        match (self.shallow(t1), self.shallow(t2)) {
+            (Type::Var(v1), Type::Var(v2)) if v1 == v2 => Ok(()),
+            (Type::Var(var), ty) | (ty, Type::Var(var)) => {
+                let mut vars = vec![];
+                self.resolve(&ty).free_variables(&mut vars);
+                if vars.contains(&var) {
+                    return Err(Failure::Occurs(var, ty));
+                }
+                self.substitution[var] = Some(ty);
+                Ok(())
+            },
+            (Type::Int, Type::Int) | (Type::Str, Type::Str) => Ok(()),
+            (Type::List(e1), Type::List(e2)) => self.unify_types(&e1, &e2),
+            (Type::Fun(p1, r1), Type::Fun(p2, r2)) => {
+                self.unify_types(&p1, &p2)?;
+                self.unify_types(&r1, &r2)
+            },
+            (Type::Adt(n1, ps1), Type::Adt(n2, ps2)) if n1 == n2 && ps1.len() == ps2.len() => {
+                for (p1, p2) in ps1.iter().zip(ps2.iter()) {
+                    self.unify_types(p1, p2)?;
+                }
+                Ok(())
+            },
+            _ => Err(Failure::Mismatch),
+        }
+    }
+
+    // the type with the variable at its top replaced by what it stands for
+    fn shallow(&self, ty : &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.substitution[*var] {
+                Some(ty) => self.shallow(ty),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    fn lookup(&mut self, name : &str, span : Span, locals : &[(String, Scheme)]) -> Result<Type, TypeError> {
+        // like the engines: locals first, then globals, then builtins
+        let scheme = match locals.iter().rev().find(|(local, _)| local == name) {
+            Some((_, scheme)) => scheme.clone(),
+            None => match self.globals.get(name) {
+                Some(scheme) => scheme.clone(),
+                None => match Builtin::from_name(name) {
+                    Some(builtin) => builtin_scheme(builtin),
+                    None => return Err(TypeError::new(TypeErrorKind::UnboundVariable(name.to_string()), span)),
+                },
+            },
+        };
+        Ok(self.instantiate(&scheme))
+    }
+
+    fn constructor(&mut self, name : &str, span : Span) -> Result<(usize, Type), TypeError> {
+        let (arity, scheme) = self.constructors.get(name).cloned()
+            .ok_or_else(|| TypeError::new(TypeErrorKind::UnknownConstructor(name.to_string()), span))?;
+        Ok((arity, self.instantiate(&scheme)))
+    }
+
+    fn expect(&mut self, expected : &Type, exp : &ExpAst, locals : &mut Vec<(String, Scheme)>) -> Result<(), TypeError> {
+        let ty = self.infer(exp, locals)?;
+        self.unify(expected, &ty, exp.span())
+    }
+
+    fn infer(&mut self, ast : &ExpAst, locals : &mut Vec<(String, Scheme)>) -> Result<Type, TypeError> {
+        match ast {
+            ExpAst::Num(_, _) => Ok(Type::Int),
+            ExpAst::Str(_, _) => Ok(Type::Str),
+            ExpAst::Add(t1, t2, _) | ExpAst::Sub(t1, t2, _) | ExpAst::Mul(t1, t2, _) | ExpAst::Div(t1, t2, _) |
+            ExpAst::And(t1, t2, _) | ExpAst::Or(t1, t2, _) => {
+                self.expect(&Type::Int, t1, locals)?;
+                self.expect(&Type::Int, t2, locals)?;
+                Ok(Type::Int)
+            },
+            ExpAst::Not(t, _) | ExpAst::Neg(t, _) => {
+                self.expect(&Type::Int, t, locals)?;
+                Ok(Type::Int)
+            },
+            ExpAst::Concat(t1, t2, _) => {
+                self.expect(&Type::Str, t1, locals)?;
+                self.expect(&Type::Str, t2, locals)?;
+                Ok(Type::Str)
+            },
+            ExpAst::Compare(op, t1, t2, _) => self.infer_compare(*op, t1, t2, locals),
+            ExpAst::Nil(_) => Ok(Type::list(self.fresh())),
+            ExpAst::Cons(head, tail, _) => {
+                let ty = Type::list(self.infer(head, locals)?);
+                self.expect(&ty, tail, locals)?;
+                Ok(ty)
+            },
+            ExpAst::Var(name, span) => self.lookup(name, *span, locals),
+            ExpAst::Constructor(name, span) => Ok(self.constructor(name, *span)?.1),
+            ExpAst::Fun(param, body, _) => {
+                let ty = self.fresh();
+                locals.push((param.clone(), Scheme::mono(ty.clone())));
+                let result = self.infer(body, locals);
+                locals.pop();
+                Ok(Type::fun(ty, result?))
+            },
+            ExpAst::App(t1, t2, _) => {
+                let fun = self.infer(t1, locals)?;
+                let arg = self.infer(t2, locals)?;
+                // the argument is reported against the parameter when the function is known
+                if let Type::Fun(param, result) = self.shallow(&fun) {
+                    self.unify(&param, &arg, t2.span())?;
+                    return Ok(*result);
+                }
+                let result = self.fresh();
+                self.unify(&Type::fun(arg, result.clone()), &fun, t1.span())?;
+                Ok(result)
+            },
+            ExpAst::If(cond_exp, then_exp, else_exp, _) => {
+                self.expect(&Type::Int, cond_exp, locals)?;
+                let ty = self.infer(then_exp, locals)?;
+                self.expect(&ty, else_exp, locals)?;
+                Ok(ty)
+            },
+            ExpAst::Let(name, value, body, _) => {
+                let ty = self.infer(value, locals)?;
+                let scheme = self.generalize(&ty, locals);
+                locals.push((name.clone(), scheme));
+                let result = self.infer(body, locals);
+                locals.pop();
+                result
+            },
+            ExpAst::LetRec(name, value, body, _) => {
+                // the function has a single type in its own body
+                let ty = self.fresh();
+                locals.push((name.clone(), Scheme::mono(ty.clone())));
+                let result = self.expect(&ty, value, locals);
+                locals.pop();
+                result?;
+                let scheme = self.generalize(&ty, locals);
+                locals.push((name.clone(), scheme));
+                let result = self.infer(body, locals);
+                locals.pop();
+                result
+            },
+            ExpAst::Match(exp, arms, _) => {
+                let ty = self.infer(exp, locals)?;
+                let result = self.fresh();
+                for (pattern, body) in arms {
+                    let depth = locals.len();
+                    let checked = self.infer_arm(&ty, &result, pattern, body, locals);
+                    locals.truncate(depth);
+                    checked?;
+                }
+                Ok(result)
+            },
+        }
+    }
+
+    fn infer_arm(&mut self, ty : &Type, result : &Type, pattern : &Pattern, body : &ExpAst, locals : &mut Vec<(String, Scheme)>) -> Result<(), TypeError> {
+        let pattern_type = self.infer_pattern(pattern, locals)?;
+        self.unify(ty, &pattern_type, pattern.span())?;
+        self.expect(result, body, locals)
+    }
+
+    // == and != compare numbers or strings, and the other comparisons only numbers
+    fn infer_compare(&mut self, op : CmpOp, t1 : &ExpAst, t2 : &ExpAst, locals : &mut Vec<(String, Scheme)>) -> Result<Type, TypeError> {
+        if op != CmpOp::Equal && op != CmpOp::NotEqual {
+            self.expect(&Type::Int, t1, locals)?;
+            self.expect(&Type::Int, t2, locals)?;
+            return Ok(Type::Int);
+        }
+        let ty = self.infer(t1, locals)?;
+        self.expect(&ty, t2, locals)?;
+        // values whose type is still unknown are taken to be numbers
+        match self.shallow(&ty) {
+            Type::Str => (),
+            ty => self.unify(&Type::Int, &ty, t1.span())?,
+        }
+        Ok(Type::Int)
+    }
+
+    // The type of the values the pattern matches; its variables are bound in locals
+    fn infer_pattern(&mut self, pattern : &Pattern, locals : &mut Vec<(String, Scheme)>) -> Result<Type, TypeError> {
+        match pattern {
+            Pattern::Wildcard(_) => Ok(self.fresh()),
+            Pattern::Var(name, _) => {
+                let ty = self.fresh();
+                locals.push((name.clone(), Scheme::mono(ty.clone())));
+                Ok(ty)
+            },
+            Pattern::Num(_, _) => Ok(Type::Int),
+            Pattern::Str(_, _) => Ok(Type::Str),
+            Pattern::Constructor(name, patterns, span) => {
+                let (arity, mut ty) = self.constructor(name, *span)?;
+                if patterns.len() != arity {
+                    return Err(TypeError::new(TypeErrorKind::ConstructorArity(name.clone(), arity, patterns.len()), *span));
+                }
+                for pattern in patterns {
+                    let (param, result) = match ty {
+                        Type::Fun(param, result) => (param, result),
+                        _ => unreachable!("a constructor takes one parameter for each field"),
+                    };
+                    let field = self.infer_pattern(pattern, locals)?;
+                    self.unify(&param, &field, pattern.span())?;
+                    ty = *result;
+                }
+                Ok(ty)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+use parser;
+
+#[cfg(test)]
+fn types(checker : &mut TypeChecker, source : &str) -> Result<Vec<String>, String> {
+    let ast = block_to_ast(parser::parse_source("types.sm", source).unwrap());
+    match checker.check_block(&ast) {
+        Ok(types) => Ok(types.iter().map(|(name, ty)| format!("{} : {}", name, ty)).collect()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[test]
+fn types_test() {
+    let infer = |source : &str| types(&mut TypeChecker::new(), source);
+
+    assert_eq!(infer("{ plus = |x, y| x + y }").unwrap(), ["plus : Int -> Int -> Int"]);
+    assert_eq!(infer("{ id = |x| x; id 1; id \"one\"; id }").unwrap(), ["id : a -> a", "- : Int", "- : String", "- : a -> a"]);
+    assert_eq!(infer("{ compose = |f, g, x| f (g x) }").unwrap(), ["compose : (a -> b) -> (c -> a) -> c -> b"]);
+    assert_eq!(infer("let pair = |x, y| x :: y :: [] in pair \"a\"").unwrap(), ["- : String -> [String]"]);
+    assert_eq!(infer("let id = |x| x in if id 1 then id \"yes\" else length end").unwrap_err(),
+        "expected String but got String -> Int at 1:46-1:52");
+    assert_eq!(infer("{ f = |x| substring x 0 (length x); head (tail [1, 2]) }").unwrap(), ["f : String -> String", "- : Int"]);
+    assert_eq!(infer("|s, t| s == t").unwrap(), ["- : Int -> Int -> Int"]);
+    assert_eq!(infer("|s| s != \"\"").unwrap(), ["- : String -> Int"]);
+
+    // recursion, through let rec and through globals, even before they are assigned
+    assert_eq!(infer("let rec f = |n| if n then n * f (n - 1) else 1 end in f").unwrap(), ["- : Int -> Int"]);
+    assert_eq!(infer("{ even = |n| if n == 0 then 1 else odd (n - 1) end; odd = |n| if n == 0 then 0 else even (n - 1) end }").unwrap(),
+        ["even : Int -> Int", "odd : Int -> Int"]);
+    assert_eq!(infer("{ map = |f, l| if empty? l then [] else f (head l) :: map f (tail l) end; map length [\"a\"] }").unwrap(),
+        ["map : (a -> b) -> [a] -> [b]", "- : [Int]"]);
+
+    // errors give both types, named together
+    assert_eq!(infer("(|x| x + 1) (|y| y)").unwrap_err(), "expected Int but got a -> a at 1:14-1:19");
+    assert_eq!(infer("1 2").unwrap_err(), "expected Int -> a but got Int at 1:1-1:2");
+    assert_eq!(infer("[1, \"two\"]").unwrap_err(), "expected [Int] but got [String] at 1:1-1:11");
+    assert_eq!(infer("|f| f f").unwrap_err(), "cannot build the infinite type a = a -> b at 1:5-1:6");
+    assert_eq!(infer("x + 1").unwrap_err(), "unbound variable 'x' at 1:1-1:2");
+    assert_eq!(infer("|l| l == []").unwrap_err(), "expected Int but got [a] at 1:5-1:6");
+
+    // declared types take the type variables of their fields as parameters
+    let list = "type List = Nil | Cons a (List a); type Option = None | Some a;";
+    assert_eq!(infer(&format!("{{ {} Some (Cons 1 Nil) }}", list)).unwrap(),
+        ["Nil : List a", "Cons : a -> List a -> List a", "None : Option a", "Some : a -> Option a", "- : Option (List Int)"]);
+    assert_eq!(infer(&format!("{{ {} find = |p, l| match l with | Nil -> None | Cons x rest -> if p x then Some x else find p rest end end }}", list)).unwrap()[4],
+        "find : (a -> Int) -> List a -> Option a");
+    assert_eq!(infer(&format!("{{ {} match Some 1 with | Some \"one\" -> 1 | _ -> 0 end }}", list)).unwrap_err(),
+        "expected Option Int but got Option String at 1:87-1:97");
+    assert_eq!(infer("{ type Fn = Fn (String -> Int) [Int]; Fn length }").unwrap(), ["Fn : (String -> Int) -> [Int] -> Fn", "- : [Int] -> Fn"]);
+    assert_eq!(infer("{ type T = T (Option Int) }").unwrap_err(), "unknown type 'Option' at 1:15-1:25");
+    assert_eq!(infer("{ type T = T (T Int) }").unwrap_err(), "type 'T' has 0 parameters but is given 1 at 1:15-1:20");
+    assert_eq!(infer("match 1 with | Some x -> x end").unwrap_err(), "unknown constructor 'Some' at 1:16-1:22");
+
+    // a session keeps what checked, and forgets a block that did not
+    let mut checker = TypeChecker::new();
+    assert_eq!(types(&mut checker, "{ twice = |f, x| f (f x) }").unwrap(), ["twice : (a -> a) -> a -> a"]);
+    assert!(types(&mut checker, "{ broken = 1; twice 1 }").is_err());
+    assert_eq!(types(&mut checker, "twice (|s| s ++ \"!\")").unwrap(), ["- : String -> String"]);
+    assert_eq!(types(&mut checker, "broken").unwrap_err(), "unbound variable 'broken' at 1:1-1:7");
+
+    // a global keeps its type when it is assigned again, so what used it still checks
+    assert_eq!(types(&mut checker, "x = 1").unwrap(), ["x : Int"]);
+    assert_eq!(types(&mut checker, "f = |u| x + 1").unwrap(), ["f : a -> Int"]);
+    assert_eq!(types(&mut checker, "x = \"a\"").unwrap_err(), "expected Int but got String at 1:5-1:8");
+    assert_eq!(types(&mut checker, "x = 2").unwrap(), ["x : Int"]);
+    assert_eq!(types(&mut checker, "twice = |g, y| g 1").unwrap_err(), "expected (a -> a) -> a -> a but got (Int -> Int) -> Int -> Int at 1:9-1:19");
+    assert_eq!(types(&mut checker, "twice = |g, y| g y").unwrap(), ["twice : (a -> a) -> a -> a"]);
+    assert_eq!(types(&mut checker, "{ id = |y| y; id = |y| y + 1 }").unwrap_err(), "expected a -> a but got Int -> Int at 1:20-1:30");
+}
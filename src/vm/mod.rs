@@ -1,6 +1,22 @@
-#[derive(Debug, Copy, Clone)]
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use arithmetic;
+use arithmetic::{ArithError, BinOp, Overflow};
+use builtins;
+use builtins::{Builtin, BuiltinError};
+use equality;
+use equality::Mismatch;
+use limits::{LimitError, Limits, Stats};
+use list;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operator {
     PushInt32(i32),
+    PushStr(Rc<str>),  // push a string, which is shared rather than copied
+    PushBuiltin(Builtin),
+    PushNil,           // push the empty list
+    PushConstructor(Rc<Constructor>), // push a constructor, which is called with its fields like a function
     Pop,
 
     Add,
@@ -9,13 +25,28 @@ pub enum Operator {
     Div,
 
     Not,
+    Concat,            // pop 2 strings and push the first pushed followed by the second
+    Cons,              // pop a list and a value, and push a new cell with the value in front of the list
+    GetConstructor,    // pop a value built by a constructor and push the name of the constructor
+    GetField(usize),   // pop a value built by a constructor and push its n-th field
 
-    Equal,             // read 2 value from stack, compare them  and push 1/0 if values are the same/different
+    Equal,             // read 2 value from stack, compare them  and push 1/0 if values are the same/different; works on numbers and strings
+    Less,              // pop 2 values and push 1 if the first pushed is less than the second, 0 otherwise
+    Greater,           // pop 2 values and push 1 if the first pushed is greater than the second, 0 otherwise
 
     Load(usize),       // read the n-th item in the stack and push it on top
     Store(usize),      // write value on top of the stack to the n-th item in the stack
+    LoadGlobal(usize), // read the n-th item from the bottom of the stack and push it on top
+
+    MakeClosure(isize, usize), // pop n captured values and push a closure whose code starts at PC + the offset
+    LoadCaptured(usize),       // push the n-th captured value of the running closure
+    LoadClosure,               // push the running closure itself
+    Call,              // pop an argument and a function, and call the function with the argument
+    TailCall,          // like Call, but the callee replaces the current frame and returns to its caller
+    Ret,               // discard the current frame and push the value on top of the stack to the caller
 
     Print,             // print the value on top of the stack
+    MatchFailure,      // fail with the value on top of the stack, which no pattern matched
 
     JumpIf(isize),     // proceed the PC if top of the stack is not 0
     JumpUnless(isize), // proced the PC if top of the stack is 0
@@ -24,100 +55,523 @@ pub enum Operator {
     Dump,
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Data {
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
     Num(i32),
+    Str(Rc<str>),                // strings live on the heap and are never modified
+    Nil,
+    Cons(Rc<ListCell>),          // list cells live on the heap too, and share their tails
+    Fun(Rc<Closure>),
+    Builtin(Builtin, Vec<Data>), // with the arguments it was given so far
+    Adt(Rc<Constructor>, Rc<[Data]>), // with the fields it was given so far
+}
+
+// Constructors are told apart by their name, whatever type declared them
+#[derive(Debug, PartialEq)]
+pub struct Constructor {
+    pub name: Rc<str>,
+    pub arity: usize,
+}
+
+pub type ListCell = list::ListCell<Data>;
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::Num(num) => write!(f, "{}", num),
+            Data::Str(text) => write!(f, "{}", text),
+            Data::Nil | Data::Cons(_) => list::fmt_list(f, self),
+            Data::Fun(_) | Data::Builtin(..) => write!(f, "<fun>"),
+            Data::Adt(constructor, fields) => list::fmt_adt(f, &constructor.name, constructor.arity, fields),
+        }
+    }
+}
+
+impl equality::Value for Data {
+    fn operand(&self) -> equality::Operand<'_> {
+        match self {
+            Data::Num(n) => equality::Operand::Num(*n),
+            Data::Str(text) => equality::Operand::Str(text),
+            _ => equality::Operand::Other,
+        }
+    }
 }
 
-pub fn process(program : &Vec<Operator>) {
-    let mut pc : usize = 0;
-    //let mut sp : usize = 0;
-    let sp : usize = 0;
-    let mut stack : Vec<Data> = Vec::new();
+impl list::Value for Data {
+    fn nil() -> Data {
+        Data::Nil
+    }
+
+    fn cell(&self) -> Option<&Rc<ListCell>> {
+        match self {
+            Data::Cons(cell) => Some(cell),
+            _ => None,
+        }
+    }
+
+    fn into_cell(self) -> Option<Rc<ListCell>> {
+        match self {
+            Data::Cons(cell) => Some(cell),
+            _ => None,
+        }
+    }
 
-    while pc < program.len() {
-        println!("{:?}", stack);
-        match program[pc] {
-            Operator::PushInt32(i) => stack.push(Data::Num(i)),
-            Operator::Pop => {stack.pop();},
+    fn text(&self) -> Option<&str> {
+        match self {
+            Data::Str(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn is_compound(&self) -> bool {
+        match self {
+            Data::Adt(c, fields) => !fields.is_empty() || c.arity > 0,
+            Data::Num(num) => *num < 0,
+            _ => false,
+        }
+    }
+}
+
+impl Data {
+    pub fn quoted(&self) -> String {
+        list::quoted(self)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Closure {
+    pub addr: usize,         // address of the first instruction of the function
+    pub captured: Vec<Data>, // values of the free variables, in the order given by the compiler
+}
+
+// There is no kind for an unknown instruction: a program is a slice of Operator, and
+// step matches every variant of it, so the VM cannot be handed an opcode it does not know.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmErrorKind {
+    StackUnderflow,
+    BadJumpTarget(isize),       // absolute address the PC would have moved to
+    DivisionByZero,
+    Overflow,
+    TypeMismatch(&'static str, Data), // expected kind of value and the value actually found
+    BadCapture(usize),          // captured slot that the running closure does not have
+    IndexOutOfRange,
+    EmptyList,
+    NoClosure,                  // the running code is not part of a closure
+    MatchFailure(Data),         // value that no pattern of a match matched
+    Limit(LimitError),
+}
+
+// Reported instead of aborting when a program cannot be run to the end
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    pub pc: usize,              // address of the faulting instruction
+    pub stack: Vec<Data>,       // the stack as it was before the faulting instruction
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            VmErrorKind::StackUnderflow => write!(f, "stack underflow")?,
+            VmErrorKind::BadJumpTarget(target) => write!(f, "jump to invalid address {}", target)?,
+            VmErrorKind::DivisionByZero => write!(f, "division by zero")?,
+            VmErrorKind::Overflow => write!(f, "integer overflow")?,
+            VmErrorKind::TypeMismatch(expected, got) => write!(f, "expected {} but got {}", expected, got.quoted())?,
+            VmErrorKind::BadCapture(n) => write!(f, "no captured value {}", n)?,
+            VmErrorKind::IndexOutOfRange => write!(f, "index out of range")?,
+            VmErrorKind::EmptyList => write!(f, "the list is empty")?,
+            VmErrorKind::NoClosure => write!(f, "no running closure")?,
+            VmErrorKind::MatchFailure(data) => write!(f, "no pattern matches {}", data.quoted())?,
+            VmErrorKind::Limit(e) => write!(f, "{}", e)?,
+        }
+        write!(f, " at pc {} (stack: {:?})", self.pc, self.stack)
+    }
+}
+
+impl From<LimitError> for VmErrorKind {
+    fn from(e : LimitError) -> VmErrorKind {
+        VmErrorKind::Limit(e)
+    }
+}
+
+impl From<BuiltinError> for VmErrorKind {
+    fn from(e : BuiltinError) -> VmErrorKind {
+        match e {
+            BuiltinError::IndexOutOfRange => VmErrorKind::IndexOutOfRange,
+            BuiltinError::EmptyList => VmErrorKind::EmptyList,
+        }
+    }
+}
+
+impl From<ArithError> for VmErrorKind {
+    fn from(e : ArithError) -> VmErrorKind {
+        match e {
+            ArithError::Overflow => VmErrorKind::Overflow,
+            ArithError::DivisionByZero => VmErrorKind::DivisionByZero,
+        }
+    }
+}
+
+// Settings chosen per run
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub overflow: Overflow,
+    pub limits: Limits,
+}
+
+// Saved state of the caller, restored by Ret
+#[derive(Debug)]
+struct Frame {
+    return_pc: usize,
+    fp: usize,
+    closure: Option<Rc<Closure>>,
+}
+
+struct Machine<'a> {
+    program: &'a [Operator],
+    config: &'a Config,
+    pc: usize,
+    fp: usize,                    // index of the first item of the current frame
+    stack: Vec<Data>,
+    frames: Vec<Frame>,
+    closure: Option<Rc<Closure>>, // closure being run, none at top level
+    popped: Vec<Data>,            // operands the running instruction took off the stack
+    stats: Stats,
+}
+impl<'a> Machine<'a> {
+    fn new(program : &'a [Operator], config : &'a Config) -> Machine<'a> {
+        Machine{program, config, pc: 0, fp: 0, stack: vec![], frames: vec![], closure: None, popped: vec![], stats: Stats::default()}
+    }
+
+    fn run(&mut self) -> Result<Data, VmError> {
+        while self.pc < self.program.len() {
+            let pc = self.pc;
+            self.popped.clear();
+            let result = self.config.limits.check_fuel(self.stats.steps)
+                .map_err(VmErrorKind::from)
+                .and_then(|()| self.step())
+                // the operands go back on the stack, so that the error shows them
+                .inspect_err(|_| self.stack.extend(self.popped.drain(..).rev()))
+                .and_then(|()| self.config.limits.check_stack(self.stack.len()).map_err(VmErrorKind::from));
+            if let Err(kind) = result {
+                return Err(VmError{kind, pc, stack: self.stack.clone()});
+            }
+            self.stats.steps += 1;
+            self.stats.max_stack = self.stats.max_stack.max(self.stack.len());
+            self.stats.max_frames = self.stats.max_frames.max(self.frames.len());
+        }
+        self.pop().map_err(|kind| VmError{kind, pc: self.pc, stack: vec![]})
+    }
+
+    fn pop(&mut self) -> Result<Data, VmErrorKind> {
+        let data = self.stack.pop().ok_or(VmErrorKind::StackUnderflow)?;
+        self.popped.push(data.clone());
+        Ok(data)
+    }
+
+    fn pop_num(&mut self) -> Result<i32, VmErrorKind> {
+        match self.pop()? {
+            Data::Num(n) => Ok(n),
+            data => Err(VmErrorKind::TypeMismatch("a number", data)),
+        }
+    }
+
+    fn arithmetic(&mut self, op : BinOp) -> Result<(), VmErrorKind> {
+        let v1 = self.pop_num()?;
+        let v2 = self.pop_num()?;
+        self.stack.push(Data::Num(arithmetic::apply(self.config.overflow, op, v2, v1)?));
+        Ok(())
+    }
+
+    // n-th item from the top of the stack
+    fn peek(&self, n : usize) -> Result<usize, VmErrorKind> {
+        if n < self.stack.len() {
+            Ok(self.stack.len() - n - 1)
+        }
+        else {
+            Err(VmErrorKind::StackUnderflow)
+        }
+    }
+
+    // a jump may land right after the last instruction, which ends the program
+    fn jump(&mut self, offset : isize) -> Result<(), VmErrorKind> {
+        let target = self.pc as isize + offset;
+        if target < 0 || target > self.program.len() as isize {
+            return Err(VmErrorKind::BadJumpTarget(target));
+        }
+        self.pc = target as usize;
+        Ok(())
+    }
+
+    fn pop_str(&mut self) -> Result<Rc<str>, VmErrorKind> {
+        match self.pop()? {
+            Data::Str(text) => Ok(text),
+            data => Err(VmErrorKind::TypeMismatch("a string", data)),
+        }
+    }
 
-            Operator::Add => {
-                let Data::Num(v1) = stack.pop().unwrap();
-                let Data::Num(v2) = stack.pop().unwrap();
-                stack.push(Data::Num(v2 + v1));
+    fn pop_adt(&mut self) -> Result<(Rc<Constructor>, Rc<[Data]>), VmErrorKind> {
+        match self.pop()? {
+            Data::Adt(constructor, fields) if fields.len() == constructor.arity => Ok((constructor, fields)),
+            data => Err(VmErrorKind::TypeMismatch("a value built by a constructor", data)),
+        }
+    }
+
+    // A builtin runs in place once it has all its arguments, without a frame of its own
+    fn call_builtin(&mut self, builtin : Builtin, args : Vec<Data>) -> Result<(), VmErrorKind> {
+        let result = match (builtin, args.as_slice()) {
+            _ if args.len() < builtin.arity() => Data::Builtin(builtin, args),
+            (Builtin::Length, [Data::Str(text)]) => Data::Num(builtins::length(text)),
+            (Builtin::Substring, [Data::Str(text), Data::Num(start), Data::Num(len)]) => {
+                Data::Str(Rc::from(builtins::substring(text, *start, *len)?))
             },
+            (Builtin::Head, [Data::Cons(cell)]) => cell.head.clone(),
+            (Builtin::Tail, [Data::Cons(cell)]) => cell.tail.clone(),
+            (Builtin::Head, [Data::Nil]) | (Builtin::Tail, [Data::Nil]) => return Err(VmErrorKind::EmptyList),
+            (Builtin::IsEmpty, [Data::Nil]) => Data::Num(1),
+            (Builtin::IsEmpty, [Data::Cons(_)]) => Data::Num(0),
+            (Builtin::Head | Builtin::Tail | Builtin::IsEmpty, [data]) => return Err(VmErrorKind::TypeMismatch("a list", data.clone())),
+            (_, [Data::Str(_), Data::Num(_), data]) | (_, [Data::Str(_), data, _]) => return Err(VmErrorKind::TypeMismatch("a number", data.clone())),
+            (_, [data, ..]) => return Err(VmErrorKind::TypeMismatch("a string", data.clone())),
+            // the argument a builtin is run with has to come from the stack
+            (_, []) => return Err(VmErrorKind::StackUnderflow),
+        };
+        self.stack.push(result);
+        self.pc += 1;
+        Ok(())
+    }
 
-            Operator::Sub => {
-                let Data::Num(v1) = stack.pop().unwrap();
-                let Data::Num(v2) = stack.pop().unwrap();
-                stack.push(Data::Num(v2 - v1));
+    fn call(&mut self, tail : bool) -> Result<(), VmErrorKind> {
+        let arg = self.pop()?;
+        let callee = match self.pop()? {
+            Data::Fun(callee) => callee,
+            Data::Builtin(builtin, mut args) => {
+                args.push(arg);
+                return self.call_builtin(builtin, args);
+            },
+            // a constructor is complete once it has all its fields
+            Data::Adt(constructor, fields) if fields.len() < constructor.arity => {
+                let fields : Vec<Data> = fields.iter().cloned().chain(Some(arg)).collect();
+                self.stack.push(Data::Adt(constructor, Rc::from(fields)));
+                self.pc += 1;
+                return Ok(());
             },
+            data => return Err(VmErrorKind::TypeMismatch("a function", data)),
+        };
+        if tail && !self.frames.is_empty() {
+            // nothing in the current frame is needed anymore, so the callee takes it over
+            self.stack.truncate(self.fp);
+        }
+        else {
+            self.config.limits.check_depth(self.frames.len() + 1)?;
+            self.frames.push(Frame{return_pc: self.pc, fp: self.fp, closure: self.closure.take()});
+            self.fp = self.stack.len();
+        }
+        self.stack.push(arg);
+        self.pc = callee.addr;
+        self.closure = Some(callee);
+        Ok(())
+    }
 
+    fn step(&mut self) -> Result<(), VmErrorKind> {
+        match self.program[self.pc].clone() {
+            Operator::PushInt32(i) => self.stack.push(Data::Num(i)),
+            Operator::PushStr(text) => self.stack.push(Data::Str(text)),
+            Operator::PushBuiltin(builtin) => self.stack.push(Data::Builtin(builtin, vec![])),
+            Operator::PushNil => self.stack.push(Data::Nil),
+            Operator::PushConstructor(constructor) => self.stack.push(Data::Adt(constructor, Rc::from(vec![]))),
+            Operator::Pop => {self.pop()?;},
+
+            Operator::Add => self.arithmetic(BinOp::Add)?,
+            Operator::Sub => self.arithmetic(BinOp::Sub)?,
+            Operator::Mul => self.arithmetic(BinOp::Mul)?,
+            Operator::Div => self.arithmetic(BinOp::Div)?,
 
             Operator::Not => {
-                let Data::Num(n) = stack.pop().unwrap();
+                let n = self.pop_num()?;
                 if n == 0 {
-                    stack.push(Data::Num(1));
+                    self.stack.push(Data::Num(1));
                 }
                 else {
-                    stack.push(Data::Num(0));
+                    self.stack.push(Data::Num(0));
+                }
+            },
+
+            Operator::Less => {
+                let v1 = self.pop_num()?;
+                let v2 = self.pop_num()?;
+                self.stack.push(Data::Num((v2 < v1) as i32));
+            },
+
+            Operator::Greater => {
+                let v1 = self.pop_num()?;
+                let v2 = self.pop_num()?;
+                self.stack.push(Data::Num((v2 > v1) as i32));
+            },
+
+            Operator::Concat => {
+                let s1 = self.pop_str()?;
+                let s2 = self.pop_str()?;
+                self.stack.push(Data::Str(Rc::from(format!("{}{}", s2, s1))));
+            },
+
+            Operator::Cons => {
+                let tail = match self.pop()? {
+                    tail @ (Data::Nil | Data::Cons(_)) => tail,
+                    data => return Err(VmErrorKind::TypeMismatch("a list", data)),
+                };
+                let head = self.pop()?;
+                self.stack.push(Data::Cons(Rc::new(ListCell{head, tail})));
+            },
+
+            Operator::GetConstructor => {
+                let (constructor, _) = self.pop_adt()?;
+                self.stack.push(Data::Str(constructor.name.clone()));
+            },
+
+            Operator::GetField(n) => {
+                let (constructor, fields) = self.pop_adt()?;
+                match fields.get(n) {
+                    Some(field) => self.stack.push(field.clone()),
+                    None => {
+                        let data = Data::Adt(constructor, fields.clone());
+                        return Err(VmErrorKind::TypeMismatch("a value with more fields", data));
+                    },
                 }
             },
 
             Operator::Equal => {
-                let Data::Num(v1) = stack.pop().unwrap();
-                let Data::Num(v2) = stack.pop().unwrap();
-                if v2 == v1 {
-                    stack.push(Data::Num(1));
+                let right = self.pop()?;
+                let left = self.pop()?;
+                let equal = match equality::equal(&left, &right) {
+                    Ok(equal) => equal,
+                    Err(Mismatch::Left(expected)) => return Err(VmErrorKind::TypeMismatch(expected, left)),
+                    Err(Mismatch::Right(expected)) => return Err(VmErrorKind::TypeMismatch(expected, right)),
+                };
+                if equal {
+                    self.stack.push(Data::Num(1));
                 }
                 else {
-                    stack.push(Data::Num(0));
+                    self.stack.push(Data::Num(0));
                 }
             },
 
             Operator::Load(n) => {
-                let data = stack[sp + stack.len() - n - 1];
-                stack.push(data);
+                let data = self.stack[self.peek(n)?].clone();
+                self.stack.push(data);
             },
 
             Operator::Store(n) => {
-                let target_index = sp + stack.len() - n - 1;
-                let source_index = sp + stack.len() - 1;
-                stack[target_index] = stack[source_index];
+                let target_index = self.peek(n)?;
+                let source_index = self.peek(0)?;
+                self.stack[target_index] = self.stack[source_index].clone();
+            },
+
+            Operator::LoadGlobal(n) => {
+                let data = self.stack.get(n).ok_or(VmErrorKind::StackUnderflow)?.clone();
+                self.stack.push(data);
+            },
+
+            Operator::MakeClosure(i, n) => {
+                if n > self.stack.len() {
+                    return Err(VmErrorKind::StackUnderflow);
+                }
+                let target = self.pc as isize + i;
+                if target < 0 || target >= self.program.len() as isize {
+                    return Err(VmErrorKind::BadJumpTarget(target));
+                }
+                let captured = self.stack.split_off(self.stack.len() - n);
+                self.stack.push(Data::Fun(Rc::new(Closure{addr: target as usize, captured})));
+            },
+
+            Operator::LoadCaptured(n) => {
+                let data = self.closure.as_ref()
+                    .and_then(|closure| closure.captured.get(n))
+                    .ok_or(VmErrorKind::BadCapture(n))?
+                    .clone();
+                self.stack.push(data);
+            },
+
+            Operator::LoadClosure => {
+                let closure = self.closure.clone().ok_or(VmErrorKind::NoClosure)?;
+                self.stack.push(Data::Fun(closure));
+            },
+
+            Operator::Call => return self.call(false),
+            Operator::TailCall => return self.call(true),
+
+            Operator::Ret => {
+                let v = self.pop()?;
+                let frame = self.frames.pop().ok_or(VmErrorKind::StackUnderflow)?;
+                self.stack.truncate(self.fp);
+                self.fp = frame.fp;
+                self.pc = frame.return_pc;
+                self.closure = frame.closure;
+                self.stack.push(v);
             },
 
             Operator::Print => {
-                let Data::Num(v1) = stack.last().unwrap();
-                println!("{}", v1);
+                println!("{}", self.stack[self.peek(0)?]);
+            },
+
+            Operator::MatchFailure => {
+                let data = self.pop()?;
+                return Err(VmErrorKind::MatchFailure(data));
             },
 
             Operator::JumpIf(i) => {
-                let Data::Num(v) = stack.pop().unwrap();
+                let v = self.pop_num()?;
                 if v != 0 {
-                    pc = ((pc as isize) + i - 1) as usize;
+                    return self.jump(i);
                 }
             },
             Operator::JumpUnless(i) => {
-                let Data::Num(v) = stack.pop().unwrap();
+                let v = self.pop_num()?;
                 if v == 0 {
-                    pc = ((pc as isize) + i - 1) as usize;
+                    return self.jump(i);
                 }
             },
             Operator::Jump(i) => {
-                pc = ((pc as isize) + i - 1) as usize;
+                return self.jump(i);
             },
 
             Operator::Dump => {
-                println!("{:?}", stack);
+                println!("{:?}", self.stack);
             },
-
-            _ => panic!("Unknown instruction"),
         }
-        
-        pc += 1;
+
+        self.pc += 1;
+        Ok(())
     }
+}
+
+// Runs the program and returns the value left on top of the stack
+pub fn process(program : &[Operator]) -> Result<Data, VmError> {
+    process_with(program, &Config::default())
+}
+
+pub fn process_with(program : &[Operator], config : &Config) -> Result<Data, VmError> {
+    process_with_stats(program, config).0
+}
+
+// Also reports the fuel and stack the run used, whether it succeeded or not.
+// Every instruction costs one unit of fuel.
+pub fn process_with_stats(program : &[Operator], config : &Config) -> (Result<Data, VmError>, Stats) {
+    let mut machine = Machine::new(program, config);
+    let result = machine.run();
+    (result, machine.stats)
+}
 
-    println!("STACK: {:?}", stack);
+// Runs the program from the given address on the stack an earlier run left, as the REPL
+// does with the code of each line. The value returned is popped off; whatever else the
+// run left stays on the stack, even when it fails.
+pub fn resume(program : &[Operator], start : usize, stack : &mut Vec<Data>, config : &Config) -> Result<Data, VmError> {
+    let mut machine = Machine::new(program, config);
+    machine.pc = start;
+    machine.stack = mem::take(stack);
+    let result = machine.run();
+    *stack = machine.stack;
+    result
 }
 
 
@@ -143,5 +597,132 @@ fn vm_test() {
         Operator::JumpIf(-12),
         Operator::Print,
     ];
-    process(&program);
+    assert_eq!(process(&program), Ok(Data::Num(55)));
+}
+
+#[test]
+fn vm_error_test() {
+    let error = process(&[Operator::PushInt32(1), Operator::Add]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+    assert_eq!(error.pc, 1);
+    assert_eq!(error.stack, vec![Data::Num(1)]);
+
+    let error = process(&[Operator::PushInt32(1), Operator::Jump(-2)]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::BadJumpTarget(-1));
+    assert_eq!(error.stack, vec![Data::Num(1)]);
+
+    let error = process(&[Operator::PushInt32(1), Operator::PushInt32(2), Operator::Call]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::TypeMismatch("a function", Data::Num(1)));
+    assert_eq!(error.pc, 2);
+    assert_eq!(error.stack, vec![Data::Num(1), Data::Num(2)]);
+
+    // the stack is shown as it was before the instruction, whatever made it fail
+    let error = process(&[Operator::PushInt32(1), Operator::PushStr(Rc::from("a")), Operator::Add]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::TypeMismatch("a number", Data::Str(Rc::from("a"))));
+    assert_eq!(error.stack, vec![Data::Num(1), Data::Str(Rc::from("a"))]);
+    let error = process(&[Operator::PushInt32(1), Operator::PushInt32(0), Operator::Div]).unwrap_err();
+    assert_eq!(error.stack, vec![Data::Num(1), Data::Num(0)]);
+
+    let error = process(&[Operator::Ret]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+
+    let error = process(&[]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+}
+
+#[test]
+fn vm_string_test() {
+    let text = |s : &str| Data::Str(Rc::from(s));
+    let program = [
+        Operator::PushBuiltin(Builtin::Substring),
+        Operator::PushStr(Rc::from("heap ")),
+        Operator::PushStr(Rc::from("strings")),
+        Operator::Concat,
+        Operator::Call,
+        Operator::PushInt32(5),
+        Operator::Call,
+        Operator::PushInt32(3),
+        Operator::Call,
+    ];
+    assert_eq!(process(&program), Ok(text("str")));
+    assert_eq!(process(&[Operator::PushStr(Rc::from("a")), Operator::PushStr(Rc::from("a")), Operator::Equal]), Ok(Data::Num(1)));
+    assert_eq!(text("a\"b").to_string(), "a\"b");
+    assert_eq!(text("a\"b").quoted(), "\"a\\\"b\"");
+
+    let error = process(&[Operator::PushStr(Rc::from("a")), Operator::PushInt32(1), Operator::Concat]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::TypeMismatch("a string", Data::Num(1)));
+    let error = process(&[Operator::PushBuiltin(Builtin::Length), Operator::PushInt32(1), Operator::Call]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::TypeMismatch("a string", Data::Num(1)));
+    assert_eq!(error.pc, 2);
+}
+
+#[test]
+fn vm_constructor_test() {
+    let pair = Rc::new(Constructor{name: Rc::from("Pair"), arity: 2});
+    let build = [Operator::PushConstructor(pair.clone()), Operator::PushInt32(3), Operator::Call, Operator::PushStr(Rc::from("a")), Operator::Call];
+    let value = process(&build).unwrap();
+    assert_eq!(value.to_string(), "Pair 3 \"a\"");
+    assert_eq!(process(&build[..3]).unwrap().to_string(), "<fun>");
+
+    let field = |ops : &[Operator]| process(&[&build[..], ops].concat());
+    assert_eq!(field(&[Operator::GetConstructor]), Ok(Data::Str(Rc::from("Pair"))));
+    assert_eq!(field(&[Operator::GetField(1)]), Ok(Data::Str(Rc::from("a"))));
+    assert_eq!(field(&[Operator::MatchFailure]).unwrap_err().kind, VmErrorKind::MatchFailure(value));
+    let error = process(&[Operator::PushConstructor(pair.clone()), Operator::GetConstructor]).unwrap_err();
+    assert_eq!(error.to_string(), "expected a value built by a constructor but got <fun> at pc 1 (stack: [Adt(Constructor { name: \"Pair\", arity: 2 }, [])])");
+    assert_eq!(error.stack, vec![Data::Adt(pair, Rc::from(vec![]))]);
+}
+
+#[test]
+fn vm_arithmetic_test() {
+    let program = [Operator::PushInt32(7), Operator::PushInt32(6), Operator::Mul, Operator::PushInt32(4), Operator::Div];
+    assert_eq!(process(&program), Ok(Data::Num(10)));
+
+    let error = process(&[Operator::PushInt32(1), Operator::PushInt32(0), Operator::Div]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::DivisionByZero);
+    assert_eq!(error.pc, 2);
+    assert_eq!(error.stack, vec![Data::Num(1), Data::Num(0)]);
+
+    let program = [Operator::PushInt32(i32::MAX), Operator::PushInt32(2), Operator::Mul];
+    assert_eq!(process(&program).unwrap_err().kind, VmErrorKind::Overflow);
+    let wrapping = Config{overflow: Overflow::Wrapping, ..Config::default()};
+    assert_eq!(process_with(&program, &wrapping), Ok(Data::Num(-2)));
+}
+
+#[test]
+fn vm_limits_test() {
+    // counts down forever
+    let program = [Operator::PushInt32(1), Operator::PushInt32(1), Operator::Sub, Operator::Jump(-2)];
+    let config = Config{limits: Limits{fuel: Some(1000), ..Limits::default()}, ..Config::default()};
+    let (result, stats) = process_with_stats(&program, &config);
+    assert_eq!(result.unwrap_err().kind, VmErrorKind::Limit(LimitError::OutOfFuel));
+    assert_eq!(stats.steps, 1000);
+
+    // pushes forever
+    let program = [Operator::PushInt32(1), Operator::Jump(-1)];
+    let config = Config{limits: Limits{max_stack: Some(100), ..Limits::default()}, ..Config::default()};
+    let error = process_with(&program, &config).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::Limit(LimitError::StackOverflow));
+    assert_eq!((error.pc, error.stack.len()), (0, 101));
+
+    // f = |x| f x, called forever without a tail call
+    let program = [
+        Operator::MakeClosure(2, 0),
+        Operator::Jump(5),
+        Operator::LoadGlobal(0),
+        Operator::Load(1),
+        Operator::Call,
+        Operator::Ret,
+        Operator::LoadGlobal(0),
+        Operator::PushInt32(0),
+        Operator::Call,
+    ];
+    let config = Config{limits: Limits{max_depth: Some(50), ..Limits::default()}, ..Config::default()};
+    let (result, stats) = process_with_stats(&program, &config);
+    assert_eq!(result.unwrap_err().kind, VmErrorKind::Limit(LimitError::CallDepthExceeded));
+    assert_eq!(stats.max_frames, 50);
+
+    let (result, stats) = process_with_stats(&[Operator::PushInt32(1), Operator::PushInt32(2), Operator::Add], &config);
+    assert_eq!(result, Ok(Data::Num(3)));
+    assert_eq!(stats.steps, 3);
 }